@@ -0,0 +1,78 @@
+use std::process::exit;
+
+use log::error;
+use orderbook_rs::config::read_config;
+use orderbook_rs::matching::kafka_market_data::{
+    run_market_data, KafkaMarketDataPublisher, KafkaMatchReader, LOOKBACK_MILLIS,
+};
+use orderbook_rs::matching::market_data::Aggregator;
+
+const USAGE: &str = "usage: market_data [product_id ...]";
+
+#[tokio::main]
+async fn main() {
+    let config = read_config().await;
+    env_logger::Builder::new()
+        .parse_filters(&config.log.level)
+        .init();
+
+    // every configured product unless some are named
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let products = if args.is_empty() {
+        config.all_products()
+    } else {
+        let mut products = Vec::new();
+        for product_id in &args {
+            match config.find_product(product_id) {
+                Some(p) => products.push(p),
+                None => {
+                    eprintln!("product {} is not in the config\n{}", product_id, USAGE);
+                    exit(2);
+                }
+            }
+        }
+        products
+    };
+
+    let mut aggregators = Vec::new();
+    for product in products {
+        let config = config.clone();
+        aggregators.push(tokio::spawn(async move {
+            let mut match_reader =
+                match KafkaMatchReader::new_kafka_match_reader(&config.kafka.brokers, &product.id) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("{}: {}", product.id, e);
+                        return;
+                    }
+                };
+            // rebuild the 24 hour window and the current candles first
+            let since = chrono::Utc::now().timestamp_millis() - LOOKBACK_MILLIS;
+            if let Err(e) = match_reader.seek_to_time(since) {
+                error!("{}: {}", product.id, e);
+                return;
+            }
+            let mut publisher = match KafkaMarketDataPublisher::new_kafka_market_data_publisher(
+                &config.kafka.brokers,
+                &product.id,
+                config.kafka.message_timeout,
+                &config.kafka.log_format,
+            ) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("{}: {}", product.id, e);
+                    return;
+                }
+            };
+
+            let mut aggregator = Aggregator::new_aggregator(&product.id);
+            run_market_data(&mut match_reader, &mut aggregator, &mut publisher).await;
+        }));
+    }
+
+    for aggregator in aggregators {
+        if let Err(e) = aggregator.await {
+            error!("market data failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::process::exit;
+
+use orderbook_rs::config::read_config;
+use orderbook_rs::matching::engine::Engine;
+use orderbook_rs::matching::replay::{
+    find_divergence, read_logs, read_orders, read_snapshot, replay,
+};
+
+const USAGE: &str = "usage: replay <snapshot.json> <orders.jsonl> <output.jsonl> [expected.jsonl]";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+
+    // trading rules come from the same config file as the engine
+    let config = read_config().await;
+
+    let snapshot = read_snapshot(&args[0]).unwrap_or_else(|e| panic!("{}", e));
+    let orders = read_orders(&args[1]).unwrap_or_else(|e| panic!("{}", e));
+
+    // the product is the one of the snapshot, or of the orders if the snapshot is empty
+    let product_id = match (&snapshot.order_book_snapshot, orders.first()) {
+        (Some(s), _) => s.product_id.clone(),
+        (None, Some(o)) => o.product_id.clone(),
+        (None, None) => {
+            eprintln!("nothing to replay");
+            exit(2);
+        }
+    };
+    let product = config
+        .find_product(&product_id)
+        .unwrap_or_else(|| panic!("product {} is not in the config", product_id));
+
+    let mut engine = Engine::new_engine(&product);
+    if snapshot.order_book_snapshot.is_some() {
+        engine.restore(&snapshot);
+    }
+    let log_seq = engine.order_book.log_seq;
+
+    let logs = replay(&mut engine, &orders);
+    let mut output = logs.join("\n");
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    fs::write(&args[2], output).unwrap_or_else(|e| panic!("write {}: {}", args[2], e));
+    println!(
+        "replayed {} orders from offset {}, wrote {} logs to {}",
+        orders.len(),
+        snapshot.order_offset,
+        logs.len(),
+        args[2]
+    );
+
+    if let Some(path) = args.get(3) {
+        let expected = read_logs(path).unwrap_or_else(|e| panic!("{}", e));
+        match find_divergence(&logs, &expected, log_seq) {
+            None => println!("no divergence from {}", path),
+            Some(d) => {
+                println!("first divergence at log {}:", d.line);
+                println!("  expected: {}", d.expected.as_deref().unwrap_or("<end of dump>"));
+                println!("  actual:   {}", d.actual.as_deref().unwrap_or("<end of replay>"));
+                exit(1);
+            }
+        }
+    }
+}
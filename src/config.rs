@@ -3,6 +3,11 @@ use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
 use crate::models::models::Product;
+use crate::models::types::{
+    deserialize_compression, deserialize_snapshot_store_type, deserialize_wire_format,
+    serialize_compression, serialize_snapshot_store_type, serialize_wire_format, Compression,
+    SnapshotStoreType, WireFormat,
+};
 
 const CONFIG_FILE_NAME: &str = "config.json";
 
@@ -12,12 +17,73 @@ pub struct RedisConfig {
     pub port: u16,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SnapshotConfig {
+    // where snapshots are kept, redis if not set
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_snapshot_store_type")]
+    #[serde(deserialize_with = "deserialize_snapshot_store_type")]
+    pub store: SnapshotStoreType,
+    // directory of the file store, one subdirectory per product, "snapshots" if not set
+    #[serde(default)]
+    pub dir: String,
+    // compression of snapshot files, none if not set
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_compression")]
+    #[serde(deserialize_with = "deserialize_compression")]
+    pub compression: Compression,
+    // snapshots kept per product besides the chains they build on, all if not set
+    #[serde(default)]
+    pub retention: usize,
+    // one snapshot in this many is full and the others are deltas, 10 if not set,
+    // 1 takes only full snapshots
+    #[serde(default)]
+    pub full_interval: usize,
+}
+
+impl SnapshotConfig {
+    pub fn effective_dir(&self) -> &str {
+        if self.dir.is_empty() {
+            "snapshots"
+        } else {
+            &self.dir
+        }
+    }
+
+    pub fn effective_full_interval(&self) -> usize {
+        if self.full_interval == 0 {
+            10
+        } else {
+            self.full_interval
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KafkaConfig {
     pub brokers: Vec<String>,
     pub message_timeout: u64,
     pub session_timeout: u64,
     pub group_id: String,
+    // encoding of the order topic, json if not set
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_wire_format")]
+    #[serde(deserialize_with = "deserialize_wire_format")]
+    pub order_format: WireFormat,
+    // encoding of the log topic, json if not set
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_wire_format")]
+    #[serde(deserialize_with = "deserialize_wire_format")]
+    pub log_format: WireFormat,
+    // commit order offsets in the log producer's transactions instead of auto committing
+    #[serde(default)]
+    pub exactly_once: bool,
+    // seconds between order book snapshots on matching_book_{product_id}, 0 publishes none
+    #[serde(default)]
+    pub book_interval: u64,
+    // fold match logs into tickers and candles in the engine process
+    #[serde(default)]
+    pub market_data: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,12 +93,37 @@ pub struct LogConfig {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
-    pub product: Product,
-    pub redis: RedisConfig,
+    // a single product, kept for configs written before `products`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<Product>,
+    // every product gets its own engine in the same process
+    #[serde(default)]
+    pub products: Vec<Product>,
+    // only needed when snapshots are kept in redis
+    #[serde(default)]
+    pub redis: Option<RedisConfig>,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
     pub kafka: KafkaConfig,
     pub log: LogConfig,
 }
 
+impl Config {
+    /// All configured products, `product` first.
+    pub fn all_products(&self) -> Vec<Product> {
+        self.product
+            .iter()
+            .chain(self.products.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn find_product(&self, product_id: &str) -> Option<Product> {
+        self.all_products().into_iter().find(|p| p.id == product_id)
+    }
+}
+
 pub async fn read_config() -> Config {
     let mut file = File::open(CONFIG_FILE_NAME)
         .await
@@ -49,3 +140,44 @@ pub async fn read_config() -> Config {
 
     config
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::models::types::{Compression, SnapshotStoreType};
+
+    #[test]
+    fn test_config_products() {
+        let string = r#"{
+            "product": {"id": "BTC-USD", "base_currency": "BTC", "quote_currency": "USD", "base_scale": 6, "quote_scale": 2},
+            "products": [{"id": "ETH-USD", "base_currency": "ETH", "quote_currency": "USD", "base_scale": 6, "quote_scale": 2}],
+            "redis": {"ip": "127.0.0.1", "port": 6379},
+            "kafka": {"brokers": ["127.0.0.1:9092"], "message_timeout": 40, "session_timeout": 10, "group_id": "g"},
+            "log": {"level": "info"}
+        }"#;
+        let config: Config = serde_json::from_str(string).unwrap();
+
+        let ids: Vec<String> = config.all_products().into_iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec!["BTC-USD", "ETH-USD"]);
+        assert_eq!(config.find_product("ETH-USD").unwrap().base_currency, "ETH");
+        assert!(config.find_product("LTC-USD").is_none());
+    }
+
+    #[test]
+    fn test_config_snapshot() {
+        let string = r#"{
+            "products": [{"id": "BTC-USD", "base_currency": "BTC", "quote_currency": "USD", "base_scale": 6, "quote_scale": 2}],
+            "snapshot": {"store": "file", "dir": "/var/lib/orderbook", "compression": "gzip"},
+            "kafka": {"brokers": ["127.0.0.1:9092"], "message_timeout": 40, "session_timeout": 10, "group_id": "g"},
+            "log": {"level": "info"}
+        }"#;
+        let config: Config = serde_json::from_str(string).unwrap();
+
+        assert!(config.redis.is_none());
+        assert_eq!(config.snapshot.store, SnapshotStoreType::SnapshotStoreTypeFile);
+        assert_eq!(config.snapshot.effective_dir(), "/var/lib/orderbook");
+        assert_eq!(config.snapshot.compression, Compression::CompressionGzip);
+        // every snapshot is kept unless a retention is set
+        assert_eq!(config.snapshot.retention, 0);
+    }
+}
@@ -1,14 +1,25 @@
+use std::collections::HashSet;
 use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
 
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{error, info, LevelFilter};
+use tokio::join;
+use tokio::time::Duration;
 
-use crate::config::read_config;
+use crate::config::{read_config, Config};
 use crate::matching::engine::Engine;
+use crate::matching::file_store::FileSnapshotStore;
+use crate::matching::kafka_book::KafkaBookPublisher;
 use crate::matching::kafka_log::KafkaLogStore;
+use crate::matching::kafka_market_data::{KafkaMarketDataPublisher, KafkaMatchReader};
 use crate::matching::kafka_order::KafkaOrderReader;
+use crate::matching::market_data::MarketDataLogSink;
 use crate::matching::redis_snapshot::RedisSnapshotStore;
+use crate::matching::store::{LogSink, SnapshotStore};
+use crate::models::models::Product;
+use crate::models::types::SnapshotStoreType;
 
 mod config;
 mod matching;
@@ -31,38 +42,192 @@ fn init_log(level: &str) {
         .init();
 }
 
-#[tokio::main]
-async fn main() {
-    let config = read_config().await;
+/// Restore the engine of one product from its snapshots and run it.
+async fn run_engine<S: SnapshotStore, L: LogSink + Send>(
+    config: &Config,
+    product: &Product,
+    snapshot_store: &mut S,
+    order_reader: &mut KafkaOrderReader,
+    log_store: &mut L,
+) {
+    let mut engine = Engine::new(product, snapshot_store).await;
+    engine.full_snapshot_interval = config.snapshot.effective_full_interval();
 
-    init_log(&config.log.level);
+    if config.kafka.book_interval == 0 {
+        info!("engine started: product={}", product.id);
+        engine.start(snapshot_store, order_reader, log_store).await;
+        return;
+    }
 
-    let mut snapshot_store = RedisSnapshotStore::new_redis_snapshot_store(
-        &config.product.id,
-        &config.redis.ip,
-        config.redis.port,
-    )
-    .await
-    .unwrap_or_else(|e| panic!("{}", e));
+    let mut book_publisher = match KafkaBookPublisher::new_kafka_book_publisher(
+        &config.kafka.brokers,
+        &product.id,
+        config.kafka.message_timeout,
+        &config.kafka.log_format,
+    ) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{}: {}", product.id, e);
+            return;
+        }
+    };
+    let book_rx = engine.publish_book(Duration::from_secs(config.kafka.book_interval));
 
-    let mut order_reader = KafkaOrderReader::new_kafka_order_consumer(
+    info!("engine started: product={}", product.id);
+    join!(
+        engine.start(snapshot_store, order_reader, log_store),
+        Engine::run_book_publisher(&product.id, book_rx, &mut book_publisher),
+    );
+}
+
+/// Run the engine of one product until it stops. Errors and panics only end this product.
+async fn run_product(config: Config, product: Product) {
+    let mut order_reader = match KafkaOrderReader::new_kafka_order_consumer(
         &config.kafka.brokers,
         &config.kafka.group_id,
-        &config.product.id,
+        &product.id,
         config.kafka.session_timeout,
-    )
-    .unwrap_or_else(|e| panic!("{}", e));
+        &config.kafka.order_format,
+        config.kafka.exactly_once,
+    ) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{}: {}", product.id, e);
+            return;
+        }
+    };
+
+    let mut log_store = match KafkaLogStore::new_kafka_log_producer(
+        &config.kafka.brokers,
+        &product.id,
+        config.kafka.message_timeout,
+        &config.kafka.log_format,
+    ) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{}: {}", product.id, e);
+            return;
+        }
+    };
+
+    if config.kafka.exactly_once
+        && let Err(e) = log_store.commit_order_offsets(&order_reader)
+    {
+        error!("{}: {}", product.id, e);
+        return;
+    }
+
+    if !config.kafka.market_data {
+        run_with_log_store(&config, &product, &mut order_reader, &mut log_store).await;
+        return;
+    }
 
-    let mut log_store = KafkaLogStore::new_kafka_log_producer(
+    let market_data_publisher = match KafkaMarketDataPublisher::new_kafka_market_data_publisher(
         &config.kafka.brokers,
-        &config.product.id,
+        &product.id,
         config.kafka.message_timeout,
-    )
-    .unwrap_or_else(|e| panic!("{}", e));
+        &config.kafka.log_format,
+    ) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{}: {}", product.id, e);
+            return;
+        }
+    };
+    let mut log_store =
+        MarketDataLogSink::new_market_data_log_sink(&product.id, log_store, market_data_publisher);
+
+    // continue the candles of the log topic, an empty aggregator would overwrite them
+    let caught_up = match KafkaMatchReader::new_kafka_match_reader(&config.kafka.brokers, &product.id) {
+        Ok(mut r) => r.catch_up(&mut log_store.aggregator).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = caught_up {
+        error!("{}: {}", product.id, e);
+        return;
+    }
+    run_with_log_store(&config, &product, &mut order_reader, &mut log_store).await;
+}
+
+/// Open the configured snapshot store and run the engine with it.
+async fn run_with_log_store<L: LogSink + Send>(
+    config: &Config,
+    product: &Product,
+    order_reader: &mut KafkaOrderReader,
+    log_store: &mut L,
+) {
+    let snapshot = &config.snapshot;
+    match snapshot.store {
+        SnapshotStoreType::SnapshotStoreTypeRedis => {
+            let Some(redis) = &config.redis else {
+                error!("{}: snapshot store redis needs a redis config", product.id);
+                return;
+            };
+            let mut snapshot_store = match RedisSnapshotStore::new_redis_snapshot_store(
+                &product.id,
+                &redis.ip,
+                redis.port,
+                snapshot.retention,
+            )
+            .await
+            {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("{}: {}", product.id, e);
+                    return;
+                }
+            };
+            run_engine(config, product, &mut snapshot_store, order_reader, log_store).await;
+        }
+        SnapshotStoreType::SnapshotStoreTypeFile => {
+            let dir = Path::new(snapshot.effective_dir()).join(&product.id);
+            let mut snapshot_store = FileSnapshotStore::new_file_snapshot_store(
+                &dir.to_string_lossy(),
+                snapshot.retention,
+                &snapshot.compression,
+            );
+            run_engine(config, product, &mut snapshot_store, order_reader, log_store).await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = read_config().await;
+
+    init_log(&config.log.level);
+
+    let products = config.all_products();
+    if products.is_empty() {
+        panic!("no product configured");
+    }
+    // the chain of a delta snapshot must fit in the history, 0 keeps all of it
+    if config.snapshot.retention > 0
+        && config.snapshot.retention < config.snapshot.effective_full_interval()
+    {
+        panic!(
+            "snapshot retention {} is shorter than the full snapshot interval {}",
+            config.snapshot.retention,
+            config.snapshot.effective_full_interval()
+        );
+    }
+    let mut product_ids = HashSet::new();
+    for product in &products {
+        if !product_ids.insert(product.id.clone()) {
+            panic!("product {} configured twice", product.id);
+        }
+    }
 
-    let mut engine = Engine::new(&config.product, &mut snapshot_store).await;
+    let mut engines = Vec::new();
+    for product in products {
+        let product_id = product.id.clone();
+        engines.push((product_id, tokio::spawn(run_product(config.clone(), product))));
+    }
 
-    engine
-        .start(&mut snapshot_store, &mut order_reader, &mut log_store)
-        .await;
+    for (product_id, engine) in engines {
+        match engine.await {
+            Ok(()) => error!("engine stopped: product={}", product_id),
+            Err(e) => error!("engine failed: product={} {}", product_id, e),
+        }
+    }
 }
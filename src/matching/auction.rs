@@ -0,0 +1,167 @@
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+use crate::matching::depth::{AskDepth, BidDepth};
+use crate::matching::order_book::BookOrder;
+
+/// The single price an auction uncrosses at, with the volume it executes and the size left
+/// over on one side. A positive imbalance is a buy surplus, a negative one a sell surplus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Equilibrium {
+    pub price: Decimal,
+    pub volume: Decimal,
+    pub imbalance: Decimal,
+}
+
+/// Total size, hidden reserves included, resting at each price of a depth.
+fn price_levels<'a>(orders: impl Iterator<Item = &'a BookOrder>) -> BTreeMap<Decimal, Decimal> {
+    let mut levels: BTreeMap<Decimal, Decimal> = BTreeMap::new();
+    for order in orders {
+        *levels.entry(order.price).or_default() += order.size + order.hidden_size;
+    }
+    levels
+}
+
+/// Find the equilibrium price of a crossed book. Every resting price is a candidate, and
+/// the winner is picked by, in order:
+///
+/// 1. the largest executable volume
+/// 2. the smallest absolute imbalance
+/// 3. market pressure: the highest price if every remaining candidate has a buy surplus,
+///    the lowest if every one has a sell surplus
+/// 4. the price closest to `reference_price`, then the lowest price
+///
+/// Returns `None` when nothing would execute.
+pub fn find_equilibrium(
+    bids: &BidDepth,
+    asks: &AskDepth,
+    reference_price: &Decimal,
+) -> Option<Equilibrium> {
+    let bid_levels = price_levels(bids.orders.values());
+    let ask_levels = price_levels(asks.orders.values());
+
+    let mut prices: Vec<Decimal> = bid_levels.keys().chain(ask_levels.keys()).cloned().collect();
+    prices.sort();
+    prices.dedup();
+
+    // cumulative sell volume at or below each price, and buy volume at or above it
+    let mut sell_volumes: Vec<Decimal> = Vec::with_capacity(prices.len());
+    let mut sell_volume = Decimal::ZERO;
+    for price in &prices {
+        sell_volume += ask_levels.get(price).cloned().unwrap_or_default();
+        sell_volumes.push(sell_volume);
+    }
+    let mut buy_volumes: Vec<Decimal> = vec![Decimal::ZERO; prices.len()];
+    let mut buy_volume = Decimal::ZERO;
+    for (i, price) in prices.iter().enumerate().rev() {
+        buy_volume += bid_levels.get(price).cloned().unwrap_or_default();
+        buy_volumes[i] = buy_volume;
+    }
+
+    let candidates: Vec<Equilibrium> = prices
+        .iter()
+        .enumerate()
+        .map(|(i, price)| Equilibrium {
+            price: *price,
+            volume: Decimal::min(buy_volumes[i], sell_volumes[i]),
+            imbalance: buy_volumes[i] - sell_volumes[i],
+        })
+        .collect();
+
+    let max_volume = candidates.iter().map(|c| c.volume).max()?;
+    if max_volume.is_zero() {
+        return None;
+    }
+    let candidates: Vec<Equilibrium> = candidates
+        .into_iter()
+        .filter(|c| c.volume == max_volume)
+        .collect();
+
+    let min_imbalance = candidates.iter().map(|c| c.imbalance.abs()).min()?;
+    let candidates: Vec<Equilibrium> = candidates
+        .into_iter()
+        .filter(|c| c.imbalance.abs() == min_imbalance)
+        .collect();
+
+    // candidates are in ascending price order
+    if candidates.iter().all(|c| c.imbalance > Decimal::ZERO) {
+        return candidates.last().cloned();
+    }
+    if candidates.iter().all(|c| c.imbalance < Decimal::ZERO) {
+        return candidates.first().cloned();
+    }
+
+    candidates
+        .into_iter()
+        .min_by_key(|c| ((c.price - reference_price).abs(), c.price))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    use crate::matching::auction::find_equilibrium;
+    use crate::matching::depth::{AskDepth, BidDepth};
+    use crate::matching::order_book::BookOrder;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn make_depths(bids: &[(&str, &str)], asks: &[(&str, &str)]) -> (BidDepth, AskDepth) {
+        let mut bid_depth = BidDepth::default();
+        let mut ask_depth = AskDepth::default();
+        let mut order_id = 0;
+        for (depth_side, levels) in [(true, bids), (false, asks)] {
+            for (price, size) in levels {
+                order_id += 1;
+                let order = BookOrder {
+                    order_id,
+                    price: dec(price),
+                    size: dec(size),
+                    ..Default::default()
+                };
+                if depth_side {
+                    bid_depth.add(&order);
+                } else {
+                    ask_depth.add(&order);
+                }
+            }
+        }
+        (bid_depth, ask_depth)
+    }
+
+    #[test]
+    fn test_equilibrium_maximizes_volume() {
+        let (bids, asks) = make_depths(
+            &[("102", "1"), ("101", "2"), ("100", "3")],
+            &[("99", "1"), ("100", "2"), ("101", "2")],
+        );
+        let e = find_equilibrium(&bids, &asks, &Decimal::ZERO).unwrap();
+        // both 100 (buy 6, sell 3) and 101 (buy 3, sell 5) execute 3, 101 leaves less over
+        assert_eq!(e.price, dec("101"));
+        assert_eq!(e.volume, dec("3"));
+        assert_eq!(e.imbalance, dec("-2"));
+    }
+
+    #[test]
+    fn test_equilibrium_uncrossed_book() {
+        let (bids, asks) = make_depths(&[("99", "1")], &[("100", "1")]);
+        assert!(find_equilibrium(&bids, &asks, &Decimal::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_equilibrium_tie_breakers() {
+        // volume 1 and no imbalance anywhere from 100 to 101
+        let (bids, asks) = make_depths(&[("101", "1")], &[("100", "1")]);
+        assert_eq!(find_equilibrium(&bids, &asks, &dec("110")).unwrap().price, dec("101"));
+        assert_eq!(find_equilibrium(&bids, &asks, &dec("90")).unwrap().price, dec("100"));
+
+        // a buy surplus on every candidate pushes the price up
+        let (bids, asks) = make_depths(&[("101", "3")], &[("100", "1")]);
+        let e = find_equilibrium(&bids, &asks, &dec("90")).unwrap();
+        assert_eq!(e.price, dec("101"));
+        assert_eq!(e.imbalance, dec("2"));
+    }
+}
@@ -0,0 +1,253 @@
+use std::result::Result;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::matching::log::{
+    AuctionLog, ChangeLog, DoneLog, L2UpdateLog, LogTrait, MatchLog, OpenLog, ReceivedLog,
+    StatusLog, TransferLog, TriggerLog,
+};
+use crate::matching::market_data::{Candle, Ticker};
+use crate::matching::order_book::L3Snapshot;
+use crate::models::models::Order;
+use crate::models::types::WireFormat;
+use crate::utils::error::CustomError;
+
+// A binary message is a two byte header followed by a MessagePack body. The magic byte can
+// never start a JSON text, and the version changes whenever the layout of the body does.
+//
+// Version 2 writes every struct as an array of its fields in declaration order, so the
+// field order of the encoded types is the schema: a field may only be appended, with
+// `#[serde(default)]` so shorter messages still decode, anything else needs a new version.
+// Optional fields are written even without a value, so they always keep their slot.
+// Version 1 wrote maps keyed by field name and is still read.
+const BINARY_MAGIC: u8 = 0xb7;
+const BINARY_VERSION: u8 = 2;
+const BINARY_VERSION_NAMED: u8 = 1;
+
+fn encode<T: Serialize + ?Sized>(value: &T, format: &WireFormat) -> Result<Vec<u8>, CustomError> {
+    match format {
+        WireFormat::WireFormatJson => serde_json::to_vec(value).map_err(|e| CustomError::new(&e)),
+        WireFormat::WireFormatBinary => {
+            // decimals and enums are written as the same strings as in JSON so they
+            // round-trip exactly, optional fields are never skipped so they keep their slot
+            let body = rmp_serde::to_vec(value).map_err(|e| CustomError::new(&e))?;
+            let mut buf = Vec::with_capacity(body.len() + 2);
+            buf.extend_from_slice(&[BINARY_MAGIC, BINARY_VERSION]);
+            buf.extend(body);
+            Ok(buf)
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(payload: &[u8], format: &WireFormat) -> Result<T, CustomError> {
+    match format {
+        WireFormat::WireFormatJson => serde_json::from_slice(payload).map_err(|e| CustomError::new(&e)),
+        WireFormat::WireFormatBinary => match payload {
+            // structs decode from an array of fields as well as from a map of them
+            [BINARY_MAGIC, BINARY_VERSION | BINARY_VERSION_NAMED, body @ ..] => {
+                rmp_serde::from_slice(body).map_err(|e| CustomError::new(&e))
+            }
+            [BINARY_MAGIC, version, ..] => Err(CustomError::from_string(format!(
+                "unsupported binary version {}",
+                version
+            ))),
+            _ => Err(CustomError::from_string("missing binary header".to_string())),
+        },
+    }
+}
+
+/// Re-encode a log decoded without its type into the JSON form of its type.
+fn to_json_log<'de, T: Deserialize<'de> + Serialize>(
+    value: &'de serde_json::Value,
+) -> Result<serde_json::Value, CustomError> {
+    let log = T::deserialize(value).map_err(|e| CustomError::new(&e))?;
+    serde_json::to_value(&log).map_err(|e| CustomError::new(&e))
+}
+
+/// The format a payload was written in, told apart by the binary header.
+pub fn payload_format(payload: &[u8]) -> WireFormat {
+    match payload.first() {
+        Some(&BINARY_MAGIC) => WireFormat::WireFormatBinary,
+        _ => WireFormat::WireFormatJson,
+    }
+}
+
+pub fn encode_order(order: &Order, format: &WireFormat) -> Result<Vec<u8>, CustomError> {
+    encode(order, format)
+}
+
+pub fn decode_order(payload: &[u8], format: &WireFormat) -> Result<Order, CustomError> {
+    decode(payload, format)
+}
+
+pub fn encode_log(log: &dyn LogTrait, format: &WireFormat) -> Result<Vec<u8>, CustomError> {
+    encode(log, format)
+}
+
+/// Decode a log of any type into its JSON form, for consumers of the log topic.
+pub fn decode_log(payload: &[u8], format: &WireFormat) -> Result<serde_json::Value, CustomError> {
+    let value: serde_json::Value = decode(payload, format)?;
+    // a positional log only tells its fields apart through the type in its base
+    if !value.is_array() {
+        return Ok(value);
+    }
+    match value[0][0].as_str() {
+        Some("match") => to_json_log::<MatchLog>(&value),
+        Some("open") => to_json_log::<OpenLog>(&value),
+        Some("done") => to_json_log::<DoneLog>(&value),
+        Some("trigger") => to_json_log::<TriggerLog>(&value),
+        Some("received") => to_json_log::<ReceivedLog>(&value),
+        Some("change") => to_json_log::<ChangeLog>(&value),
+        Some("status") => to_json_log::<StatusLog>(&value),
+        Some("auction") => to_json_log::<AuctionLog>(&value),
+        Some("l2update") => to_json_log::<L2UpdateLog>(&value),
+        Some("transfer") => to_json_log::<TransferLog>(&value),
+        _ => Err(CustomError::from_string(format!(
+            "unknown log type {}",
+            value[0][0]
+        ))),
+    }
+}
+
+pub fn encode_ticker(ticker: &Ticker, format: &WireFormat) -> Result<Vec<u8>, CustomError> {
+    encode(ticker, format)
+}
+
+pub fn encode_candle(candle: &Candle, format: &WireFormat) -> Result<Vec<u8>, CustomError> {
+    encode(candle, format)
+}
+
+pub fn encode_book(book: &L3Snapshot, format: &WireFormat) -> Result<Vec<u8>, CustomError> {
+    encode(book, format)
+}
+
+pub fn decode_book(payload: &[u8], format: &WireFormat) -> Result<L3Snapshot, CustomError> {
+    decode(payload, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::codec::{decode_log, decode_order, encode_log, encode_order};
+    use crate::matching::log::{new_done_log, new_match_log, new_status_log, LogTrait};
+    use crate::matching::order_book::BookOrder;
+    use crate::models::models::{Order, Product};
+    use crate::models::types::{
+        DoneReason, MarketState, OrderType, RejectReason, SelfTradePrevention, Side, TimeInForceType,
+        WireFormat,
+    };
+
+    fn make_order(market_state: Option<MarketState>) -> Order {
+        Order {
+            id: 7,
+            created_at: 1695783003020967000,
+            user_id: 3,
+            client_oid: "oid".to_string(),
+            funds: Decimal::from_str("79228162514264337593543950335").unwrap(),
+            stop_price: Decimal::from_str("-0.50").unwrap(),
+            r#type: OrderType::OrderTypeStopLimit,
+            time_in_force: TimeInForceType::GoodTillTime,
+            expire_time: 1695783063020967000,
+            stp: SelfTradePrevention::SelfTradePreventionCancelBoth,
+            market_state,
+            maker_fee_bps: Some(Decimal::from_str("-2.5").unwrap()),
+            ..Order::new_test_order(7, 3, Side::SideSell, "0.000001", "1000.10")
+        }
+    }
+
+    #[test]
+    fn test_order_round_trip() {
+        for market_state in [None, Some(MarketState::MarketStateAuction)] {
+            let order = make_order(market_state);
+            let payload = encode_order(&order, &WireFormat::WireFormatBinary).unwrap();
+            let decoded = decode_order(&payload, &WireFormat::WireFormatBinary).unwrap();
+            // trailing zeros are part of a decimal, compare the exact text
+            assert_eq!(
+                serde_json::to_string(&decoded).unwrap(),
+                serde_json::to_string(&order).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_log_round_trip() {
+        let maker = BookOrder {
+            order_id: 1,
+            user_id: 1,
+            price: Decimal::from_str("1000.10").unwrap(),
+            size: Decimal::from_str("2.50").unwrap(),
+            side: Side::SideBuy,
+            ..Default::default()
+        };
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            quote_scale: 2,
+            maker_fee_bps: Decimal::from_str("-1").unwrap(),
+            taker_fee_bps: Decimal::from_str("5").unwrap(),
+            ..Default::default()
+        };
+        let order = make_order(None);
+        let mut done_log = new_done_log(2, 10, "BTC-USD", &maker, &maker.size, &DoneReason::DoneReasonRejected);
+        done_log.reject_reason = Some(RejectReason::RejectReasonPriceBand);
+        let logs: Vec<Box<dyn LogTrait>> = vec![
+            Box::new(new_match_log(1, 10, &product, 1, &maker, &maker, &maker.price, &maker.size)),
+            Box::new(done_log),
+            // without a reject reason in the middle of the fields
+            Box::new(new_done_log(4, 10, "BTC-USD", &maker, &maker.size, &DoneReason::DoneReasonFilled)),
+            Box::new(new_status_log(
+                3,
+                10,
+                "BTC-USD",
+                &MarketState::MarketStateHalted,
+                &order.price,
+                &order.stop_price,
+                20,
+            )),
+        ];
+        for log in logs {
+            let json = encode_log(log.as_ref(), &WireFormat::WireFormatJson).unwrap();
+            let binary = encode_log(log.as_ref(), &WireFormat::WireFormatBinary).unwrap();
+            assert!(binary.len() < json.len());
+            // positional, the body is an array of fields rather than a map
+            assert!(matches!(binary[2], 0x90..=0x9f | 0xdc));
+            assert_eq!(
+                decode_log(&binary, &WireFormat::WireFormatBinary).unwrap(),
+                decode_log(&json, &WireFormat::WireFormatJson).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_binary_header() {
+        let order = make_order(None);
+        let mut payload = encode_order(&order, &WireFormat::WireFormatBinary).unwrap();
+        let json = encode_order(&order, &WireFormat::WireFormatJson).unwrap();
+        assert!(decode_order(&json, &WireFormat::WireFormatBinary).is_err());
+        payload[1] = 3;
+        assert!(decode_order(&payload, &WireFormat::WireFormatBinary).is_err());
+    }
+
+    #[test]
+    fn test_named_version_still_read() {
+        let order = make_order(None);
+        let mut payload = vec![0xb7, 1];
+        rmp_serde::encode::write_named(&mut payload, &order).unwrap();
+        let decoded = decode_order(&payload, &WireFormat::WireFormatBinary).unwrap();
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            serde_json::to_string(&order).unwrap()
+        );
+
+        let log = new_status_log(3, 10, "BTC-USD", &MarketState::MarketStateHalted, &order.price, &order.stop_price, 20);
+        let mut payload = vec![0xb7, 1];
+        rmp_serde::encode::write_named(&mut payload, &log).unwrap();
+        assert_eq!(
+            decode_log(&payload, &WireFormat::WireFormatBinary).unwrap(),
+            serde_json::to_value(&log).unwrap()
+        );
+    }
+}
@@ -1,22 +1,88 @@
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap};
-use std::ops::Sub;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::{Add, Sub};
 
 use crate::matching::order_book::BookOrder;
 use crate::matching::ordering::{OrderingTrait, PriceOrderIdKeyAsc, PriceOrderIdKeyDesc};
 use crate::utils::error::CustomError;
 
+/// Visible size and number of orders resting at one price.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PriceLevel {
+    pub price: Decimal,
+    pub size: Decimal,
+    pub count: u64,
+}
+
+#[derive(Default)]
 pub struct Depth<T: OrderingTrait + Ord> {
     pub orders: HashMap<u64, BookOrder>,
     pub queue: BTreeMap<T, u64>,
+    // one entry per price keyed like the queue with a zero priority, best price first
+    pub levels: BTreeMap<T, PriceLevel>,
+    // prices whose level changed since the last call to take_changed_levels
+    pub changed_levels: BTreeSet<Decimal>,
+    // orders added, changed or removed since the last snapshot, see OrderBook::delta
+    pub changed: HashSet<u64>,
 }
 
 impl<T: OrderingTrait + Ord> Depth<T> {
+    /// Move the level at `price` by `size` and `count` orders, dropping it once empty.
+    fn update_level(&mut self, price: &Decimal, size: &Decimal, count: i64) {
+        let level = self
+            .levels
+            .entry(T::with_priority(price, 0, 0))
+            .or_insert_with(|| PriceLevel {
+                price: *price,
+                ..Default::default()
+            });
+        level.size = level.size.add(size);
+        level.count = level.count.saturating_add_signed(count);
+        if level.count == 0 {
+            self.levels.remove(&T::with_priority(price, 0, 0));
+        }
+        self.changed_levels.insert(*price);
+    }
+
+    /// The level at `price`, empty if no order rests there.
+    pub fn level(&self, price: &Decimal) -> PriceLevel {
+        match self.levels.get(&T::with_priority(price, 0, 0)) {
+            Some(level) => level.clone(),
+            None => PriceLevel {
+                price: *price,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The best `n` price levels, best first.
+    pub fn top_levels(&self, n: usize) -> Vec<PriceLevel> {
+        self.levels.values().take(n).cloned().collect()
+    }
+
+    /// The current state of every level changed since the last call, best first. A level
+    /// that emptied has a zero size and count.
+    pub fn take_changed_levels(&mut self) -> Vec<PriceLevel> {
+        let prices = std::mem::take(&mut self.changed_levels);
+        let mut levels: Vec<PriceLevel> = prices.iter().map(|p| self.level(p)).collect();
+        levels.sort_by(|a, b| T::with_priority(&a.price, 0, 0).cmp(&T::with_priority(&b.price, 0, 0)));
+        levels
+    }
+
     pub fn add(&mut self, order: &BookOrder) {
-        self.orders.insert(order.order_id, order.clone());
-        self.queue
-            .insert(T::new(&order.price, order.order_id), order.order_id);
+        let mut order = order.clone();
+        if order.priority == 0 {
+            order.priority = order.order_id;
+        }
+        self.queue.insert(
+            T::with_priority(&order.price, order.priority, order.order_id),
+            order.order_id,
+        );
+        self.changed.insert(order.order_id);
+        self.update_level(&order.price, &order.size, 1);
+        self.orders.insert(order.order_id, order);
     }
 
     pub fn decr_size(&mut self, order_id: u64, size: &Decimal) -> Result<(), CustomError> {
@@ -31,11 +97,29 @@ impl<T: OrderingTrait + Ord> Depth<T> {
                     ))),
                     _ => {
                         order.size = order.size.sub(size);
+                        let left = if order.size.is_zero() { -1 } else { 0 };
+                        self.update_level(&order.price, &-size, left);
                         if order.size.is_zero() {
-                            self.orders.remove(&order_id);
-                            self.queue.remove(&T::new(&order.price, order.order_id));
+                            self.queue.remove(&T::with_priority(
+                                &order.price,
+                                order.priority,
+                                order.order_id,
+                            ));
+                            self.changed.insert(order_id);
+                            if order.hidden_size.is_zero() {
+                                self.orders.remove(&order_id);
+                            } else {
+                                // Iceberg: show the next slice from the reserve, it loses its
+                                // time priority and joins the back of the price level
+                                let slice = Decimal::min(order.display_size, order.hidden_size);
+                                order.size = slice;
+                                order.hidden_size = order.hidden_size.sub(slice);
+                                order.priority = self.next_priority(&order.price, order.priority);
+                                self.add(&order);
+                            }
                         } else {
                             // Partial fill: update the order in HashMap with reduced size
+                            self.changed.insert(order_id);
                             self.orders.insert(order_id, order);
                         }
                         Ok(())
@@ -49,13 +133,60 @@ impl<T: OrderingTrait + Ord> Depth<T> {
             ))),
         }
     }
+
+    /// Reduce the total size of an order without touching its queue position, the hidden
+    /// reserve of an iceberg order is used up before its visible slice.
+    pub fn reduce_size(&mut self, order_id: u64, size: &Decimal) -> Result<(), CustomError> {
+        let order = match self.orders.get_mut(&order_id) {
+            Some(order) => order,
+            // order not found in order book (maybe some fatal issue)
+            None => {
+                return Err(CustomError::from_string(format!(
+                    "order {} not found on book",
+                    order_id
+                )));
+            }
+        };
+
+        let from_hidden = Decimal::min(order.hidden_size, *size);
+        order.hidden_size = order.hidden_size.sub(from_hidden);
+        self.changed.insert(order_id);
+
+        let from_visible = size.sub(from_hidden);
+        if from_visible.is_zero() {
+            return Ok(());
+        }
+        self.decr_size(order_id, &from_visible)
+    }
+
+    /// Remove an order from the book, including any hidden reserve.
+    pub fn remove(&mut self, order_id: u64) -> Option<BookOrder> {
+        let order = self.orders.remove(&order_id)?;
+        self.queue
+            .remove(&T::with_priority(&order.price, order.priority, order.order_id));
+        self.changed.insert(order_id);
+        self.update_level(&order.price, &-order.size, -1);
+        Some(order)
+    }
+
+    /// Priority that puts an order behind everything currently queued at `price`.
+    pub fn next_priority(&self, price: &Decimal, priority: u64) -> u64 {
+        let last = self
+            .queue
+            .range(..=T::with_priority(price, u64::MAX, u64::MAX))
+            .next_back()
+            .filter(|(k, _)| k.price() == price)
+            .map(|(k, _)| k.priority())
+            .unwrap_or(0);
+        u64::max(last, priority) + 1
+    }
 }
 
 // AskDepth is order by key PriceOrderIdKeyAsc
-// order by price ASC first, and then order id ASC
+// order by price ASC first, and then priority (order id unless re-queued) ASC
 pub type AskDepth = Depth<PriceOrderIdKeyAsc>;
 // BidDepth is order by key PriceOrderIdKeyDesc
-// order by price DESC first, and then order id ASC
+// order by price DESC first, and then priority (order id unless re-queued) ASC
 pub type BidDepth = Depth<PriceOrderIdKeyDesc>;
 
 #[cfg(test)]
@@ -65,27 +196,22 @@ mod tests {
 
     use crate::matching::depth::AskDepth;
     use crate::matching::order_book::BookOrder;
-    use crate::models::types::{OrderType, Side, TimeInForceType};
+    use crate::models::types::Side;
 
     fn make_book_order(order_id: u64, size: &str, price: &str) -> BookOrder {
         BookOrder {
             order_id,
             user_id: 1,
             size: Decimal::from_str(size).unwrap(),
-            funds: Decimal::ZERO,
             price: Decimal::from_str(price).unwrap(),
             side: Side::SideSell,
-            r#type: OrderType::OrderTypeLimit,
-            time_in_force: TimeInForceType::GoodTillCanceled,
+            ..Default::default()
         }
     }
 
     #[test]
     fn test_partial_fill_updates_hashmap() {
-        let mut depth = AskDepth {
-            orders: Default::default(),
-            queue: Default::default(),
-        };
+        let mut depth = AskDepth::default();
 
         // Add maker order: size=10, price=100
         let order = make_book_order(1, "10", "100");
@@ -105,10 +231,7 @@ mod tests {
 
     #[test]
     fn test_full_fill_removes_from_hashmap() {
-        let mut depth = AskDepth {
-            orders: Default::default(),
-            queue: Default::default(),
-        };
+        let mut depth = AskDepth::default();
 
         let order = make_book_order(1, "10", "100");
         depth.add(&order);
@@ -124,10 +247,7 @@ mod tests {
 
     #[test]
     fn test_sequential_partial_fills() {
-        let mut depth = AskDepth {
-            orders: Default::default(),
-            queue: Default::default(),
-        };
+        let mut depth = AskDepth::default();
 
         let order = make_book_order(1, "10", "100");
         depth.add(&order);
@@ -147,10 +267,7 @@ mod tests {
 
     #[test]
     fn test_decr_size_exceeds_available() {
-        let mut depth = AskDepth {
-            orders: Default::default(),
-            queue: Default::default(),
-        };
+        let mut depth = AskDepth::default();
 
         let order = make_book_order(1, "5", "100");
         depth.add(&order);
@@ -159,4 +276,40 @@ mod tests {
         let result = depth.decr_size(1, &Decimal::from_str("6").unwrap());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_iceberg_slice_replenished_at_back_of_level() {
+        let mut depth = AskDepth::default();
+
+        // iceberg: visible 2, reserve 3, slices of 2
+        let mut iceberg = make_book_order(1, "2", "100");
+        iceberg.display_size = Decimal::from_str("2").unwrap();
+        iceberg.hidden_size = Decimal::from_str("3").unwrap();
+        depth.add(&iceberg);
+        depth.add(&make_book_order(2, "1", "100"));
+
+        // exhausting the visible slice shows the next one behind order 2
+        depth.decr_size(1, &Decimal::from_str("2").unwrap()).unwrap();
+        let updated = depth.orders.get(&1).unwrap();
+        assert_eq!(updated.size, Decimal::from_str("2").unwrap());
+        assert_eq!(updated.hidden_size, Decimal::from_str("1").unwrap());
+        let ids: Vec<u64> = depth.queue.values().copied().collect();
+        assert_eq!(ids, vec![2, 1]);
+        // the level shows the visible sizes only
+        let level = depth.level(&Decimal::from_str("100").unwrap());
+        assert_eq!(level.size, Decimal::from_str("3").unwrap());
+        assert_eq!(level.count, 2);
+
+        // the last slice is smaller than the display size
+        depth.decr_size(1, &Decimal::from_str("2").unwrap()).unwrap();
+        assert_eq!(depth.orders.get(&1).unwrap().size, Decimal::from_str("1").unwrap());
+        assert!(depth.orders.get(&1).unwrap().hidden_size.is_zero());
+
+        // removing drops the whole order
+        assert!(depth.remove(1).is_some());
+        assert!(!depth.orders.contains_key(&1));
+        assert_eq!(depth.queue.len(), 1);
+        assert_eq!(depth.top_levels(5)[0].count, 1);
+        assert_eq!(depth.top_levels(5)[0].size, Decimal::from_str("1").unwrap());
+    }
 }
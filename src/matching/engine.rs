@@ -1,23 +1,68 @@
 use log::{debug, error, info};
-use rdkafka::Offset;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::time::{sleep, Duration, interval};
 use tokio::{join, select};
 
-use crate::matching::kafka_log::KafkaLogStore;
-use crate::matching::kafka_order::KafkaOrderReader;
 use crate::matching::log::LogTrait;
-use crate::matching::order_book::{OrderBook, OrderBookSnapshot};
-use crate::matching::redis_snapshot::RedisSnapshotStore;
+use crate::matching::order_book::{L3Snapshot, OrderBook, OrderBookDelta, OrderBookSnapshot};
+use crate::matching::store::{BookPublisher, LogSink, OrderSource, SnapshotInfo, SnapshotStore};
 use crate::models::models::{Order, Product};
 use crate::models::types::{OrderStatus, TimeInForceType};
+use crate::utils::error::CustomError;
 
+// one snapshot in this many is full when none is set
+const DEFAULT_FULL_SNAPSHOT_INTERVAL: usize = 10;
+
+/// A full snapshot carries `order_book_snapshot`. A delta snapshot carries
+/// `order_book_delta` instead, with the changes since the snapshot `base`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Snapshot {
     pub order_book_snapshot: Option<OrderBookSnapshot>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_book_delta: Option<OrderBookDelta>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<SnapshotInfo>,
+    pub order_offset: u64,
+}
+
+impl Snapshot {
+    pub fn is_delta(&self) -> bool {
+        self.order_book_delta.is_some()
+    }
+
+    /// Sequence of the last log included in the snapshot.
+    pub fn log_seq(&self) -> u64 {
+        match (&self.order_book_snapshot, &self.order_book_delta) {
+            (Some(s), _) => s.log_seq,
+            (None, Some(d)) => d.log_seq,
+            (None, None) => 0,
+        }
+    }
+
+    /// Bring a full snapshot forward by a delta taken on top of it.
+    pub fn apply_delta(&mut self, delta: &Snapshot) -> Result<(), CustomError> {
+        let (Some(book), Some(book_delta)) = (&mut self.order_book_snapshot, &delta.order_book_delta) else {
+            return Err(CustomError::from_string(format!(
+                "snapshot {} is not a delta on a full snapshot",
+                SnapshotInfo::of(delta).id()
+            )));
+        };
+        book.apply_delta(book_delta);
+        self.order_offset = delta.order_offset;
+        Ok(())
+    }
+}
+
+/// Asks the applier for a snapshot. `stored` is the last snapshot stored, if the next
+/// one may be a delta on top of it.
+#[derive(Debug, Clone)]
+pub struct SnapshotRequest {
     pub order_offset: u64,
+    pub stored: Option<SnapshotInfo>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -26,19 +71,30 @@ pub struct OffsetOrder {
     pub order: Order,
 }
 
+/// The logs one order produced, tagged with the order's offset. An order's logs always
+/// travel together so they are never split across two stored batches.
+pub struct OffsetLogs {
+    pub offset: u64,
+    pub logs: Vec<Box<dyn LogTrait>>,
+}
+
 pub struct Engine {
     pub product_id: String,
     pub order_book: OrderBook,
     pub order_offset: u64,
+    // one snapshot in this many is full, the others are deltas, 1 takes only full snapshots
+    pub full_snapshot_interval: usize,
+    // order book snapshots for market data, taken every book_interval once publish_book
+    // was called
+    book_tx: Option<Sender<L3Snapshot>>,
+    book_interval: Duration,
 }
 
 impl Engine {
-    pub async fn new(product: &Product, snapshot_store: &mut RedisSnapshotStore) -> Self {
-        let mut engine = Engine {
-            product_id: product.id.clone(),
-            order_book: OrderBook::new_order_book(product),
-            order_offset: 0,
-        };
+    /// Restore the newest snapshot that loads with its delta chain, falling back to older
+    /// ones if it is corrupted.
+    pub async fn new<S: SnapshotStore>(product: &Product, snapshot_store: &mut S) -> Self {
+        let mut engine = Engine::new_engine(product);
         match snapshot_store.get_latest().await {
             Ok(o) => if let Some(snapshot) = o {
                 engine.restore(&snapshot);
@@ -51,21 +107,63 @@ impl Engine {
         engine
     }
 
-    pub async fn start(
+    /// Restore a chosen snapshot of the history instead of the newest one, for example to
+    /// go back before a bad snapshot. Logs already published after it are not written again.
+    pub async fn new_at<S: SnapshotStore>(
+        product: &Product,
+        snapshot_store: &mut S,
+        info: &SnapshotInfo,
+    ) -> Result<Self, CustomError> {
+        let snapshot = snapshot_store.get_full(info).await?;
+        let mut engine = Engine::new_engine(product);
+        if snapshot.order_book_snapshot.is_some() {
+            engine.restore(&snapshot);
+        }
+        Ok(engine)
+    }
+
+    /// An engine with an empty book, for callers that restore it without Redis.
+    pub fn new_engine(product: &Product) -> Self {
+        Engine {
+            product_id: product.id.clone(),
+            order_book: OrderBook::new_order_book(product),
+            order_offset: 0,
+            full_snapshot_interval: DEFAULT_FULL_SNAPSHOT_INTERVAL,
+            book_tx: None,
+            book_interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Take an order-by-order snapshot of the book every `interval` while the engine runs,
+    /// for `run_book_publisher` to publish from the returned receiver. A snapshot is
+    /// dropped rather than holding up orders if the previous one is still being published.
+    pub fn publish_book(&mut self, interval: Duration) -> Receiver<L3Snapshot> {
+        let (book_tx, book_rx) = mpsc::channel::<L3Snapshot>(1);
+        self.book_tx = Some(book_tx);
+        self.book_interval = interval;
+        book_rx
+    }
+
+    pub async fn start<S: SnapshotStore, O: OrderSource, L: LogSink>(
         &mut self,
-        snapshot_store: &mut RedisSnapshotStore,
-        order_reader: &mut KafkaOrderReader,
-        log_store: &mut KafkaLogStore,
+        snapshot_store: &mut S,
+        order_reader: &mut O,
+        log_store: &mut L,
     ) {
-        let (log_tx, log_rx) = mpsc::channel::<Box<dyn LogTrait>>(10000);
+        let (log_tx, log_rx) = mpsc::channel::<OffsetLogs>(10000);
         let (order_tx, order_rx) = mpsc::channel::<OffsetOrder>(10000);
-        let (snapshot_req_tx, snapshot_req_rx) = mpsc::channel::<Snapshot>(32);
+        let (snapshot_req_tx, snapshot_req_rx) = mpsc::channel::<SnapshotRequest>(32);
         let (snapshot_approve_req_tx, snapshot_approve_req_rx) = mpsc::channel::<Snapshot>(32);
         let (snapshot_tx, snapshot_rx) = mpsc::channel::<Snapshot>(32);
 
         let product_id = self.product_id.clone();
         let order_offset = self.order_offset;
-        let log_seq = self.order_book.log_seq;
+        // logs the sink already has, from before a crash, are regenerated from the
+        // snapshot and must not be written again
+        let log_seq = match log_store.last_seq().await {
+            Ok(seq) => u64::max(self.order_book.log_seq, seq),
+            Err(e) => panic!("read last log seq: {}", e),
+        };
 
         let fut1 = Engine::run_fetcher(order_offset, order_reader, order_tx);
 
@@ -102,22 +200,17 @@ impl Engine {
             .restore(&snapshot.order_book_snapshot.clone().unwrap());
     }
 
-    pub async fn run_fetcher(
+    pub async fn run_fetcher<O: OrderSource>(
         order_offset: u64,
-        order_reader: &mut KafkaOrderReader,
+        order_reader: &mut O,
         order_tx: Sender<OffsetOrder>,
     ) {
-        let offset = if order_offset == 0 {
-            Offset::Beginning
-        } else {
-            Offset::Offset(order_offset as i64 + 1)
-        };
-
-        if let Err(e) = order_reader.set_offset(offset).await {
+        if let Err(e) = order_reader.seek(order_offset).await {
             panic!("set order reader offset error: {}", e);
         }
 
-        loop {
+        // the fetcher is the first task to stop, dropping order_tx stops the others in turn
+        while !order_reader.is_exhausted() {
             match order_reader.fetch_order().await {
                 Err(e) => {
                     error!("{}", e);
@@ -142,103 +235,177 @@ impl Engine {
         }
     }
 
+    /// Run one order from the order topic through the book and return the logs it produces.
+    /// This is everything `run_applier` does per order, so an offline replay of the same
+    /// orders gives the same logs.
+    pub fn apply(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let order = &self.order_book.normalize_order(order);
+        // the book only reads the time of the order stream, never the wall clock
+        // orders that expired give back their holds before the order is checked
+        let mut logs = self.order_book.advance_time(OrderBook::clock_time(order));
+        match order.status {
+            OrderStatus::OrderStatusCancelling => {
+                logs.extend(self.order_book.cancel_order(order));
+            }
+            OrderStatus::OrderStatusAmending => {
+                logs.extend(self.order_book.amend_order(order));
+            }
+            OrderStatus::OrderStatusControl if order.transfer.is_some() => {
+                logs.extend(self.order_book.transfer(order));
+            }
+            OrderStatus::OrderStatusControl if order.market_state.is_none() => {
+                // a clock tick only moves the clock, which may end a halt or expire orders
+            }
+            OrderStatus::OrderStatusControl => {
+                logs.extend(self.order_book.set_market_state(order));
+            }
+            OrderStatus::OrderStatusNew if self.order_book.is_duplicate(order) => {
+                // a redelivered order is dropped before it is checked, or a rejected
+                // one would be rejected again
+                info!("duplicate order {}, skipping", order.id);
+            }
+            OrderStatus::OrderStatusNew if let Err(reason) = self.order_book.check_order(order) => {
+                logs.extend(self.order_book.reject_order(order, &reason));
+            }
+            OrderStatus::OrderStatusNew if order.r#type.is_stop() => {
+                // time in force is applied when a stop order gets triggered
+                logs.extend(self.order_book.apply_order(order));
+            }
+            OrderStatus::OrderStatusNew => {
+                match order.time_in_force {
+                    TimeInForceType::ImmediateOrCancel => {
+                        logs.extend(self.order_book.apply_order(order));
+                        let ioc_logs = self.order_book.cancel_order(order);
+                        if !ioc_logs.is_empty() {
+                            logs.extend(ioc_logs);
+                        }
+                    },
+                    TimeInForceType::GoodTillCrossing => {
+                        if self.order_book.is_order_will_not_match(order) {
+                            logs.extend(self.order_book.apply_order(order));
+                        } else {
+                            logs.extend(self.order_book.nullify_order(order));
+                        }
+                    },
+                    TimeInForceType::FillOrKill => {
+                        if self.order_book.is_order_will_full_match(order) {
+                            logs.extend(self.order_book.apply_order(order));
+                        } else {
+                            logs.extend(self.order_book.nullify_order(order));
+                        }
+                    },
+                    TimeInForceType::GoodTillCanceled
+                    | TimeInForceType::GoodTillTime
+                    | TimeInForceType::GoodTillDate => {
+                        logs.extend(self.order_book.apply_order(order));
+                    },
+                }
+            }
+            _ => {
+                // Ignore orders with invalid status (open, partial, filled, cancelled)
+                info!("Ignoring order {} with invalid status: {:?}", order.id, order.status);
+            }
+        }
+        logs.extend(self.order_book.indicate_auction());
+        logs.extend(self.order_book.l2_update());
+        logs
+    }
+
     pub async fn run_applier(
         &mut self,
         order_rx: Receiver<OffsetOrder>,
-        log_tx: Sender<Box<dyn LogTrait>>,
-        snapshot_req_rx: Receiver<Snapshot>,
+        log_tx: Sender<OffsetLogs>,
+        snapshot_req_rx: Receiver<SnapshotRequest>,
         snapshot_approve_req_tx: Sender<Snapshot>,
     ) {
         let mut order_offset = 0u64;
+        // last snapshot taken, and the deltas taken since the last full one. The first
+        // snapshot after a start is always full.
+        let mut taken: Option<SnapshotInfo> = None;
+        let mut deltas = 0usize;
         let mut order_rx = order_rx;
         let mut snapshot_req_rx = snapshot_req_rx;
-        let mut cleanup_interval = interval(Duration::from_secs(10));
+        let mut book_interval = interval(self.book_interval);
 
         loop {
             select! {
-                Some(offset_order) = order_rx.recv() => {
-                    let mut logs= Vec::default();
-                    match offset_order.order.status {
-                        OrderStatus::OrderStatusCancelling => {
-                            logs = self.order_book.cancel_order(&offset_order.order);
-                        }
-                        OrderStatus::OrderStatusNew => {
-                            match offset_order.order.time_in_force {
-                                TimeInForceType::ImmediateOrCancel => {
-                                    logs = self.order_book.apply_order(&offset_order.order);
-                                    let ioc_logs = self.order_book.cancel_order(&offset_order.order);
-                                    if !ioc_logs.is_empty() {
-                                        logs.extend(ioc_logs);
-                                    }
-                                },
-                                TimeInForceType::GoodTillCrossing => {
-                                    if self.order_book.is_order_will_not_match(&offset_order.order) {
-                                        logs = self.order_book.apply_order(&offset_order.order);
-                                    } else {
-                                        logs = self.order_book.nullify_order(&offset_order.order);
-                                    }
-                                },
-                                TimeInForceType::FillOrKill => {
-                                 if self.order_book.is_order_will_full_match(&offset_order.order) {
-                                        logs = self.order_book.apply_order(&offset_order.order);
-                                    } else {
-                                        logs = self.order_book.nullify_order(&offset_order.order);
-                                    }
-                                },
-                                TimeInForceType::GoodTillCanceled => {
-                                    logs = self.order_book.apply_order(&offset_order.order);
-                                },
+                offset_order = order_rx.recv() => {
+                    // every order fetched is applied, publish the final book and stop
+                    let Some(offset_order) = offset_order else {
+                        if let Some(book_tx) = self.book_tx.take()
+                            && let Err(e) = book_tx.send(self.order_book.l3_snapshot()).await {
+                                error!("{}", e);
                             }
-                        }
-                        _ => {
-                            // Ignore orders with invalid status (open, partial, filled, cancelled)
-                            info!("Ignoring order {} with invalid status: {:?}", offset_order.order.id, offset_order.order.status);
-                        }
-                    }
+                        break;
+                    };
+                    let logs = self.apply(&offset_order.order);
 
-                    for log in logs {
-                        if let Err(e) = log_tx.send(log).await{
-                            error!("{}", e);
-                            continue;
-                        }
+                    // sent even without logs, so the committer sees every order offset
+                    if let Err(e) = log_tx.send(OffsetLogs {
+                        offset: offset_order.offset,
+                        logs,
+                    }).await {
+                        error!("{}", e);
                     }
 
                     order_offset = offset_order.offset;
                 },
-                Some(mut snapshot) = snapshot_req_rx.recv() => {
-                    let delta: i64 = order_offset as i64 - snapshot.order_offset as i64;
+                Some(request) = snapshot_req_rx.recv() => {
+                    let delta: i64 = order_offset as i64 - request.order_offset as i64;
                     if delta <= 1000 {
                         continue;
                     }
 
-                    // Cleanup expired orders from time window before taking snapshot
-                    self.order_book.cleanup_time_window();
-
                     info!("should take snapshot: {} {}-[{}]-{}->",
-                        self.product_id, snapshot.order_offset, delta, order_offset);
-
-                    snapshot.order_book_snapshot = Some(self.order_book.snapshot());
-                    snapshot.order_offset = order_offset;
+                        self.product_id, request.order_offset, delta, order_offset);
+
+                    // a delta only builds on the last snapshot taken once it is stored,
+                    // otherwise the changes recorded since then are not in the store
+                    let snapshot = if request.stored.is_some()
+                        && request.stored == taken
+                        && deltas + 1 < self.full_snapshot_interval
+                    {
+                        deltas += 1;
+                        Snapshot {
+                            order_book_snapshot: None,
+                            order_book_delta: Some(self.order_book.delta()),
+                            base: request.stored,
+                            order_offset,
+                        }
+                    } else {
+                        deltas = 0;
+                        Snapshot {
+                            order_book_snapshot: Some(self.order_book.snapshot()),
+                            order_book_delta: None,
+                            base: None,
+                            order_offset,
+                        }
+                    };
+                    self.order_book.clear_changes();
+                    taken = Some(SnapshotInfo::of(&snapshot));
 
                     if let Err(e) = snapshot_approve_req_tx.send(snapshot).await {
                         error!("{}", e);
                         continue;
                     }
                 }
-                _ = cleanup_interval.tick() => {
-                    // Periodic cleanup of expired orders from time window
-                    self.order_book.cleanup_time_window();
+                _ = book_interval.tick(), if self.book_tx.is_some() => {
+                    let book = self.order_book.l3_snapshot();
+                    if let Some(book_tx) = &self.book_tx
+                        && let Err(e) = book_tx.try_send(book) {
+                            info!("skip order book snapshot: {} {}", self.product_id, e);
+                        }
                 }
             }
         }
     }
 
-    pub async fn run_committer(
+    pub async fn run_committer<L: LogSink>(
         log_seq: u64,
-        log_rx: Receiver<Box<dyn LogTrait>>,
+        log_rx: Receiver<OffsetLogs>,
         snapshot_approve_req_rx: Receiver<Snapshot>,
         snapshot_tx: Sender<Snapshot>,
-        log_store: &mut KafkaLogStore,
+        log_store: &mut L,
     ) {
         let mut seq = log_seq;
         let mut pending: Option<Snapshot> = None;
@@ -249,20 +416,20 @@ impl Engine {
 
         loop {
             select! {
-                Some(log) = log_rx.recv() => {
-                    // discard duplicate log
-                    if log.get_seq() <= seq {
-                        info!("discard log seq={}", seq);
-                        continue;
-                    }
-
-                    logs.push(log);
+                offset_logs = log_rx.recv() => {
+                    // the applier stopped and every batch is stored
+                    let Some(offset_logs) = offset_logs else {
+                        break;
+                    };
+                    let mut order_offset = offset_logs.offset;
+                    logs.extend(offset_logs.logs);
 
                     // channel is not empty and buffer is not full, continue read.
-                    for _ in 0..100 {
+                    while logs.len() <= 100 {
                         match log_rx.try_recv() {
-                            Ok(log) => {
-                                logs.push(log);
+                            Ok(offset_logs) => {
+                                order_offset = offset_logs.offset;
+                                logs.extend(offset_logs.logs);
                             }
                             Err(_e) => {
                                 break;
@@ -270,8 +437,14 @@ impl Engine {
                         }
                     }
 
+                    // discard duplicate log
+                    let stored = logs.len();
+                    logs.retain(|l| l.get_seq() > seq);
+                    if logs.len() < stored {
+                        info!("discard {} logs up to seq={}", stored - logs.len(), seq);
+                    }
                     // store log first, only update seq after successful persistence
-                    if let Err(e) = log_store.store(&logs).await {
+                    if let Err(e) = log_store.store(&logs, order_offset).await {
                         panic!("{}", e);
                     }
 
@@ -285,7 +458,7 @@ impl Engine {
 
                     // approve pending snapshot
                     if let Some(p) = &pending
-                        && seq >= p.order_book_snapshot.clone().unwrap().log_seq {
+                        && seq >= p.log_seq() {
                             if let Err(e) = snapshot_tx.send(p.clone()).await{
                                 error!("{}", e);
                                 continue;
@@ -294,7 +467,7 @@ impl Engine {
                         }
                 },
                 Some(snapshot) = snapshot_approve_req_rx.recv() => {
-                    if seq >= snapshot.order_book_snapshot.clone().unwrap().log_seq {
+                    if seq >= snapshot.log_seq() {
                         if let Err(e) = snapshot_tx.send(snapshot.clone()).await{
                             error!("{}", e);
                             continue;
@@ -304,7 +477,7 @@ impl Engine {
                     }
 
                     if let Some(p) = &pending {
-                        info!("discard snapshot request (seq={}), new one (seq={}) received", p.order_book_snapshot.clone().unwrap().log_seq, snapshot.order_book_snapshot.clone().unwrap().log_seq);
+                        info!("discard snapshot request (seq={}), new one (seq={}) received", p.log_seq(), snapshot.log_seq());
                     }
                     pending = Some(snapshot);
                 }
@@ -312,40 +485,271 @@ impl Engine {
         }
     }
 
-    pub async fn run_snapshots(
+    pub async fn run_snapshots<S: SnapshotStore>(
         product_id: &str,
         order_offset: u64,
-        snapshot_req_tx: Sender<Snapshot>,
+        snapshot_req_tx: Sender<SnapshotRequest>,
         snapshot_rx: Receiver<Snapshot>,
-        snapshot_store: &mut RedisSnapshotStore,
+        snapshot_store: &mut S,
     ) {
         let mut order_offset = order_offset;
+        let mut stored: Option<SnapshotInfo> = None;
         let mut snapshot_rx = snapshot_rx;
 
         loop {
             select! {
                 _ = sleep(Duration::from_secs(30)) => {
                     // make a new snapshot request
-                    if let Err(e) = snapshot_req_tx.send(Snapshot{
-                        order_book_snapshot: None,
+                    if let Err(e) = snapshot_req_tx.send(SnapshotRequest{
                         order_offset,
+                        stored: stored.clone(),
                     }).await{
                         error!("{}", e);
                         continue;
                     };
                 },
-                Some(snapshot) = snapshot_rx.recv() => {
+                snapshot = snapshot_rx.recv() => {
+                    // the committer stopped, no snapshot is approved any more
+                    let Some(snapshot) = snapshot else {
+                        break;
+                    };
                     // store snapshot
                     if let Err(e) = snapshot_store.store(&snapshot).await {
                         error!("store snapshot failed: {}", e);
                         continue;
                     }
-                    info!("new snapshot stored :product={} OrderOffset={} LogSeq={}", product_id, snapshot.order_offset, snapshot.order_book_snapshot.unwrap().log_seq);
+                    info!("new snapshot stored :product={} OrderOffset={} LogSeq={} Delta={}", product_id, snapshot.order_offset, snapshot.log_seq(), snapshot.is_delta());
 
                     // update offset for next snapshot request
                     order_offset = snapshot.order_offset;
+                    stored = Some(SnapshotInfo::of(&snapshot));
                 }
             }
         }
     }
+
+    pub async fn run_book_publisher<P: BookPublisher>(
+        product_id: &str,
+        book_rx: Receiver<L3Snapshot>,
+        book_publisher: &mut P,
+    ) {
+        let mut book_rx = book_rx;
+
+        while let Some(book) = book_rx.recv().await {
+            if let Err(e) = book_publisher.publish(&book).await {
+                error!("publish order book failed: {}", e);
+                continue;
+            }
+            debug!("order book published :product={} LogSeq={} Bids={} Asks={}", product_id, book.log_seq, book.bids.len(), book.asks.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::matching::engine::{Engine, Snapshot};
+    use crate::matching::memory_store::MemorySnapshotStore;
+    use crate::matching::order_book::OrderBook;
+    use crate::matching::store::{SnapshotInfo, SnapshotStore};
+    use crate::models::models::{Order, Product};
+    use crate::models::types::{OrderStatus, OrderType, Side};
+    use crate::utils::time_window::SNOWFLAKE_EPOCH;
+
+    fn make_order(id: u64, side: Side) -> Order {
+        Order {
+            created_at: 1695783003020967000 + id,
+            ..Order::new_test_order(id, id, side, "1", "1000")
+        }
+    }
+
+    #[test]
+    fn test_redelivered_order_is_skipped_before_checks() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            min_size: Decimal::from(2),
+            ..Default::default()
+        };
+        let mut engine = Engine::new_engine(&product);
+        // a snowflake id from the order's own time, so it lands inside the time window
+        let created_at = 1695783003020967000u64;
+        let id = (created_at / 1_000_000 - SNOWFLAKE_EPOCH as u64) << 22;
+        let order = Order {
+            created_at,
+            ..Order::new_test_order(id, 1, Side::SideBuy, "1", "1000")
+        };
+
+        let logs = engine.apply(&order);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(serde_json::to_value(&logs[0]).unwrap()["reject_reason"], "min_size");
+        assert!(engine.apply(&order).is_empty());
+    }
+
+    #[test]
+    fn test_prices_and_sizes_rounded_without_rules() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut engine = Engine::new_engine(&product);
+        let order = Order {
+            created_at: 1695783003020967001,
+            ..Order::new_test_order(1, 1, Side::SideBuy, "0.00015", "1000.005")
+        };
+
+        let logs = engine.apply(&order);
+        let open = serde_json::to_value(&logs[0]).unwrap();
+        assert_eq!(open["base"]["type"], "open");
+        assert_eq!(open["price"], "1000.01");
+        assert_eq!(open["remaining_size"], "0.0002");
+    }
+
+    /// Stamp an order `millis` after the test epoch, with a snowflake id of that time.
+    fn at(order: Order, millis: u64) -> Order {
+        let created_at = 1695783003020967000 + millis * 1_000_000;
+        Order {
+            id: ((created_at / 1_000_000 - SNOWFLAKE_EPOCH as u64) << 22) | order.id,
+            created_at,
+            ..order
+        }
+    }
+
+    #[test]
+    fn test_clock_tick_ends_halt_without_orders() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            circuit_breaker: Decimal::from(5),
+            ..Default::default()
+        };
+        let mut engine = Engine::new_engine(&product);
+        let mut market_buy = Order::new_test_order(5, 2, Side::SideBuy, "1", "0");
+        market_buy.r#type = OrderType::OrderTypeMarket;
+        engine.apply(&at(Order::new_test_order(1, 1, Side::SideSell, "1", "100"), 1));
+        engine.apply(&at(Order::new_test_order(2, 2, Side::SideBuy, "1", "100"), 2));
+        engine.apply(&at(Order::new_test_order(3, 1, Side::SideSell, "1", "110"), 3));
+        // sweeping to 110 is more than 5% away from 100
+        engine.apply(&at(market_buy, 4));
+        assert!(engine.order_book.is_halted());
+
+        let mut tick = Order::new_test_order(6, 0, Side::SideBuy, "0", "0");
+        tick.status = OrderStatus::OrderStatusControl;
+        assert!(engine.apply(&at(tick.clone(), 30_000)).is_empty());
+        let logs = engine.apply(&at(tick, 60_004));
+        let status = serde_json::to_value(&logs[0]).unwrap();
+        assert_eq!(status["status"], "continuous");
+        assert!(!engine.order_book.is_halted());
+    }
+
+    /// A full snapshot of `book` if there is no `base`, otherwise a delta on top of it.
+    fn take_snapshot(book: &mut OrderBook, base: &Option<SnapshotInfo>, order_offset: u64) -> Snapshot {
+        let snapshot = match base {
+            None => Snapshot {
+                order_book_snapshot: Some(book.snapshot()),
+                order_book_delta: None,
+                base: None,
+                order_offset,
+            },
+            Some(_) => Snapshot {
+                order_book_snapshot: None,
+                order_book_delta: Some(book.delta()),
+                base: base.clone(),
+                order_offset,
+            },
+        };
+        book.clear_changes();
+        snapshot
+    }
+
+    #[tokio::test]
+    async fn test_delta_snapshots_restore_through_chain() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore {
+            retention: 3,
+            ..Default::default()
+        };
+        let mut book = OrderBook::new_order_book(&product);
+
+        let mut base: Option<SnapshotInfo> = None;
+        for order_offset in 1..=4u64 {
+            book.apply_order(&make_order(order_offset, Side::SideBuy));
+            let snapshot = take_snapshot(&mut book, &base, order_offset);
+            snapshot_store.store(&snapshot).await.unwrap();
+            base = Some(SnapshotInfo::of(&snapshot));
+
+            if order_offset == 3 {
+                // a full snapshot and two deltas on top of it
+                let engine = Engine::new(&product, &mut snapshot_store).await;
+                assert_eq!(engine.order_offset, 3);
+                assert_eq!(engine.order_book.bid_depths.orders.len(), 3);
+            }
+        }
+
+        // the full snapshot the kept deltas build on stays past the retention
+        assert_eq!(snapshot_store.snapshots.len(), 4);
+        let engine = Engine::new(&product, &mut snapshot_store).await;
+        assert_eq!(engine.order_offset, 4);
+        assert_eq!(engine.order_book.bid_depths.orders.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_full_snapshot_falls_back_to_older_chain() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore {
+            retention: 3,
+            ..Default::default()
+        };
+        let mut book = OrderBook::new_order_book(&product);
+
+        // a full snapshot at 1 and at 4, the others are deltas on the one before
+        let mut base: Option<SnapshotInfo> = None;
+        for order_offset in 1..=4u64 {
+            book.apply_order(&make_order(order_offset, Side::SideBuy));
+            if order_offset == 4 {
+                base = None;
+            }
+            let snapshot = take_snapshot(&mut book, &base, order_offset);
+            snapshot_store.store(&snapshot).await.unwrap();
+            base = Some(SnapshotInfo::of(&snapshot));
+        }
+
+        // the newest full snapshot is damaged, the chain of 3 back to 1 is still whole
+        let offsets: Vec<u64> = snapshot_store.list().await.unwrap().iter().map(|i| i.order_offset).collect();
+        assert_eq!(offsets, vec![4, 3, 2, 1]);
+        let newest = snapshot_store.list().await.unwrap()[0].clone();
+        let sealed = snapshot_store.snapshots[&newest].clone();
+        let damaged = sealed.replacen("\"order_offset\":4", "\"order_offset\":5", 1);
+        assert_ne!(damaged, sealed);
+        snapshot_store.snapshots.insert(newest.clone(), damaged);
+        let engine = Engine::new(&product, &mut snapshot_store).await;
+        assert_eq!(engine.order_offset, 3);
+        assert_eq!(engine.order_book.bid_depths.orders.len(), 3);
+
+        // once the retention only holds the chain of 4, the older chain goes as a whole
+        snapshot_store.snapshots.insert(newest, sealed);
+        for order_offset in 5..=6u64 {
+            book.apply_order(&make_order(order_offset, Side::SideBuy));
+            let snapshot = take_snapshot(&mut book, &base, order_offset);
+            snapshot_store.store(&snapshot).await.unwrap();
+            base = Some(SnapshotInfo::of(&snapshot));
+        }
+        let offsets: Vec<u64> = snapshot_store.list().await.unwrap().iter().map(|i| i.order_offset).collect();
+        assert_eq!(offsets, vec![6, 5, 4]);
+    }
 }
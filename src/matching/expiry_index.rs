@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use crate::matching::order_book::BookOrder;
+use crate::models::types::Side;
+
+/// Key for resting orders sorted by expiry time, then by order_id for uniqueness.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ExpiryOrderIdKey {
+    expire_time: u64, // nanoseconds, like Order::created_at
+    order_id: u64,
+}
+
+/// ExpiryIndex keeps the resting GTT and GTD orders in the order they expire. The book
+/// removes an order's entry when it is filled, cancelled or taken off for an amend.
+#[derive(Debug, Default)]
+pub struct ExpiryIndex {
+    orders: BTreeMap<ExpiryOrderIdKey, Side>,
+}
+
+impl ExpiryIndex {
+    pub fn add(&mut self, order: &BookOrder) {
+        if order.expire_time == 0 {
+            return;
+        }
+        self.orders.insert(
+            ExpiryOrderIdKey {
+                expire_time: order.expire_time,
+                order_id: order.order_id,
+            },
+            order.side.clone(),
+        );
+    }
+
+    pub fn remove(&mut self, order: &BookOrder) {
+        if order.expire_time == 0 {
+            return;
+        }
+        self.orders.remove(&ExpiryOrderIdKey {
+            expire_time: order.expire_time,
+            order_id: order.order_id,
+        });
+    }
+
+    /// Remove and return the id and side of every order expiring at or before `now`,
+    /// earliest first.
+    pub fn take_expired(&mut self, now: u64) -> Vec<(u64, Side)> {
+        let mut expired: Vec<(u64, Side)> = Vec::new();
+        while let Some(entry) = self.orders.first_entry() {
+            if entry.key().expire_time > now {
+                break;
+            }
+            let order_id = entry.key().order_id;
+            expired.push((order_id, entry.remove()));
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+impl ExpiryIndex {
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
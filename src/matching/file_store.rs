@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::matching::engine::Snapshot;
+use crate::matching::log::LogTrait;
+use crate::matching::memory_store::{log_seq, MemoryOrderSource};
+use crate::matching::replay::read_orders;
+use crate::matching::store::{LogSink, OrderSource, SnapshotInfo, SnapshotStore};
+use crate::models::models::Order;
+use crate::models::types::Compression;
+use crate::utils::error::CustomError;
+
+/// Orders read from a JSONL file in the format of the order topic, one per line. The offset
+/// of an order is its index among the orders in the file.
+#[derive(Debug)]
+pub struct FileOrderSource {
+    pub path: String,
+    orders: MemoryOrderSource,
+}
+
+impl FileOrderSource {
+    pub fn new_file_order_source(path: &str) -> Result<FileOrderSource, CustomError> {
+        Ok(FileOrderSource {
+            path: path.to_string(),
+            orders: MemoryOrderSource::new_memory_order_source(read_orders(path)?),
+        })
+    }
+}
+
+impl OrderSource for FileOrderSource {
+    async fn seek(&mut self, order_offset: u64) -> Result<(), CustomError> {
+        self.orders.seek(order_offset).await
+    }
+
+    async fn fetch_order(&mut self) -> Result<(i64, Option<Order>), CustomError> {
+        self.orders.fetch_order().await
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.orders.is_exhausted()
+    }
+}
+
+/// Appends logs to a file, one JSON log per line, the same format `replay` compares against.
+pub struct FileLogSink {
+    pub path: String,
+    file: File,
+    last_seq: u64,
+}
+
+impl FileLogSink {
+    pub async fn new_file_log_sink(path: &str) -> Result<FileLogSink, CustomError> {
+        // logs already in the file count as stored
+        let last_seq = match fs::read_to_string(path).await {
+            Ok(s) => s.lines().map(log_seq).max().unwrap_or(0),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(CustomError::new(&e)),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        Ok(FileLogSink {
+            path: path.to_string(),
+            file,
+            last_seq,
+        })
+    }
+}
+
+impl LogSink for FileLogSink {
+    async fn store(&mut self, logs: &[Box<dyn LogTrait>], _order_offset: u64) -> Result<(), CustomError> {
+        if logs.is_empty() {
+            return Ok(());
+        }
+        let mut s = String::new();
+        for log in logs {
+            s.push_str(&serde_json::to_string(log).map_err(|e| CustomError::new(&e))?);
+            s.push('\n');
+            self.last_seq = u64::max(self.last_seq, log.get_seq());
+        }
+        self.file
+            .write_all(s.as_bytes())
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        self.file.sync_data().await.map_err(|e| CustomError::new(&e))
+    }
+
+    async fn last_seq(&mut self) -> Result<u64, CustomError> {
+        Ok(self.last_seq)
+    }
+}
+
+// Every snapshot file ends with a fixed footer:
+//
+// | magic "OBSN" | version u8 | compression u8 | 2 reserved bytes | payload length u64 | CRC-32 u32 |
+//
+// integers little endian. The payload before it is the snapshot JSON, gzipped if the
+// compression byte is 1, and the CRC-32 covers the payload as stored.
+const FOOTER_MAGIC: &[u8; 4] = b"OBSN";
+const FOOTER_VERSION: u8 = 1;
+const FOOTER_LEN: usize = 20;
+
+pub fn encode_snapshot_file(snapshot: &Snapshot, compression: &Compression) -> Result<Vec<u8>, CustomError> {
+    let json = serde_json::to_vec(snapshot).map_err(|e| CustomError::new(&e))?;
+    let (mut buf, compression_byte) = match compression {
+        Compression::CompressionNone => (json, 0u8),
+        Compression::CompressionGzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&json).map_err(|e| CustomError::new(&e))?;
+            (encoder.finish().map_err(|e| CustomError::new(&e))?, 1u8)
+        }
+    };
+
+    let checksum = crc32fast::hash(&buf);
+    let payload_len = buf.len() as u64;
+    buf.extend_from_slice(FOOTER_MAGIC);
+    buf.extend_from_slice(&[FOOTER_VERSION, compression_byte, 0, 0]);
+    buf.extend_from_slice(&payload_len.to_le_bytes());
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    Ok(buf)
+}
+
+/// Check the footer and checksum of a snapshot file and decode it.
+pub fn decode_snapshot_file(bytes: &[u8]) -> Result<Snapshot, CustomError> {
+    let corrupted = |reason: &str| CustomError::from_string(format!("corrupted snapshot file: {}", reason));
+
+    if bytes.len() < FOOTER_LEN {
+        return Err(corrupted("too short"));
+    }
+    let (payload, footer) = bytes.split_at(bytes.len() - FOOTER_LEN);
+    if &footer[0..4] != FOOTER_MAGIC {
+        return Err(corrupted("no footer"));
+    }
+    if footer[4] != FOOTER_VERSION {
+        return Err(corrupted(&format!("unsupported version {}", footer[4])));
+    }
+    let payload_len = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+    if payload_len != payload.len() as u64 {
+        return Err(corrupted("length mismatch"));
+    }
+    let checksum = u32::from_le_bytes(footer[16..20].try_into().unwrap());
+    if checksum != crc32fast::hash(payload) {
+        return Err(corrupted("checksum mismatch"));
+    }
+
+    let json = match footer[5] {
+        0 => payload.to_vec(),
+        1 => {
+            let mut json = Vec::new();
+            GzDecoder::new(payload)
+                .read_to_end(&mut json)
+                .map_err(|e| CustomError::new(&e))?;
+            json
+        }
+        c => return Err(corrupted(&format!("unknown compression {}", c))),
+    };
+    serde_json::from_slice(&json).map_err(|e| CustomError::new(&e))
+}
+
+/// Keeps the newest `retention` snapshots with the chains they build on, or all if 0, as
+/// `snapshot_{order_offset}-{log_seq}.snap` in a directory, for deployments without Redis. A snapshot is written to a temporary file,
+/// synced and renamed into place, so a crash leaves either the old set of snapshots or the
+/// new one.
+pub struct FileSnapshotStore {
+    pub dir: String,
+    pub retention: usize,
+    pub compression: Compression,
+    // the base of each snapshot stored or loaded, so pruning does not load them again
+    bases: HashMap<SnapshotInfo, Option<SnapshotInfo>>,
+}
+
+impl FileSnapshotStore {
+    pub fn new_file_snapshot_store(dir: &str, retention: usize, compression: &Compression) -> FileSnapshotStore {
+        FileSnapshotStore {
+            dir: dir.to_string(),
+            retention,
+            compression: compression.clone(),
+            bases: HashMap::new(),
+        }
+    }
+
+    fn snapshot_path(&self, info: &SnapshotInfo) -> PathBuf {
+        Path::new(&self.dir).join(format!("snapshot_{}.snap", info.id()))
+    }
+}
+
+impl SnapshotStore for FileSnapshotStore {
+    async fn store(&mut self, snapshot: &Snapshot) -> Result<(), CustomError> {
+        let bytes = encode_snapshot_file(snapshot, &self.compression)?;
+        let path = self.snapshot_path(&SnapshotInfo::of(snapshot));
+        let tmp_path = path.with_extension("tmp");
+        fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+
+        let mut file = File::create(&tmp_path)
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        file.write_all(&bytes)
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        file.sync_all().await.map_err(|e| CustomError::new(&e))?;
+        fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        // make the rename itself durable
+        File::open(&self.dir)
+            .await
+            .map_err(|e| CustomError::new(&e))?
+            .sync_all()
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+
+        self.bases
+            .insert(SnapshotInfo::of(snapshot), snapshot.base.clone());
+
+        for info in self.expired(self.retention).await? {
+            fs::remove_file(self.snapshot_path(&info))
+                .await
+                .map_err(|e| CustomError::new(&e))?;
+            self.bases.remove(&info);
+        }
+        Ok(())
+    }
+
+    async fn list(&mut self) -> Result<Vec<SnapshotInfo>, CustomError> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(CustomError::new(&e)),
+        };
+
+        let mut infos: Vec<SnapshotInfo> = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(|e| CustomError::new(&e))? {
+            let name = entry.file_name();
+            if let Some(info) = name
+                .to_str()
+                .and_then(|n| n.strip_prefix("snapshot_"))
+                .and_then(|n| n.strip_suffix(".snap"))
+                .and_then(SnapshotInfo::from_id)
+            {
+                infos.push(info);
+            }
+        }
+        infos.sort_by(|a, b| b.cmp(a));
+        Ok(infos)
+    }
+
+    async fn get(&mut self, info: &SnapshotInfo) -> Result<Snapshot, CustomError> {
+        let bytes = fs::read(self.snapshot_path(info))
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        let snapshot = decode_snapshot_file(&bytes)?;
+        self.bases.insert(info.clone(), snapshot.base.clone());
+        Ok(snapshot)
+    }
+
+    async fn base_of(&mut self, info: &SnapshotInfo) -> Result<Option<SnapshotInfo>, CustomError> {
+        match self.bases.get(info) {
+            Some(base) => Ok(base.clone()),
+            None => Ok(self.get(info).await?.base),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matching::engine::Snapshot;
+    use crate::matching::file_store::{decode_snapshot_file, encode_snapshot_file, FileSnapshotStore};
+    use crate::matching::store::{SnapshotInfo, SnapshotStore};
+    use crate::models::types::Compression;
+
+    #[tokio::test]
+    async fn test_file_snapshot_history() {
+        let dir = std::env::temp_dir().join(format!("orderbook_snapshots_{}", std::process::id()));
+        let mut store = FileSnapshotStore::new_file_snapshot_store(
+            dir.to_str().unwrap(),
+            2,
+            &Compression::CompressionGzip,
+        );
+
+        assert!(store.get_latest().await.unwrap().is_none());
+
+        for order_offset in [10, 20, 30] {
+            let snapshot = Snapshot {
+                order_book_snapshot: None,
+                order_book_delta: None,
+                base: None,
+                order_offset,
+            };
+            store.store(&snapshot).await.unwrap();
+        }
+        let offsets: Vec<u64> = store.list().await.unwrap().iter().map(|i| i.order_offset).collect();
+        assert_eq!(offsets, vec![30, 20]);
+        assert_eq!(store.get_latest().await.unwrap().unwrap().order_offset, 30);
+
+        // a corrupted latest snapshot falls back to the previous one
+        let latest = SnapshotInfo {
+            order_offset: 30,
+            log_seq: 0,
+        };
+        let path = store.snapshot_path(&latest);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(store.get(&latest).await.is_err());
+        assert_eq!(store.get_latest().await.unwrap().unwrap().order_offset, 20);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_file_format() {
+        let snapshot = Snapshot {
+            order_book_snapshot: None,
+            order_book_delta: None,
+            base: None,
+            order_offset: 42,
+        };
+        for compression in [Compression::CompressionNone, Compression::CompressionGzip] {
+            let bytes = encode_snapshot_file(&snapshot, &compression).unwrap();
+            assert_eq!(decode_snapshot_file(&bytes).unwrap().order_offset, 42);
+
+            // a torn write loses the footer
+            assert!(decode_snapshot_file(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::result::Result;
+use std::time::Duration;
+
+use log::error;
+use rdkafka::producer::FutureRecord;
+
+use crate::matching::codec::encode_book;
+use crate::matching::order_book::L3Snapshot;
+use crate::matching::store::BookPublisher;
+use crate::models::types::WireFormat;
+use crate::utils::error::CustomError;
+use crate::utils::kafka::{new_kafka_idempotent_producer, DefaultProducer};
+
+const TOPIC_BOOK_PREFIX: &str = "matching_book_";
+
+/// Publishes order book snapshots to `matching_book_{product_id}`, keyed by the product id
+/// so a compacted topic keeps the newest one.
+pub struct KafkaBookPublisher {
+    pub topic: String,
+    pub product_id: String,
+    pub book_producer: DefaultProducer,
+    pub format: WireFormat,
+    pub message_timeout: Duration,
+}
+
+impl KafkaBookPublisher {
+    pub fn new_kafka_book_publisher(
+        brokers: &[String],
+        product_id: &str,
+        message_time_out: u64,
+        format: &WireFormat,
+    ) -> Result<KafkaBookPublisher, CustomError> {
+        let dp = new_kafka_idempotent_producer(brokers, message_time_out)
+            .map_err(|e| CustomError::new(&e))?;
+        Ok(KafkaBookPublisher {
+            topic: [TOPIC_BOOK_PREFIX, product_id].join(""),
+            product_id: product_id.to_string(),
+            book_producer: dp,
+            format: format.clone(),
+            message_timeout: Duration::from_secs(message_time_out),
+        })
+    }
+}
+
+impl BookPublisher for KafkaBookPublisher {
+    async fn publish(&mut self, book: &L3Snapshot) -> Result<(), CustomError> {
+        let payload = encode_book(book, &self.format)?;
+        let record = FutureRecord::to(&self.topic)
+            .payload(&payload)
+            .key(&self.product_id);
+        match self.book_producer.send(record, self.message_timeout).await {
+            Ok(_) => Ok(()),
+            Err((kafka_error, _owned_message)) => {
+                error!("Kafka delivery failed for book at log seq {}: {:?}", book.log_seq, kafka_error);
+                Err(CustomError::new(&kafka_error))
+            }
+        }
+    }
+}
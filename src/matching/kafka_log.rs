@@ -1,18 +1,41 @@
+use std::collections::VecDeque;
 use std::result::Result;
+use std::sync::Arc;
+use std::time::Duration;
 
-use log::error;
-use rdkafka::producer::FutureRecord;
-use rdkafka::util::Timeout;
+use log::{error, info};
+use rdkafka::consumer::Consumer;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::future_producer::{DeliveryFuture, OwnedDeliveryResult};
+use rdkafka::producer::{FutureRecord, Producer};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use tokio::task::block_in_place;
+use tokio::time::{sleep, timeout};
 
+use crate::matching::codec::{decode_log, encode_log, payload_format};
+use crate::matching::kafka_order::KafkaOrderReader;
 use crate::matching::log::LogTrait;
+use crate::matching::store::LogSink;
+use crate::models::types::WireFormat;
 use crate::utils::error::CustomError;
-use crate::utils::kafka::{new_kafka_producer, DefaultProducer};
+use crate::utils::kafka::{new_kafka_producer, new_kafka_reader, DefaultConsumer, DefaultProducer};
 
-const TOPIC_BOOK_MESSAGE_PREFIX: &str = "matching_message_";
+pub const TOPIC_BOOK_MESSAGE_PREFIX: &str = "matching_message_";
+
+// how long reading back the log topic waits for the broker
+const READ_BACK_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_BACK_IDLE: Duration = Duration::from_secs(1);
 
 pub struct KafkaLogStore {
     pub topic: String,
     pub log_producer: DefaultProducer,
+    pub log_reader: DefaultConsumer,
+    pub format: WireFormat,
+    pub transaction_timeout: Duration,
+    // consumer and topic of the orders, set when their offsets are committed in the same
+    // transaction as the logs
+    pub order_consumer: Option<Arc<DefaultConsumer>>,
+    pub order_topic: String,
 }
 
 impl KafkaLogStore {
@@ -20,44 +43,238 @@ impl KafkaLogStore {
         brokers: &[String],
         product_id: &str,
         message_time_out: u64,
+        format: &WireFormat,
     ) -> Result<KafkaLogStore, CustomError> {
-        match new_kafka_producer(brokers, message_time_out) {
-            Ok(dp) => Ok(KafkaLogStore {
-                topic: [TOPIC_BOOK_MESSAGE_PREFIX, product_id].join(""),
-                log_producer: dp,
-            }),
-            Err(e) => Err(CustomError::new(&e)),
+        let topic = [TOPIC_BOOK_MESSAGE_PREFIX, product_id].join("");
+        let transaction_timeout = Duration::from_secs(message_time_out);
+
+        // one transactional id per product, a restarted engine fences off its predecessor
+        let dp = new_kafka_producer(brokers, message_time_out, &topic)
+            .map_err(|e| CustomError::new(&e))?;
+        dp.init_transactions(transaction_timeout)
+            .map_err(|e| CustomError::new(&e))?;
+
+        let dc = new_kafka_reader(brokers, &[topic.as_str(), "_reader"].join(""), true)
+            .map_err(|e| CustomError::new(&e))?;
+
+        Ok(KafkaLogStore {
+            topic,
+            log_producer: dp,
+            log_reader: dc,
+            format: format.clone(),
+            transaction_timeout,
+            order_consumer: None,
+            order_topic: String::new(),
+        })
+    }
+
+    /// Commit the offsets of `order_reader` with every batch of logs, so the logs of an
+    /// order and its consumption become visible together or not at all.
+    pub fn commit_order_offsets(&mut self, order_reader: &KafkaOrderReader) -> Result<(), CustomError> {
+        if order_reader.order_consumer.group_metadata().is_none() {
+            return Err(CustomError::from_string(
+                "order consumer has no group metadata".to_string(),
+            ));
         }
+        self.order_consumer = Some(order_reader.order_consumer.clone());
+        self.order_topic = order_reader.topic.clone();
+        Ok(())
     }
 
-    pub async fn store(&self, logs: &[Box<dyn LogTrait>]) -> Result<(), CustomError> {
+    /// Add the offset after `order_offset` to the open transaction. The group metadata is
+    /// read for every transaction, its generation changes with each rebalance, and the
+    /// partition is the one the order reader is assigned.
+    fn send_order_offset(&self, order_offset: u64) -> Result<(), CustomError> {
+        let Some(order_consumer) = &self.order_consumer else {
+            return Ok(());
+        };
+        let group = order_consumer.group_metadata().ok_or_else(|| {
+            CustomError::from_string("order consumer has no group metadata".to_string())
+        })?;
+        // order offsets are a single sequence, the reader is assigned one partition
+        let assignment = order_consumer
+            .assignment()
+            .map_err(|e| CustomError::new(&e))?;
+        let partition = assignment
+            .elements_for_topic(&self.order_topic)
+            .first()
+            .map(|e| e.partition())
+            .ok_or_else(|| {
+                CustomError::from_string(format!("no partition of {} assigned", self.order_topic))
+            })?;
+
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition_offset(
+            &self.order_topic,
+            partition,
+            Offset::Offset(order_offset as i64 + 1),
+        )
+        .map_err(|e| CustomError::new(&e))?;
+        block_in_place(|| {
+            self.log_producer
+                .send_offsets_to_transaction(&tpl, &group, self.transaction_timeout)
+        })
+        .map_err(|e| CustomError::new(&e))
+    }
+
+    /// Enqueue every log, then wait for all of them to be delivered. Logs go out in
+    /// sequence order, and the idempotent producer keeps that order across retries.
+    async fn send_batch(&self, logs: &[Box<dyn LogTrait>]) -> Result<(), CustomError> {
+        let mut payloads: Vec<Vec<u8>> = Vec::with_capacity(logs.len());
         for log in logs {
-            let s = serde_json::to_string(log)
+            payloads.push(encode_log(log.as_ref(), &self.format)?);
+        }
+
+        let mut deliveries: VecDeque<(u64, DeliveryFuture)> = VecDeque::new();
+        for (log, payload) in logs.iter().zip(&payloads) {
+            let mut record = FutureRecord::to(&self.topic).payload(payload).key("");
+            loop {
+                match self.log_producer.send_result(record) {
+                    Ok(delivery) => {
+                        deliveries.push_back((log.get_seq(), delivery));
+                        break;
+                    }
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                        // the local queue is full, wait for the oldest delivery to make room
+                        record = r;
+                        match deliveries.pop_front() {
+                            Some((seq, delivery)) => check_delivery(seq, delivery.await.ok())?,
+                            None => sleep(Duration::from_millis(10)).await,
+                        }
+                    }
+                    Err((e, _)) => {
+                        error!("Kafka enqueue failed for log seq {}: {:?}", log.get_seq(), e);
+                        return Err(CustomError::new(&e));
+                    }
+                }
+            }
+        }
+
+        for (seq, delivery) in deliveries {
+            check_delivery(seq, delivery.await.ok())?;
+        }
+        Ok(())
+    }
+
+    /// Highest sequence in the committed messages of one partition, reading back from the
+    /// end until one is found. Aborted transactions leave offsets with nothing to read, so
+    /// the window grows until it reaches a committed message or the start of the partition.
+    /// Reading stops at the end of the partition rather than waiting for it to go idle.
+    async fn last_seq_of_partition(&mut self, partition: i32) -> Result<u64, CustomError> {
+        let (low, high) = self
+            .log_reader
+            .fetch_watermarks(&self.topic, partition, READ_BACK_TIMEOUT)
+            .map_err(|e| CustomError::new(&e))?;
+
+        let mut window = 100i64;
+        loop {
+            let start = i64::max(low, high - window);
+            if start >= high {
+                return Ok(0);
+            }
+
+            let mut tpl = TopicPartitionList::new();
+            tpl.add_partition_offset(&self.topic, partition, Offset::Offset(start))
+                .map_err(|e| CustomError::new(&e))?;
+            self.log_reader
+                .assign(&tpl)
                 .map_err(|e| CustomError::new(&e))?;
 
-            // send() returns OwnedDeliveryResult = Result<(i32, i64), (KafkaError, OwnedMessage)>
-            let delivery_result = self
-                .log_producer
-                .send(
-                    FutureRecord::to(&self.topic).payload(&s).key(""),
-                    Timeout::Never,
-                )
-                .await;
-
-            // Check delivery result: Ok((partition, offset)) or Err((KafkaError, OwnedMessage))
-            match delivery_result {
-                Ok((_partition, _offset)) => {
-                    // Message successfully delivered to Kafka
+            let mut last_seq: Option<u64> = None;
+            while let Ok(r) = timeout(READ_BACK_IDLE, self.log_reader.recv()).await {
+                let message = match r {
+                    Ok(message) => message,
+                    // a transaction ends with a marker that is never received, the end of
+                    // the partition comes after it
+                    Err(KafkaError::PartitionEOF(_)) => break,
+                    Err(e) => return Err(CustomError::new(&e)),
+                };
+                if let Some(payload) = message.payload() {
+                    // the topic may hold logs written before a format change
+                    let log = decode_log(payload, &payload_format(payload))?;
+                    let seq = log["base"]["sequence"].as_u64().unwrap_or(0);
+                    last_seq = Some(u64::max(last_seq.unwrap_or(0), seq));
                 }
-                Err((kafka_error, _owned_message)) => {
-                    error!("Kafka delivery failed for log seq {}: {:?}", log.get_seq(), kafka_error);
-                    return Err(CustomError::from_string(format!(
-                        "Kafka delivery failed: {}",
-                        kafka_error
-                    )));
+                if message.offset() >= high - 1 {
+                    break;
                 }
             }
+
+            match last_seq {
+                Some(seq) => return Ok(seq),
+                None if start == low => return Ok(0),
+                None => window *= 10,
+            }
         }
-        Ok(())
+    }
+}
+
+/// Check a delivery result, `None` if the producer dropped the message without one.
+fn check_delivery(seq: u64, result: Option<OwnedDeliveryResult>) -> Result<(), CustomError> {
+    match result {
+        // Message successfully delivered to Kafka
+        Some(Ok((_partition, _offset))) => Ok(()),
+        Some(Err((kafka_error, _owned_message))) => {
+            error!("Kafka delivery failed for log seq {}: {:?}", seq, kafka_error);
+            Err(CustomError::from_string(format!(
+                "Kafka delivery failed: {}",
+                kafka_error
+            )))
+        }
+        None => Err(CustomError::from_string(format!(
+            "Kafka delivery of log seq {} was cancelled",
+            seq
+        ))),
+    }
+}
+
+impl LogSink for KafkaLogStore {
+    /// Publish a batch in one transaction, with the order offset if it is committed too. It
+    /// becomes visible to read_committed consumers all at once or not at all.
+    async fn store(&mut self, logs: &[Box<dyn LogTrait>], order_offset: u64) -> Result<(), CustomError> {
+        // a batch without logs only matters for the order offset
+        if logs.is_empty() && self.order_consumer.is_none() {
+            return Ok(());
+        }
+        self.log_producer
+            .begin_transaction()
+            .map_err(|e| CustomError::new(&e))?;
+
+        let result = match self.send_batch(logs).await {
+            Ok(()) => self.send_order_offset(order_offset),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if let Err(abort_err) =
+                block_in_place(|| self.log_producer.abort_transaction(self.transaction_timeout))
+            {
+                error!("abort transaction failed: {}", abort_err);
+            }
+            return Err(e);
+        }
+
+        // every message is delivered already, committing only writes the markers
+        block_in_place(|| self.log_producer.commit_transaction(self.transaction_timeout))
+            .map_err(|e| CustomError::new(&e))
+    }
+
+    async fn last_seq(&mut self) -> Result<u64, CustomError> {
+        let metadata = self
+            .log_reader
+            .fetch_metadata(Some(&self.topic), READ_BACK_TIMEOUT)
+            .map_err(|e| CustomError::new(&e))?;
+        let partitions: Vec<i32> = metadata
+            .topics()
+            .iter()
+            .filter(|t| t.name() == self.topic)
+            .flat_map(|t| t.partitions().iter().map(|p| p.id()))
+            .collect();
+
+        let mut last_seq = 0u64;
+        for partition in partitions {
+            last_seq = u64::max(last_seq, self.last_seq_of_partition(partition).await?);
+        }
+        info!("last committed log seq of {}: {}", self.topic, last_seq);
+        Ok(last_seq)
     }
 }
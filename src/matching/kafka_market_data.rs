@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::result::Result;
+use std::time::Duration;
+
+use log::{error, info};
+use rdkafka::consumer::Consumer;
+use rdkafka::producer::FutureRecord;
+use rdkafka::{Message, Offset, TopicPartitionList};
+use serde::Deserialize;
+use tokio::time::timeout;
+
+use crate::matching::codec::{decode_log, encode_candle, encode_ticker, payload_format};
+use crate::matching::kafka_log::TOPIC_BOOK_MESSAGE_PREFIX;
+use crate::matching::log::MatchLog;
+use crate::matching::market_data::{Aggregator, Candle, Ticker};
+use crate::matching::store::MarketDataPublisher;
+use crate::models::types::WireFormat;
+use crate::utils::error::CustomError;
+use crate::utils::kafka::{
+    new_kafka_idempotent_producer, new_kafka_reader, DefaultConsumer, DefaultProducer,
+};
+
+const TOPIC_TICKER_PREFIX: &str = "matching_ticker_";
+const TOPIC_CANDLE_PREFIX: &str = "matching_candle_";
+
+// trades before this are not needed for the 24 hour ticker or the current candles
+pub const LOOKBACK_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+// aborted transactions leave offsets with nothing to read before the high watermark
+const CATCH_UP_IDLE: Duration = Duration::from_secs(1);
+// trades folded before the market data is published while catching up
+const MAX_BATCH: usize = 1000;
+const BATCH_IDLE: Duration = Duration::from_millis(10);
+
+/// Publishes tickers to `matching_ticker_{product_id}`, keyed by the product id, and
+/// candles to `matching_candle_{product_id}`, keyed by `{granularity}-{start}` so a
+/// compacted topic keeps the last state of every candle.
+pub struct KafkaMarketDataPublisher {
+    pub ticker_topic: String,
+    pub candle_topic: String,
+    pub product_id: String,
+    pub producer: DefaultProducer,
+    pub format: WireFormat,
+    pub message_timeout: Duration,
+}
+
+impl KafkaMarketDataPublisher {
+    pub fn new_kafka_market_data_publisher(
+        brokers: &[String],
+        product_id: &str,
+        message_time_out: u64,
+        format: &WireFormat,
+    ) -> Result<KafkaMarketDataPublisher, CustomError> {
+        let dp = new_kafka_idempotent_producer(brokers, message_time_out)
+            .map_err(|e| CustomError::new(&e))?;
+        Ok(KafkaMarketDataPublisher {
+            ticker_topic: [TOPIC_TICKER_PREFIX, product_id].join(""),
+            candle_topic: [TOPIC_CANDLE_PREFIX, product_id].join(""),
+            product_id: product_id.to_string(),
+            producer: dp,
+            format: format.clone(),
+            message_timeout: Duration::from_secs(message_time_out),
+        })
+    }
+
+    async fn send(&self, topic: &str, key: &str, payload: &[u8]) -> Result<(), CustomError> {
+        let record = FutureRecord::to(topic).payload(payload).key(key);
+        match self.producer.send(record, self.message_timeout).await {
+            Ok(_) => Ok(()),
+            Err((kafka_error, _owned_message)) => {
+                error!("Kafka delivery failed for {}: {:?}", topic, kafka_error);
+                Err(CustomError::new(&kafka_error))
+            }
+        }
+    }
+}
+
+impl MarketDataPublisher for KafkaMarketDataPublisher {
+    async fn publish(&mut self, ticker: &Ticker, candles: &[Candle]) -> Result<(), CustomError> {
+        for candle in candles {
+            let key = format!("{}-{}", candle.granularity, candle.start);
+            self.send(&self.candle_topic, &key, &encode_candle(candle, &self.format)?)
+                .await?;
+        }
+        self.send(&self.ticker_topic, &self.product_id, &encode_ticker(ticker, &self.format)?)
+            .await
+    }
+}
+
+/// Reads the match logs of a product from its committed log stream, for the standalone
+/// market data aggregator.
+pub struct KafkaMatchReader {
+    pub topic: String,
+    pub log_reader: DefaultConsumer,
+}
+
+impl KafkaMatchReader {
+    pub fn new_kafka_match_reader(
+        brokers: &[String],
+        product_id: &str,
+    ) -> Result<KafkaMatchReader, CustomError> {
+        let topic = [TOPIC_BOOK_MESSAGE_PREFIX, product_id].join("");
+        let dc = new_kafka_reader(brokers, &[topic.as_str(), "_market_data"].join(""), false)
+            .map_err(|e| CustomError::new(&e))?;
+        Ok(KafkaMatchReader {
+            topic,
+            log_reader: dc,
+        })
+    }
+
+    /// Position every partition of the log topic at its first message written at or after
+    /// `time`, in milliseconds since the Unix epoch.
+    pub fn seek_to_time(&mut self, time: i64) -> Result<(), CustomError> {
+        let metadata = self
+            .log_reader
+            .fetch_metadata(Some(&self.topic), METADATA_TIMEOUT)
+            .map_err(|e| CustomError::new(&e))?;
+        let mut tpl = TopicPartitionList::new();
+        for t in metadata.topics().iter().filter(|t| t.name() == self.topic) {
+            for p in t.partitions() {
+                tpl.add_partition_offset(&self.topic, p.id(), Offset::Offset(time))
+                    .map_err(|e| CustomError::new(&e))?;
+            }
+        }
+        let offsets = self
+            .log_reader
+            .offsets_for_times(tpl, METADATA_TIMEOUT)
+            .map_err(|e| CustomError::new(&e))?;
+        self.log_reader
+            .assign(&offsets)
+            .map_err(|e| CustomError::new(&e))
+    }
+
+    /// Fold the matches of the last `LOOKBACK_MILLIS` into `aggregator` without publishing,
+    /// up to the end of the log topic as of the call. An aggregator that runs in the engine
+    /// then continues the candles already published instead of overwriting them.
+    pub async fn catch_up(&mut self, aggregator: &mut Aggregator) -> Result<(), CustomError> {
+        self.seek_to_time(chrono::Utc::now().timestamp_millis() - LOOKBACK_MILLIS)?;
+
+        let assignment = self
+            .log_reader
+            .assignment()
+            .map_err(|e| CustomError::new(&e))?;
+        let mut ends: HashMap<i32, i64> = HashMap::new();
+        for e in assignment.elements_for_topic(&self.topic) {
+            // a partition without logs since then has nothing to fold
+            let Offset::Offset(start) = e.offset() else {
+                continue;
+            };
+            let (_, high) = self
+                .log_reader
+                .fetch_watermarks(&self.topic, e.partition(), METADATA_TIMEOUT)
+                .map_err(|e| CustomError::new(&e))?;
+            if start < high {
+                ends.insert(e.partition(), high);
+            }
+        }
+
+        let mut folded = 0usize;
+        while !ends.is_empty() {
+            let message = match timeout(CATCH_UP_IDLE, self.log_reader.recv()).await {
+                Ok(r) => r.map_err(|e| CustomError::new(&e))?,
+                Err(_) => break,
+            };
+            if let Some(m) = message.payload().map(match_of).transpose()?.flatten()
+                && aggregator.add_match(&m)
+            {
+                folded += 1;
+            }
+            if ends
+                .get(&message.partition())
+                .is_some_and(|high| message.offset() >= high - 1)
+            {
+                ends.remove(&message.partition());
+            }
+        }
+        info!("market data of {} caught up with {} trades", aggregator.product_id, folded);
+        Ok(())
+    }
+
+    /// Wait for the next log and return it if it is a match.
+    pub async fn fetch_match(&mut self) -> Result<Option<MatchLog>, CustomError> {
+        let message = self
+            .log_reader
+            .recv()
+            .await
+            .map_err(|e| CustomError::new(&e))?;
+        match message.payload() {
+            Some(payload) => match_of(payload),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The match in a log payload, `None` for any other log. The payload is decoded once and
+/// the match read from the decoded log.
+fn match_of(payload: &[u8]) -> Result<Option<MatchLog>, CustomError> {
+    let log = decode_log(payload, &payload_format(payload))?;
+    if log["base"]["type"] != "match" {
+        return Ok(None);
+    }
+    MatchLog::deserialize(&log)
+        .map(Some)
+        .map_err(|e| CustomError::new(&e))
+}
+
+/// Fold the match logs of a product as they are committed and publish the ticker and
+/// candles. While catching up, up to `MAX_BATCH` trades are folded per publish.
+pub async fn run_market_data<P: MarketDataPublisher>(
+    match_reader: &mut KafkaMatchReader,
+    aggregator: &mut Aggregator,
+    publisher: &mut P,
+) {
+    info!("market data started: product={}", aggregator.product_id);
+    loop {
+        let mut folded = 0usize;
+        let mut next = match_reader.fetch_match().await;
+        loop {
+            match next {
+                Ok(Some(m)) => {
+                    if aggregator.add_match(&m) {
+                        folded += 1;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!("{}", e),
+            }
+            if folded >= MAX_BATCH {
+                break;
+            }
+            match timeout(BATCH_IDLE, match_reader.fetch_match()).await {
+                Ok(r) => next = r,
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = aggregator.publish(publisher).await {
+            error!("publish market data failed: {}", e);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::result::Result;
+use std::sync::Arc;
 use std::time::Duration;
 
 use rdkafka::consumer::Consumer;
@@ -6,7 +7,10 @@ use rdkafka::error::RDKafkaErrorCode;
 use rdkafka::{Message, Offset};
 use tokio::time::timeout;
 
+use crate::matching::codec::decode_order;
+use crate::matching::store::OrderSource;
 use crate::models::models::Order;
+use crate::models::types::WireFormat;
 use crate::utils::error::CustomError;
 use crate::utils::kafka::new_kafka_consumer;
 use crate::utils::kafka::DefaultConsumer;
@@ -15,7 +19,9 @@ const TOPIC_ORDER_PREFIX: &str = "matching_order_";
 
 pub struct KafkaOrderReader {
     pub topic: String,
-    pub order_consumer: DefaultConsumer,
+    // shared with a log store that commits the offsets of the orders with their logs
+    pub order_consumer: Arc<DefaultConsumer>,
+    pub format: WireFormat,
 }
 
 impl KafkaOrderReader {
@@ -24,12 +30,15 @@ impl KafkaOrderReader {
         group_id: &str,
         product_id: &str,
         session_time_out: u64,
+        format: &WireFormat,
+        exactly_once: bool,
     ) -> Result<KafkaOrderReader, CustomError> {
         let topic = String::from(&[TOPIC_ORDER_PREFIX, product_id].join(""));
-        match new_kafka_consumer(brokers, group_id, topic.as_str(), session_time_out) {
+        match new_kafka_consumer(brokers, group_id, topic.as_str(), session_time_out, exactly_once) {
             Ok(dc) => Ok(KafkaOrderReader {
                 topic,
-                order_consumer: dc,
+                order_consumer: Arc::new(dc),
+                format: format.clone(),
             }),
             Err(e) => Err(CustomError::new(&e)),
         }
@@ -79,17 +88,24 @@ impl KafkaOrderReader {
             },
         };
     }
+}
+
+impl OrderSource for KafkaOrderReader {
+    async fn seek(&mut self, order_offset: u64) -> Result<(), CustomError> {
+        let offset = if order_offset == 0 {
+            Offset::Beginning
+        } else {
+            Offset::Offset(order_offset as i64 + 1)
+        };
+        self.set_offset(offset).await
+    }
 
-    pub async fn fetch_order(&mut self) -> Result<(i64, Option<Order>), CustomError> {
+    async fn fetch_order(&mut self) -> Result<(i64, Option<Order>), CustomError> {
         let (offset, payload) = self.fetch_message().await?;
 
         match payload {
             None => Ok((0, None)),
-            Some(v) => match serde_json::from_slice(&v) {
-                Ok(order) => Ok((offset, Some(order))),
-                // json serde err
-                Err(e) => Err(CustomError::new(&e)),
-            },
+            Some(v) => Ok((offset, Some(decode_order(&v, &self.format)?))),
         }
     }
 }
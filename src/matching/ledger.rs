@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Add, Sub};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::matching::log::MatchLog;
+use crate::models::types::*;
+
+/// One of the two currencies of the product.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Asset {
+    AssetBase,
+    AssetQuote,
+}
+
+impl Asset {
+    /// The currency an order of `side` pays with.
+    pub fn paid_by(side: &Side) -> Asset {
+        match side {
+            Side::SideBuy => Asset::AssetQuote,
+            Side::SideSell => Asset::AssetBase,
+        }
+    }
+}
+
+/// Funds of a user in one currency. Held funds back open orders and are neither available
+/// to new orders nor to withdrawals.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Balance {
+    pub available: Decimal,
+    pub hold: Decimal,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Account {
+    pub user_id: u64,
+    pub base: Balance,
+    pub quote: Balance,
+}
+
+impl Account {
+    pub fn balance(&self, asset: &Asset) -> &Balance {
+        match asset {
+            Asset::AssetBase => &self.base,
+            Asset::AssetQuote => &self.quote,
+        }
+    }
+
+    fn balance_mut(&mut self, asset: &Asset) -> &mut Balance {
+        match asset {
+            Asset::AssetBase => &mut self.base,
+            Asset::AssetQuote => &mut self.quote,
+        }
+    }
+}
+
+/// Funds held for one order on the book or in the stop book, in the currency it pays with.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrderHold {
+    pub order_id: u64,
+    pub user_id: u64,
+    #[serde(serialize_with = "serialize_side")]
+    #[serde(deserialize_with = "deserialize_side")]
+    pub side: Side,
+    pub amount: Decimal,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+pub struct LedgerSnapshot {
+    pub accounts: Vec<Account>,
+    pub holds: Vec<OrderHold>,
+}
+
+/// The accounts and holds that changed since the previous snapshot.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+pub struct LedgerDelta {
+    pub accounts: Vec<Account>,
+    pub holds: Vec<OrderHold>,
+    // orders whose hold was released
+    #[serde(default)]
+    pub released: Vec<u64>,
+}
+
+impl LedgerSnapshot {
+    pub fn apply_delta(&mut self, delta: &LedgerDelta) {
+        let users: BTreeSet<u64> = delta.accounts.iter().map(|a| a.user_id).collect();
+        let orders: BTreeSet<u64> = delta
+            .holds
+            .iter()
+            .map(|h| h.order_id)
+            .chain(delta.released.iter().copied())
+            .collect();
+        self.accounts.retain(|a| !users.contains(&a.user_id));
+        self.holds.retain(|h| !orders.contains(&h.order_id));
+        self.accounts.extend(delta.accounts.iter().cloned());
+        self.holds.extend(delta.holds.iter().cloned());
+    }
+}
+
+/// Balances of the users of one product, for the pre-trade checks of the risk ledger. Every
+/// order that can still trade holds what it may pay, trades are paid from those holds and
+/// whatever an order did not use is released when it is done.
+#[derive(Default)]
+pub struct Ledger {
+    accounts: BTreeMap<u64, Account>,
+    holds: BTreeMap<u64, OrderHold>,
+    // users and orders changed since the last snapshot, see OrderBook::delta
+    changed_accounts: BTreeSet<u64>,
+    changed_holds: BTreeSet<u64>,
+}
+
+impl Ledger {
+    /// Balances of a user, zero if nothing was ever deposited.
+    pub fn account(&self, user_id: u64) -> Account {
+        match self.accounts.get(&user_id) {
+            Some(a) => a.clone(),
+            None => Account {
+                user_id,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// What an order still holds, zero if it holds nothing.
+    pub fn order_hold(&self, order_id: u64) -> Decimal {
+        self.holds.get(&order_id).map(|h| h.amount).unwrap_or_default()
+    }
+
+    fn account_mut(&mut self, user_id: u64) -> &mut Account {
+        self.changed_accounts.insert(user_id);
+        self.accounts.entry(user_id).or_insert_with(|| Account {
+            user_id,
+            ..Default::default()
+        })
+    }
+
+    pub fn deposit(&mut self, user_id: u64, asset: &Asset, amount: &Decimal) {
+        let balance = self.account_mut(user_id).balance_mut(asset);
+        balance.available = balance.available.add(amount);
+    }
+
+    pub fn withdraw(&mut self, user_id: u64, asset: &Asset, amount: &Decimal) -> Result<(), RejectReason> {
+        if *amount > self.account(user_id).balance(asset).available {
+            return Err(RejectReason::RejectReasonInsufficientFunds);
+        }
+        let balance = self.account_mut(user_id).balance_mut(asset);
+        balance.available = balance.available.sub(amount);
+        Ok(())
+    }
+
+    /// Set the hold of an order to `amount`, for a new order or an amended one. Fails without
+    /// a change if the user does not have the increase available.
+    pub fn set_hold(&mut self, order_id: u64, user_id: u64, side: &Side, amount: &Decimal) -> Result<(), RejectReason> {
+        let asset = Asset::paid_by(side);
+        let increase = amount.sub(self.order_hold(order_id));
+        if increase > self.account(user_id).balance(&asset).available {
+            return Err(RejectReason::RejectReasonInsufficientFunds);
+        }
+
+        let balance = self.account_mut(user_id).balance_mut(&asset);
+        balance.available = balance.available.sub(increase);
+        balance.hold = balance.hold.add(increase);
+        self.changed_holds.insert(order_id);
+        self.holds.insert(
+            order_id,
+            OrderHold {
+                order_id,
+                user_id,
+                side: side.clone(),
+                amount: *amount,
+            },
+        );
+        Ok(())
+    }
+
+    /// Give back what is left of the hold of an order that is done.
+    pub fn release(&mut self, order_id: u64) {
+        let Some(hold) = self.holds.remove(&order_id) else {
+            return;
+        };
+        self.changed_holds.insert(order_id);
+        let balance = self.account_mut(hold.user_id).balance_mut(&Asset::paid_by(&hold.side));
+        balance.hold = balance.hold.sub(hold.amount);
+        balance.available = balance.available.add(hold.amount);
+    }
+
+    /// Move the funds of a trade. The buyer pays the notional and its fee in the quote
+    /// currency and the seller the size in the base currency, each from the hold of its
+    /// order. The seller receives the notional less its fee, and the fees leave the ledger.
+    pub fn settle(&mut self, m: &MatchLog) {
+        let maker = (m.maker_order_id, m.maker_user_id, m.maker_fee);
+        let taker = (m.taker_order_id, m.taker_user_id, m.taker_fee);
+        let (buyer, seller) = match m.side {
+            Side::SideBuy => (maker, taker),
+            Side::SideSell => (taker, maker),
+        };
+
+        let (order_id, user_id, fee) = buyer;
+        self.spend(order_id, user_id, &Side::SideBuy, &m.notional.add(fee));
+        let base = &mut self.account_mut(user_id).base;
+        base.available = base.available.add(m.size);
+
+        let (order_id, user_id, fee) = seller;
+        self.spend(order_id, user_id, &Side::SideSell, &m.size);
+        let quote = &mut self.account_mut(user_id).quote;
+        quote.available = quote.available.add(m.notional.sub(fee));
+    }
+
+    /// Pay from the hold of an order. The book never makes a fill the hold does not cover,
+    /// see OrderBook::can_pay, anything past it would come from the available balance so
+    /// that the funds of the trade are always moved in full.
+    fn spend(&mut self, order_id: u64, user_id: u64, side: &Side, amount: &Decimal) {
+        let from_hold = match self.holds.get_mut(&order_id) {
+            Some(hold) => {
+                let from_hold = Decimal::min(hold.amount, *amount);
+                hold.amount = hold.amount.sub(from_hold);
+                self.changed_holds.insert(order_id);
+                from_hold
+            }
+            None => Decimal::ZERO,
+        };
+        let balance = self.account_mut(user_id).balance_mut(&Asset::paid_by(side));
+        balance.hold = balance.hold.sub(from_hold);
+        balance.available = balance.available.sub(amount.sub(from_hold));
+    }
+
+    pub fn snapshot(&self) -> LedgerSnapshot {
+        LedgerSnapshot {
+            accounts: self.accounts.values().cloned().collect(),
+            holds: self.holds.values().cloned().collect(),
+        }
+    }
+
+    pub fn delta(&self) -> LedgerDelta {
+        let mut delta = LedgerDelta::default();
+        for user_id in &self.changed_accounts {
+            if let Some(a) = self.accounts.get(user_id) {
+                delta.accounts.push(a.clone());
+            }
+        }
+        for order_id in &self.changed_holds {
+            match self.holds.get(order_id) {
+                Some(h) => delta.holds.push(h.clone()),
+                None => delta.released.push(*order_id),
+            }
+        }
+        delta
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.changed_accounts.clear();
+        self.changed_holds.clear();
+    }
+
+    pub fn restore(&mut self, snapshot: &LedgerSnapshot) {
+        self.accounts = snapshot
+            .accounts
+            .iter()
+            .map(|a| (a.user_id, a.clone()))
+            .collect();
+        self.holds = snapshot
+            .holds
+            .iter()
+            .map(|h| (h.order_id, h.clone()))
+            .collect();
+        self.clear_changes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::engine::Engine;
+    use crate::matching::ledger::{Asset, Balance};
+    use crate::matching::order_book::OrderBook;
+    use crate::models::models::{Order, Product, Transfer};
+    use crate::models::types::{OrderStatus, Side, TransferType};
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn make_order(id: u64, user_id: u64, side: Side, size: &str, price: &str) -> Order {
+        Order {
+            created_at: 1695783003020967000 + id,
+            ..Order::new_test_order(id, user_id, side, size, price)
+        }
+    }
+
+    fn make_transfer(id: u64, user_id: u64, r#type: TransferType, currency: &str, amount: &str) -> Order {
+        let mut order = make_order(id, user_id, Side::SideBuy, "0", "0");
+        order.status = OrderStatus::OrderStatusControl;
+        order.transfer = Some(Transfer {
+            r#type,
+            currency: currency.to_string(),
+            amount: dec(amount),
+        });
+        order
+    }
+
+    fn balance(engine: &Engine, user_id: u64, asset: &Asset) -> Balance {
+        engine.order_book.ledger.account(user_id).balance(asset).clone()
+    }
+
+    fn to_json(engine: &mut Engine, order: &Order) -> Vec<serde_json::Value> {
+        engine
+            .apply(order)
+            .iter()
+            .map(|l| serde_json::to_value(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_holds_follow_fills_and_cancels() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "USD".to_string(),
+            base_scale: 6,
+            quote_scale: 2,
+            taker_fee_bps: dec("10"),
+            risk_ledger: true,
+            ..Default::default()
+        };
+        let mut engine = Engine::new_engine(&product);
+        let (base, quote) = (Asset::AssetBase, Asset::AssetQuote);
+
+        let logs = to_json(&mut engine, &make_transfer(1, 1, TransferType::TransferTypeDeposit, "USD", "1000"));
+        assert_eq!(logs[0]["base"]["type"], "transfer");
+        assert_eq!(logs[0]["available"], "1000");
+        engine.apply(&make_transfer(2, 2, TransferType::TransferTypeDeposit, "BTC", "1"));
+        let logs = to_json(&mut engine, &make_transfer(3, 2, TransferType::TransferTypeDeposit, "ETH", "1"));
+        assert_eq!(logs[0]["reject_reason"], "invalid_currency");
+
+        // 1000 plus the taker fee is more than the user has
+        let logs = to_json(&mut engine, &make_order(4, 1, Side::SideBuy, "2", "500"));
+        assert_eq!(logs[0]["reject_reason"], "insufficient_funds");
+
+        engine.apply(&make_order(5, 1, Side::SideBuy, "1", "500"));
+        assert_eq!(balance(&engine, 1, &quote), Balance { available: dec("499.50"), hold: dec("500.50") });
+
+        // the maker pays no fee, the taker gets the notional less its fee
+        engine.apply(&make_order(6, 2, Side::SideSell, "0.4", "500"));
+        assert_eq!(balance(&engine, 1, &quote), Balance { available: dec("499.50"), hold: dec("300.50") });
+        assert_eq!(balance(&engine, 1, &base), Balance { available: dec("0.4"), hold: Decimal::ZERO });
+        assert_eq!(balance(&engine, 2, &base), Balance { available: dec("0.6"), hold: Decimal::ZERO });
+        assert_eq!(balance(&engine, 2, &quote), Balance { available: dec("199.80"), hold: Decimal::ZERO });
+
+        let logs = to_json(&mut engine, &make_transfer(7, 1, TransferType::TransferTypeWithdraw, "USD", "600"));
+        assert_eq!(logs[0]["reject_reason"], "insufficient_funds");
+        assert_eq!(logs[0]["hold"], "300.50");
+
+        // the ledger is part of the snapshot
+        let mut restored = OrderBook::new_order_book(&product);
+        restored.restore(&engine.order_book.snapshot());
+        assert_eq!(restored.ledger.account(1), engine.order_book.ledger.account(1));
+        assert_eq!(restored.ledger.order_hold(5), dec("300.50"));
+
+        // cancelling gives back what the order still held
+        let mut snapshot = engine.order_book.snapshot();
+        engine.order_book.clear_changes();
+        let mut cancel = make_order(5, 1, Side::SideBuy, "0.6", "500");
+        cancel.status = OrderStatus::OrderStatusCancelling;
+        engine.apply(&cancel);
+        assert_eq!(balance(&engine, 1, &quote), Balance { available: dec("800.00"), hold: dec("0.00") });
+        assert_eq!(engine.order_book.ledger.order_hold(5), Decimal::ZERO);
+
+        // and so is a delta
+        snapshot.apply_delta(&engine.order_book.delta());
+        let mut restored = OrderBook::new_order_book(&product);
+        restored.restore(&snapshot);
+        assert_eq!(restored.ledger.account(1), engine.order_book.ledger.account(1));
+        assert!(restored.ledger.snapshot().holds.is_empty());
+    }
+
+    #[test]
+    fn test_rounded_fills_never_overdraw() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "USD".to_string(),
+            base_scale: 6,
+            quote_scale: 2,
+            risk_ledger: true,
+            ..Default::default()
+        };
+        let mut engine = Engine::new_engine(&product);
+        let quote = Asset::AssetQuote;
+        let total_quote = |engine: &Engine| -> Decimal {
+            engine
+                .order_book
+                .ledger
+                .snapshot()
+                .accounts
+                .iter()
+                .map(|a| a.quote.available + a.quote.hold)
+                .sum()
+        };
+
+        engine.apply(&make_transfer(1, 1, TransferType::TransferTypeDeposit, "USD", "0.02"));
+        engine.apply(&make_transfer(2, 2, TransferType::TransferTypeDeposit, "BTC", "1.5"));
+
+        // 0.015 is held as 0.02, all the user has
+        engine.apply(&make_order(3, 1, Side::SideBuy, "1.5", "0.01"));
+        assert_eq!(balance(&engine, 1, &quote), Balance { available: dec("0"), hold: dec("0.02") });
+
+        // each fill of 0.005 rounds up to 0.01, the hold pays for two of them
+        for id in 4..=5 {
+            let logs = to_json(&mut engine, &make_order(id, 2, Side::SideSell, "0.5", "0.01"));
+            assert_eq!(logs[0]["notional"], "0.01");
+            assert_eq!(total_quote(&engine), dec("0.02"));
+        }
+
+        // a third fill would go past the hold, the buy order is cancelled instead
+        let logs = to_json(&mut engine, &make_order(6, 2, Side::SideSell, "0.5", "0.01"));
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["order_id"], 3);
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert_eq!(logs[1]["base"]["type"], "open");
+        assert_eq!(total_quote(&engine), dec("0.02"));
+        assert_eq!(balance(&engine, 1, &quote), Balance { available: dec("0"), hold: dec("0") });
+        assert_eq!(balance(&engine, 2, &quote), Balance { available: dec("0.02"), hold: dec("0") });
+        assert_eq!(balance(&engine, 1, &Asset::AssetBase).available, dec("1.0"));
+    }
+
+    #[test]
+    fn test_book_settles_without_engine() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "USD".to_string(),
+            base_scale: 6,
+            quote_scale: 2,
+            risk_ledger: true,
+            ..Default::default()
+        };
+        let mut book = OrderBook::new_order_book(&product);
+        book.transfer(&make_transfer(1, 1, TransferType::TransferTypeDeposit, "USD", "100"));
+        book.transfer(&make_transfer(2, 2, TransferType::TransferTypeDeposit, "BTC", "1"));
+
+        book.apply_order(&make_order(3, 1, Side::SideBuy, "1", "50"));
+        book.apply_order(&make_order(4, 2, Side::SideSell, "0.5", "50"));
+        let account = book.ledger.account(1);
+        assert_eq!(account.quote, Balance { available: dec("50"), hold: dec("25") });
+        assert_eq!(account.base.available, dec("0.5"));
+
+        // an amend the user cannot hold is refused with a change log and changes nothing
+        let mut amend = make_order(3, 1, Side::SideBuy, "3", "50");
+        amend.status = OrderStatus::OrderStatusAmending;
+        let logs: Vec<serde_json::Value> = book
+            .amend_order(&amend)
+            .iter()
+            .map(|l| serde_json::to_value(l).unwrap())
+            .collect();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "change");
+        assert_eq!(logs[0]["reject_reason"], "insufficient_funds");
+        assert_eq!(logs[0]["new_size"], "0.5");
+        assert_eq!(book.ledger.account(1).quote, account.quote);
+
+        // cancelling straight on the book releases the hold too
+        let mut cancel = make_order(3, 1, Side::SideBuy, "0.5", "50");
+        cancel.status = OrderStatus::OrderStatusCancelling;
+        book.cancel_order(&cancel);
+        assert_eq!(book.ledger.account(1).quote, Balance { available: dec("75"), hold: dec("0") });
+    }
+}
@@ -1,10 +1,12 @@
-use chrono::prelude::*;
 use erased_serde::serialize_trait_object;
 use log::debug;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::matching::depth::PriceLevel;
+use crate::matching::ledger::Balance;
 use crate::matching::order_book::BookOrder;
+use crate::models::models::{Product, Transfer};
 use crate::models::types::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -12,6 +14,13 @@ pub enum LogType {
     LogTypeMatch,
     LogTypeOpen,
     LogTypeDone,
+    LogTypeTrigger,
+    LogTypeChange,
+    LogTypeStatus,
+    LogTypeAuction,
+    LogTypeL2Update,
+    LogTypeTransfer,
+    LogTypeReceived,
 }
 
 pub fn serialize_log_type<S>(log_type: &LogType, serializer: S) -> Result<S::Ok, S::Error>
@@ -22,6 +31,13 @@ where
         LogType::LogTypeMatch => "match",
         LogType::LogTypeOpen => "open",
         LogType::LogTypeDone => "done",
+        LogType::LogTypeTrigger => "trigger",
+        LogType::LogTypeChange => "change",
+        LogType::LogTypeStatus => "status",
+        LogType::LogTypeAuction => "auction",
+        LogType::LogTypeL2Update => "l2update",
+        LogType::LogTypeTransfer => "transfer",
+        LogType::LogTypeReceived => "received",
     };
     serializer.serialize_str(string)
 }
@@ -35,12 +51,29 @@ where
         "match" => Ok(LogType::LogTypeMatch),
         "open" => Ok(LogType::LogTypeOpen),
         "done" => Ok(LogType::LogTypeDone),
+        "trigger" => Ok(LogType::LogTypeTrigger),
+        "change" => Ok(LogType::LogTypeChange),
+        "status" => Ok(LogType::LogTypeStatus),
+        "auction" => Ok(LogType::LogTypeAuction),
+        "l2update" => Ok(LogType::LogTypeL2Update),
+        "transfer" => Ok(LogType::LogTypeTransfer),
+        "received" => Ok(LogType::LogTypeReceived),
         _ => Err(serde::de::Error::custom("invalid log_type string")),
     }
 }
 
-pub trait LogTrait: erased_serde::Serialize {
+pub trait LogTrait: erased_serde::Serialize + Send + Sync {
     fn get_seq(&self) -> u64;
+
+    /// The log as a match, for consumers that fold trades without serializing every log.
+    fn as_match(&self) -> Option<&MatchLog> {
+        None
+    }
+
+    /// The log as a done log, for the risk ledger to release what the order held.
+    fn as_done(&self) -> Option<&DoneLog> {
+        None
+    }
 }
 
 serialize_trait_object!(LogTrait);
@@ -76,7 +109,7 @@ impl LogTrait for OpenLog {
     }
 }
 
-pub fn new_open_log(log_seq: u64, product_id: &str, taker_order: &BookOrder) -> OpenLog {
+pub fn new_open_log(log_seq: u64, time: u64, product_id: &str, taker_order: &BookOrder) -> OpenLog {
     debug!(
         "new_open_log: product_id: {} | log_seq:{} | order:{}",
         product_id,
@@ -88,7 +121,7 @@ pub fn new_open_log(log_seq: u64, product_id: &str, taker_order: &BookOrder) ->
             r#type: LogType::LogTypeOpen,
             sequence: log_seq,
             product_id: product_id.to_string(),
-            time: Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64,
+            time,
         },
         order_id: taker_order.order_id,
         user_id: taker_order.user_id,
@@ -109,6 +142,11 @@ pub struct DoneLog {
     #[serde(serialize_with = "serialize_done_reason")]
     #[serde(deserialize_with = "deserialize_done_reason")]
     pub reason: DoneReason,
+    // only set when the reason is rejected
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_reject_reason")]
+    #[serde(deserialize_with = "deserialize_reject_reason")]
+    pub reject_reason: Option<RejectReason>,
     #[serde(serialize_with = "serialize_side")]
     #[serde(deserialize_with = "deserialize_side")]
     pub side: Side,
@@ -121,10 +159,15 @@ impl LogTrait for DoneLog {
     fn get_seq(&self) -> u64 {
         self.base.sequence
     }
+
+    fn as_done(&self) -> Option<&DoneLog> {
+        Some(self)
+    }
 }
 
 pub fn new_done_log(
     log_seq: u64,
+    time: u64,
     product_id: &str,
     order: &BookOrder,
     remaining_size: &Decimal,
@@ -142,13 +185,14 @@ pub fn new_done_log(
             r#type: LogType::LogTypeDone,
             sequence: log_seq,
             product_id: product_id.to_string(),
-            time: Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64,
+            time,
         },
         order_id: order.order_id,
         user_id: order.user_id,
         price: order.price,
         remaining_size: *remaining_size,
         reason: reason.clone(),
+        reject_reason: None,
         side: order.side.clone(),
         time_in_force: order.time_in_force.clone(),
     }
@@ -173,17 +217,29 @@ pub struct MatchLog {
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub maker_time_in_force: TimeInForceType,
+    // price * size and the fees on it in the quote currency, at quote_scale
+    #[serde(default)]
+    pub notional: Decimal,
+    #[serde(default)]
+    pub maker_fee: Decimal,
+    #[serde(default)]
+    pub taker_fee: Decimal,
 }
 
 impl LogTrait for MatchLog {
     fn get_seq(&self) -> u64 {
         self.base.sequence
     }
+
+    fn as_match(&self) -> Option<&MatchLog> {
+        Some(self)
+    }
 }
 
 pub fn new_match_log(
     log_seq: u64,
-    product_id: &str,
+    time: u64,
+    product: &Product,
     trade_seq: u64,
     taker_order: &BookOrder,
     maker_order: &BookOrder,
@@ -192,7 +248,7 @@ pub fn new_match_log(
 ) -> MatchLog {
     debug!(
         "new_match_log: product_id: {} | log_seq:{} | trade_seq:{} | taker_order_id:{} | maker_order_id:{} | price:{} | size:{}",
-        product_id,
+        product.id,
         log_seq,
         trade_seq,
         taker_order.order_id,
@@ -200,12 +256,15 @@ pub fn new_match_log(
         price,
         size
     );
+    let notional = product.notional(price, size);
+    let maker_fee_bps = maker_order.maker_fee_bps.unwrap_or(product.maker_fee_bps);
+    let taker_fee_bps = taker_order.taker_fee_bps.unwrap_or(product.taker_fee_bps);
     MatchLog {
         base: Base {
             r#type: LogType::LogTypeMatch,
             sequence: log_seq,
-            product_id: product_id.to_string(),
-            time: Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64,
+            product_id: product.id.clone(),
+            time,
         },
         trade_seq,
         taker_order_id: taker_order.order_id,
@@ -217,5 +276,375 @@ pub fn new_match_log(
         size: *size,
         taker_time_in_force: taker_order.time_in_force.clone(),
         maker_time_in_force: maker_order.time_in_force.clone(),
+        notional,
+        maker_fee: product.fee(price, size, &maker_fee_bps),
+        taker_fee: product.fee(price, size, &taker_fee_bps),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TriggerLog {
+    pub base: Base,
+    pub order_id: u64,
+    pub user_id: u64,
+    #[serde(serialize_with = "serialize_order_type")]
+    #[serde(deserialize_with = "deserialize_order_type")]
+    pub order_type: OrderType,
+    pub stop_price: Decimal,
+    pub last_trade_price: Decimal,
+    #[serde(serialize_with = "serialize_side")]
+    #[serde(deserialize_with = "deserialize_side")]
+    pub side: Side,
+    #[serde(serialize_with = "serialize_time_in_force_type")]
+    #[serde(deserialize_with = "deserialize_time_in_force_type")]
+    pub time_in_force: TimeInForceType,
+}
+
+impl LogTrait for TriggerLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_trigger_log(
+    log_seq: u64,
+    time: u64,
+    product_id: &str,
+    order: &BookOrder,
+    last_trade_price: &Decimal,
+) -> TriggerLog {
+    debug!(
+        "new_trigger_log: product_id: {} | log_seq:{} | order_id:{} | stop_price:{} | last_trade_price:{}",
+        product_id,
+        log_seq,
+        order.order_id,
+        order.stop_price,
+        last_trade_price
+    );
+    TriggerLog {
+        base: Base {
+            r#type: LogType::LogTypeTrigger,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        order_id: order.order_id,
+        user_id: order.user_id,
+        order_type: order.r#type.clone(),
+        stop_price: order.stop_price,
+        last_trade_price: *last_trade_price,
+        side: order.side.clone(),
+        time_in_force: order.time_in_force.clone(),
+    }
+}
+
+/// A stop order accepted into the trigger book, where it waits without a trace on the
+/// order book until it is triggered or cancelled.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReceivedLog {
+    pub base: Base,
+    pub order_id: u64,
+    pub user_id: u64,
+    #[serde(serialize_with = "serialize_order_type")]
+    #[serde(deserialize_with = "deserialize_order_type")]
+    pub order_type: OrderType,
+    pub size: Decimal,
+    pub funds: Decimal,
+    pub price: Decimal,
+    pub stop_price: Decimal,
+    #[serde(serialize_with = "serialize_side")]
+    #[serde(deserialize_with = "deserialize_side")]
+    pub side: Side,
+    #[serde(serialize_with = "serialize_time_in_force_type")]
+    #[serde(deserialize_with = "deserialize_time_in_force_type")]
+    pub time_in_force: TimeInForceType,
+}
+
+impl LogTrait for ReceivedLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_received_log(log_seq: u64, time: u64, product_id: &str, order: &BookOrder) -> ReceivedLog {
+    debug!(
+        "new_received_log: product_id: {} | log_seq:{} | order_id:{} | stop_price:{}",
+        product_id, log_seq, order.order_id, order.stop_price
+    );
+    ReceivedLog {
+        base: Base {
+            r#type: LogType::LogTypeReceived,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        order_id: order.order_id,
+        user_id: order.user_id,
+        order_type: order.r#type.clone(),
+        size: order.size,
+        funds: order.funds,
+        price: order.price,
+        stop_price: order.stop_price,
+        side: order.side.clone(),
+        time_in_force: order.time_in_force.clone(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangeLog {
+    pub base: Base,
+    pub order_id: u64,
+    pub user_id: u64,
+    #[serde(serialize_with = "serialize_side")]
+    #[serde(deserialize_with = "deserialize_side")]
+    pub side: Side,
+    pub old_size: Decimal,
+    pub new_size: Decimal,
+    pub old_price: Decimal,
+    pub new_price: Decimal,
+    #[serde(serialize_with = "serialize_time_in_force_type")]
+    #[serde(deserialize_with = "deserialize_time_in_force_type")]
+    pub time_in_force: TimeInForceType,
+    // set when an amend was refused and the order is unchanged
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_reject_reason")]
+    #[serde(deserialize_with = "deserialize_reject_reason")]
+    pub reject_reason: Option<RejectReason>,
+}
+
+impl LogTrait for ChangeLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_change_log(
+    log_seq: u64,
+    time: u64,
+    product_id: &str,
+    order: &BookOrder,
+    old_size: &Decimal,
+    new_size: &Decimal,
+    new_price: &Decimal,
+) -> ChangeLog {
+    debug!(
+        "new_change_log: product_id: {} | log_seq:{} | order_id:{} | size:{}->{} | price:{}->{}",
+        product_id,
+        log_seq,
+        order.order_id,
+        old_size,
+        new_size,
+        order.price,
+        new_price
+    );
+    ChangeLog {
+        base: Base {
+            r#type: LogType::LogTypeChange,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        order_id: order.order_id,
+        user_id: order.user_id,
+        side: order.side.clone(),
+        old_size: *old_size,
+        new_size: *new_size,
+        old_price: order.price,
+        new_price: *new_price,
+        time_in_force: order.time_in_force.clone(),
+        reject_reason: None,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StatusLog {
+    pub base: Base,
+    #[serde(serialize_with = "serialize_market_state")]
+    #[serde(deserialize_with = "deserialize_market_state")]
+    pub status: MarketState,
+    // last trade price at the time of the change
+    pub reference_price: Decimal,
+    // price a circuit breaker halting order would have swept the book to, zero otherwise
+    pub trigger_price: Decimal,
+    // time a circuit breaker halt ends at in timestamp_nanos, zero otherwise
+    pub resume_at: u64,
+}
+
+impl LogTrait for StatusLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_status_log(
+    log_seq: u64,
+    time: u64,
+    product_id: &str,
+    status: &MarketState,
+    reference_price: &Decimal,
+    trigger_price: &Decimal,
+    resume_at: u64,
+) -> StatusLog {
+    debug!(
+        "new_status_log: product_id: {} | log_seq:{} | status:{:?} | reference_price:{} | trigger_price:{}",
+        product_id, log_seq, status, reference_price, trigger_price
+    );
+    StatusLog {
+        base: Base {
+            r#type: LogType::LogTypeStatus,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        status: status.clone(),
+        reference_price: *reference_price,
+        trigger_price: *trigger_price,
+        resume_at,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuctionLog {
+    pub base: Base,
+    // price and volume the auction would uncross at right now, zero if the book is not crossed
+    pub indicative_price: Decimal,
+    pub indicative_volume: Decimal,
+    // size left over at the indicative price, positive for buys and negative for sells
+    pub imbalance: Decimal,
+}
+
+impl LogTrait for AuctionLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_auction_log(
+    log_seq: u64,
+    time: u64,
+    product_id: &str,
+    indicative_price: &Decimal,
+    indicative_volume: &Decimal,
+    imbalance: &Decimal,
+) -> AuctionLog {
+    debug!(
+        "new_auction_log: product_id: {} | log_seq:{} | indicative_price:{} | indicative_volume:{} | imbalance:{}",
+        product_id, log_seq, indicative_price, indicative_volume, imbalance
+    );
+    AuctionLog {
+        base: Base {
+            r#type: LogType::LogTypeAuction,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        indicative_price: *indicative_price,
+        indicative_volume: *indicative_volume,
+        imbalance: *imbalance,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct L2UpdateLog {
+    pub base: Base,
+    // new state of every level the command changed, best price first, a zero size means
+    // the level is gone
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+impl LogTrait for L2UpdateLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_l2update_log(
+    log_seq: u64,
+    time: u64,
+    product_id: &str,
+    bids: Vec<PriceLevel>,
+    asks: Vec<PriceLevel>,
+) -> L2UpdateLog {
+    debug!(
+        "new_l2update_log: product_id: {} | log_seq:{} | bids:{} | asks:{}",
+        product_id,
+        log_seq,
+        bids.len(),
+        asks.len()
+    );
+    L2UpdateLog {
+        base: Base {
+            r#type: LogType::LogTypeL2Update,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        bids,
+        asks,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferLog {
+    pub base: Base,
+    // id of the control message
+    pub transfer_id: u64,
+    pub user_id: u64,
+    #[serde(serialize_with = "serialize_transfer_type")]
+    #[serde(deserialize_with = "deserialize_transfer_type")]
+    pub transfer_type: TransferType,
+    pub currency: String,
+    pub amount: Decimal,
+    // balance of the user in the currency after the transfer
+    pub available: Decimal,
+    pub hold: Decimal,
+    // set when the transfer was refused and the balance is unchanged
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_reject_reason")]
+    #[serde(deserialize_with = "deserialize_reject_reason")]
+    pub reject_reason: Option<RejectReason>,
+}
+
+impl LogTrait for TransferLog {
+    fn get_seq(&self) -> u64 {
+        self.base.sequence
+    }
+}
+
+pub fn new_transfer_log(
+    log_seq: u64,
+    time: u64,
+    product_id: &str,
+    transfer_id: u64,
+    user_id: u64,
+    transfer: &Transfer,
+    balance: &Balance,
+) -> TransferLog {
+    debug!(
+        "new_transfer_log: product_id: {} | log_seq:{} | transfer_id:{} | user_id:{} | {} {} {}",
+        product_id,
+        log_seq,
+        transfer_id,
+        user_id,
+        transfer.r#type.as_str(),
+        transfer.amount,
+        transfer.currency
+    );
+    TransferLog {
+        base: Base {
+            r#type: LogType::LogTypeTransfer,
+            sequence: log_seq,
+            product_id: product_id.to_string(),
+            time,
+        },
+        transfer_id,
+        user_id,
+        transfer_type: transfer.r#type.clone(),
+        currency: transfer.currency.clone(),
+        amount: transfer.amount,
+        available: balance.available,
+        hold: balance.hold,
+        reject_reason: None,
     }
 }
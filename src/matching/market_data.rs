@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::ops::{Add, Mul};
+use std::result::Result;
+
+use log::error;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::matching::log::{LogTrait, MatchLog};
+use crate::matching::store::{LogSink, MarketDataPublisher};
+use crate::models::types::*;
+use crate::utils::error::CustomError;
+
+/// Candle sizes in seconds: 1m, 5m, 1h and 1d.
+pub const CANDLE_GRANULARITIES: [u64; 4] = [60, 300, 3600, 86400];
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const DAY_NANOS: u64 = 86400 * NANOS_PER_SECOND;
+
+/// OHLCV of the trades in one interval. Times are in timestamp_nanos like log times.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Candle {
+    pub product_id: String,
+    // length of the candle in seconds
+    pub granularity: u64,
+    pub start: u64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    // base currency traded
+    pub volume: Decimal,
+    // quote currency traded, price * size summed over the trades
+    pub quote_volume: Decimal,
+    pub trade_count: u64,
+}
+
+impl Candle {
+    fn new_candle(product_id: &str, granularity: u64, m: &MatchLog) -> Candle {
+        let length = granularity * NANOS_PER_SECOND;
+        Candle {
+            product_id: product_id.to_string(),
+            granularity,
+            start: m.base.time - m.base.time % length,
+            open: m.price,
+            high: m.price,
+            low: m.price,
+            close: m.price,
+            volume: Decimal::ZERO,
+            quote_volume: Decimal::ZERO,
+            trade_count: 0,
+        }
+    }
+
+    fn add_match(&mut self, m: &MatchLog) {
+        self.high = Decimal::max(self.high, m.price);
+        self.low = Decimal::min(self.low, m.price);
+        self.close = m.price;
+        self.volume = self.volume.add(m.size);
+        self.quote_volume = self.quote_volume.add(m.price.mul(m.size));
+        self.trade_count += 1;
+    }
+
+    fn contains(&self, time: u64) -> bool {
+        time >= self.start && time < self.start + self.granularity * NANOS_PER_SECOND
+    }
+}
+
+/// The last trade and the stats of the 24 hours up to it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ticker {
+    pub product_id: String,
+    pub sequence: u64,
+    pub trade_seq: u64,
+    pub time: u64,
+    pub price: Decimal,
+    pub size: Decimal,
+    // side of the taker
+    #[serde(serialize_with = "serialize_side")]
+    #[serde(deserialize_with = "deserialize_side")]
+    pub side: Side,
+    pub open_24h: Decimal,
+    pub high_24h: Decimal,
+    pub low_24h: Decimal,
+    pub volume_24h: Decimal,
+    pub quote_volume_24h: Decimal,
+}
+
+/// Folds the match logs of one product into a ticker and candles. Only the log times are
+/// read, never the wall clock, so the same logs always give the same output. The 24 hour
+/// window moves in whole minutes and ends at the last trade.
+pub struct Aggregator {
+    pub product_id: String,
+    // sequence of the last log folded, older ones are duplicates
+    pub last_seq: u64,
+    last_match: Option<MatchLog>,
+    // the current candle of each granularity, in the order of CANDLE_GRANULARITIES
+    candles: Vec<Option<Candle>>,
+    updated: Vec<bool>,
+    // one minute candles of the last 24 hours
+    minutes: VecDeque<Candle>,
+}
+
+impl Aggregator {
+    pub fn new_aggregator(product_id: &str) -> Aggregator {
+        Aggregator {
+            product_id: product_id.to_string(),
+            last_seq: 0,
+            last_match: None,
+            candles: vec![None; CANDLE_GRANULARITIES.len()],
+            updated: vec![false; CANDLE_GRANULARITIES.len()],
+            minutes: VecDeque::new(),
+        }
+    }
+
+    /// Fold one trade, false if it was folded before.
+    pub fn add_match(&mut self, m: &MatchLog) -> bool {
+        if m.base.sequence <= self.last_seq {
+            return false;
+        }
+        self.last_seq = m.base.sequence;
+
+        for (i, granularity) in CANDLE_GRANULARITIES.iter().enumerate() {
+            let candle = match &mut self.candles[i] {
+                Some(c) if c.contains(m.base.time) => c,
+                c => c.insert(Candle::new_candle(&self.product_id, *granularity, m)),
+            };
+            candle.add_match(m);
+            self.updated[i] = true;
+        }
+
+        match self.minutes.back_mut() {
+            Some(c) if c.contains(m.base.time) => c.add_match(m),
+            _ => {
+                let mut c = Candle::new_candle(&self.product_id, CANDLE_GRANULARITIES[0], m);
+                c.add_match(m);
+                self.minutes.push_back(c);
+            }
+        }
+        while let Some(c) = self.minutes.front() {
+            if c.start + DAY_NANOS > m.base.time {
+                break;
+            }
+            self.minutes.pop_front();
+        }
+
+        self.last_match = Some(m.clone());
+        true
+    }
+
+    /// The ticker as of the last trade, `None` before the first one.
+    pub fn ticker(&self) -> Option<Ticker> {
+        let m = self.last_match.as_ref()?;
+        let first = self.minutes.front()?;
+        let mut ticker = Ticker {
+            product_id: self.product_id.clone(),
+            sequence: m.base.sequence,
+            trade_seq: m.trade_seq,
+            time: m.base.time,
+            price: m.price,
+            size: m.size,
+            side: m.side.clone().opposite(),
+            open_24h: first.open,
+            high_24h: first.high,
+            low_24h: first.low,
+            volume_24h: Decimal::ZERO,
+            quote_volume_24h: Decimal::ZERO,
+        };
+        for c in &self.minutes {
+            ticker.high_24h = Decimal::max(ticker.high_24h, c.high);
+            ticker.low_24h = Decimal::min(ticker.low_24h, c.low);
+            ticker.volume_24h = ticker.volume_24h.add(c.volume);
+            ticker.quote_volume_24h = ticker.quote_volume_24h.add(c.quote_volume);
+        }
+        Some(ticker)
+    }
+
+    /// The candles that got trades since the last call, in their current state.
+    pub fn take_updated_candles(&mut self) -> Vec<Candle> {
+        let mut candles: Vec<Candle> = Vec::new();
+        for (i, updated) in self.updated.iter_mut().enumerate() {
+            if *updated && let Some(c) = &self.candles[i] {
+                candles.push(c.clone());
+            }
+            *updated = false;
+        }
+        candles
+    }
+
+    /// Publish the ticker and the updated candles, if there was a trade since the last call.
+    pub async fn publish<P: MarketDataPublisher>(&mut self, publisher: &mut P) -> Result<(), CustomError> {
+        let candles = self.take_updated_candles();
+        if candles.is_empty() {
+            return Ok(());
+        }
+        match self.ticker() {
+            Some(ticker) => publisher.publish(&ticker, &candles).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs the aggregator in the engine process. Logs go to the inner sink first, and the
+/// trades of a batch are folded and published once the batch is stored, so market data
+/// never shows a trade that is not in the log topic.
+pub struct MarketDataLogSink<L: LogSink, P: MarketDataPublisher> {
+    pub log_sink: L,
+    pub aggregator: Aggregator,
+    pub publisher: P,
+}
+
+impl<L: LogSink, P: MarketDataPublisher> MarketDataLogSink<L, P> {
+    pub fn new_market_data_log_sink(product_id: &str, log_sink: L, publisher: P) -> Self {
+        MarketDataLogSink {
+            log_sink,
+            aggregator: Aggregator::new_aggregator(product_id),
+            publisher,
+        }
+    }
+}
+
+impl<L: LogSink + Send, P: MarketDataPublisher + Send> LogSink for MarketDataLogSink<L, P> {
+    async fn store(&mut self, logs: &[Box<dyn LogTrait>], order_offset: u64) -> Result<(), CustomError> {
+        self.log_sink.store(logs, order_offset).await?;
+
+        for log in logs {
+            if let Some(m) = log.as_match() {
+                self.aggregator.add_match(m);
+            }
+        }
+        // the logs are stored, a market data failure must not fail the batch
+        if let Err(e) = self.aggregator.publish(&mut self.publisher).await {
+            error!("publish market data failed: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn last_seq(&mut self) -> Result<u64, CustomError> {
+        self.log_sink.last_seq().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::matching::log::{new_match_log, MatchLog};
+    use crate::matching::market_data::Aggregator;
+    use crate::matching::order_book::BookOrder;
+    use crate::models::models::Product;
+    use crate::models::types::Side;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    fn make_match(seq: u64, minute: u64, price: &str, size: &str) -> MatchLog {
+        let taker = BookOrder {
+            order_id: 2,
+            side: Side::SideBuy,
+            ..Default::default()
+        };
+        let maker = BookOrder {
+            order_id: 1,
+            side: Side::SideSell,
+            ..Default::default()
+        };
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            ..Default::default()
+        };
+        new_match_log(
+            seq,
+            1_700_000_100 * 1_000_000_000 + minute * MINUTE,
+            &product,
+            seq,
+            &taker,
+            &maker,
+            &Decimal::from_str(price).unwrap(),
+            &Decimal::from_str(size).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_candles_and_ticker() {
+        let mut aggregator = Aggregator::new_aggregator("BTC-USD");
+        assert!(aggregator.ticker().is_none());
+
+        assert!(aggregator.add_match(&make_match(1, 0, "100", "1")));
+        assert!(aggregator.add_match(&make_match(2, 0, "103", "2")));
+        assert!(aggregator.add_match(&make_match(3, 1, "101", "1")));
+        // a duplicate from a restarted stream is ignored
+        assert!(!aggregator.add_match(&make_match(3, 1, "101", "1")));
+
+        let candles = aggregator.take_updated_candles();
+        assert_eq!(candles.len(), 4);
+        // the minute rolled over, the 5 minute candle did not
+        assert_eq!(candles[0].trade_count, 1);
+        assert_eq!(candles[0].open, Decimal::from_str("101").unwrap());
+        assert_eq!(candles[1].trade_count, 3);
+        assert_eq!(candles[1].high, Decimal::from_str("103").unwrap());
+        assert_eq!(candles[1].close, Decimal::from_str("101").unwrap());
+        assert_eq!(candles[1].volume, Decimal::from_str("4").unwrap());
+        assert_eq!(candles[1].quote_volume, Decimal::from_str("407").unwrap());
+        assert!(aggregator.take_updated_candles().is_empty());
+
+        let ticker = aggregator.ticker().unwrap();
+        assert_eq!(ticker.price, Decimal::from_str("101").unwrap());
+        assert!(matches!(ticker.side, Side::SideBuy));
+        assert_eq!(ticker.open_24h, Decimal::from_str("100").unwrap());
+        assert_eq!(ticker.volume_24h, Decimal::from_str("4").unwrap());
+
+        // a day later the first minute has left the window
+        aggregator.add_match(&make_match(4, 24 * 60, "90", "1"));
+        let ticker = aggregator.ticker().unwrap();
+        assert_eq!(ticker.open_24h, Decimal::from_str("101").unwrap());
+        assert_eq!(ticker.low_24h, Decimal::from_str("90").unwrap());
+        assert_eq!(ticker.volume_24h, Decimal::from_str("2").unwrap());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::future::pending;
+use std::result::Result;
+
+use crate::matching::engine::Snapshot;
+use crate::matching::log::LogTrait;
+use crate::matching::market_data::{Candle, Ticker};
+use crate::matching::order_book::L3Snapshot;
+use crate::matching::store::{
+    open_snapshot, seal_snapshot, BookPublisher, LogSink, MarketDataPublisher, OrderSource,
+    SnapshotInfo, SnapshotStore,
+};
+use crate::models::models::Order;
+use crate::utils::error::CustomError;
+
+/// Orders held in memory, the offset of an order is its index. Once every order has been
+/// fetched it waits forever, like a consumer on an idle topic, unless `stop_at_end` is set.
+#[derive(Debug, Default)]
+pub struct MemoryOrderSource {
+    pub orders: Vec<Order>,
+    // report the source exhausted after the last order, so the engine stops
+    pub stop_at_end: bool,
+    next: usize,
+}
+
+impl MemoryOrderSource {
+    pub fn new_memory_order_source(orders: Vec<Order>) -> Self {
+        MemoryOrderSource {
+            orders,
+            stop_at_end: false,
+            next: 0,
+        }
+    }
+}
+
+impl OrderSource for MemoryOrderSource {
+    async fn seek(&mut self, order_offset: u64) -> Result<(), CustomError> {
+        self.next = if order_offset == 0 {
+            0
+        } else {
+            order_offset as usize + 1
+        };
+        Ok(())
+    }
+
+    async fn fetch_order(&mut self) -> Result<(i64, Option<Order>), CustomError> {
+        match self.orders.get(self.next) {
+            Some(order) => {
+                let offset = self.next as i64;
+                self.next += 1;
+                Ok((offset, Some(order.clone())))
+            }
+            None => pending().await,
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.stop_at_end && self.next >= self.orders.len()
+    }
+}
+
+/// Keeps every stored log serialized the way it is sent to the log topic, and the offset
+/// of the last order committed with them.
+#[derive(Debug, Default)]
+pub struct MemoryLogSink {
+    pub logs: Vec<String>,
+    pub order_offset: u64,
+}
+
+impl LogSink for MemoryLogSink {
+    async fn store(&mut self, logs: &[Box<dyn LogTrait>], order_offset: u64) -> Result<(), CustomError> {
+        for log in logs {
+            let s = serde_json::to_string(log).map_err(|e| CustomError::new(&e))?;
+            self.logs.push(s);
+        }
+        self.order_offset = order_offset;
+        Ok(())
+    }
+
+    async fn last_seq(&mut self) -> Result<u64, CustomError> {
+        Ok(self.logs.iter().map(|s| log_seq(s)).max().unwrap_or(0))
+    }
+}
+
+/// Sequence of a serialized log.
+pub fn log_seq(s: &str) -> u64 {
+    serde_json::from_str::<serde_json::Value>(s)
+        .map(|v| v["base"]["sequence"].as_u64().unwrap_or(0))
+        .unwrap_or(0)
+}
+
+/// Sealed snapshots by their info, keeping the newest `retention` of them with their
+/// chains, or all if 0.
+#[derive(Debug, Default)]
+pub struct MemorySnapshotStore {
+    pub snapshots: BTreeMap<SnapshotInfo, String>,
+    pub retention: usize,
+}
+
+impl SnapshotStore for MemorySnapshotStore {
+    async fn store(&mut self, snapshot: &Snapshot) -> Result<(), CustomError> {
+        self.snapshots
+            .insert(SnapshotInfo::of(snapshot), seal_snapshot(snapshot)?);
+        for info in self.expired(self.retention).await? {
+            self.snapshots.remove(&info);
+        }
+        Ok(())
+    }
+
+    async fn list(&mut self) -> Result<Vec<SnapshotInfo>, CustomError> {
+        Ok(self.snapshots.keys().rev().cloned().collect())
+    }
+
+    async fn get(&mut self, info: &SnapshotInfo) -> Result<Snapshot, CustomError> {
+        match self.snapshots.get(info) {
+            Some(s) => open_snapshot(s),
+            None => Err(CustomError::from_string(format!("snapshot {} not found", info.id()))),
+        }
+    }
+}
+
+/// Every order book snapshot published, oldest first.
+#[derive(Debug, Default)]
+pub struct MemoryBookPublisher {
+    pub books: Vec<L3Snapshot>,
+}
+
+impl BookPublisher for MemoryBookPublisher {
+    async fn publish(&mut self, book: &L3Snapshot) -> Result<(), CustomError> {
+        self.books.push(book.clone());
+        Ok(())
+    }
+}
+
+/// Every ticker and candle update published, oldest first.
+#[derive(Debug, Default)]
+pub struct MemoryMarketDataPublisher {
+    pub tickers: Vec<Ticker>,
+    pub candles: Vec<Candle>,
+}
+
+impl MarketDataPublisher for MemoryMarketDataPublisher {
+    async fn publish(&mut self, ticker: &Ticker, candles: &[Candle]) -> Result<(), CustomError> {
+        self.tickers.push(ticker.clone());
+        self.candles.extend(candles.iter().cloned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::join;
+    use tokio::time::Duration;
+
+    use crate::matching::engine::{Engine, Snapshot};
+    use crate::matching::market_data::{MarketDataLogSink, CANDLE_GRANULARITIES};
+    use crate::matching::memory_store::{
+        log_seq, MemoryBookPublisher, MemoryLogSink, MemoryMarketDataPublisher, MemoryOrderSource,
+        MemorySnapshotStore,
+    };
+    use crate::matching::order_book::OrderBook;
+    use crate::matching::store::SnapshotStore;
+    use crate::models::models::{Order, Product};
+    use crate::models::types::{OrderStatus, Side};
+
+    fn make_order(id: u64, side: Side) -> Order {
+        Order {
+            created_at: 1695783003020967000 + id,
+            ..Order::new_test_order(id, id, side, "1", "1000")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_runs_in_memory() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore::default();
+        let mut order_source = MemoryOrderSource {
+            stop_at_end: true,
+            ..MemoryOrderSource::new_memory_order_source(vec![
+                make_order(1, Side::SideBuy),
+                make_order(2, Side::SideSell),
+            ])
+        };
+        let mut log_sink = MemoryLogSink::default();
+
+        let mut engine = Engine::new(&product, &mut snapshot_store).await;
+        // the engine stops once the source is exhausted and every log is stored
+        engine.start(&mut snapshot_store, &mut order_source, &mut log_sink).await;
+
+        let types: Vec<String> = log_sink
+            .logs
+            .iter()
+            .map(|s| {
+                let log: serde_json::Value = serde_json::from_str(s).unwrap();
+                log["base"]["type"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(types, vec!["open", "match", "done", "done"]);
+        assert_eq!(log_sink.order_offset, 1);
+    }
+
+    #[tokio::test]
+    async fn test_orders_without_logs_are_committed() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut cancel = make_order(9, Side::SideBuy);
+        cancel.status = OrderStatus::OrderStatusCancelling;
+        let mut snapshot_store = MemorySnapshotStore::default();
+        let mut order_source = MemoryOrderSource {
+            stop_at_end: true,
+            ..MemoryOrderSource::new_memory_order_source(vec![make_order(1, Side::SideBuy), cancel])
+        };
+        let mut log_sink = MemoryLogSink::default();
+
+        let mut engine = Engine::new(&product, &mut snapshot_store).await;
+        engine.start(&mut snapshot_store, &mut order_source, &mut log_sink).await;
+
+        // the cancel of an unknown order has no logs, its offset is committed anyway
+        assert_eq!(log_sink.logs.len(), 1);
+        assert_eq!(log_sink.order_offset, 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart_skips_stored_logs() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore::default();
+        let mut order_source = MemoryOrderSource {
+            stop_at_end: true,
+            ..MemoryOrderSource::new_memory_order_source(vec![
+                make_order(1, Side::SideBuy),
+                make_order(2, Side::SideSell),
+            ])
+        };
+        // a crash after the first two logs were stored, before any snapshot
+        let mut log_sink = MemoryLogSink::default();
+        let mut engine = Engine::new(&product, &mut snapshot_store).await;
+        engine.start(&mut snapshot_store, &mut order_source, &mut log_sink).await;
+        log_sink.logs.truncate(2);
+
+        let mut engine = Engine::new(&product, &mut snapshot_store).await;
+        engine.start(&mut snapshot_store, &mut order_source, &mut log_sink).await;
+
+        let seqs: Vec<u64> = log_sink.logs.iter().map(|s| log_seq(s)).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_sealed_snapshot_checksum_covers_body() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore::default();
+        let mut book = OrderBook::new_order_book(&product);
+        for order_offset in 1..=2u64 {
+            book.apply_order(&make_order(order_offset, Side::SideBuy));
+            let snapshot = Snapshot {
+                order_book_snapshot: Some(book.snapshot()),
+                order_book_delta: None,
+                base: None,
+                order_offset,
+            };
+            snapshot_store.store(&snapshot).await.unwrap();
+        }
+
+        // the checksum sits on its own line in front of the plain JSON body
+        let latest = snapshot_store.list().await.unwrap()[0].clone();
+        let sealed = snapshot_store.snapshots[&latest].clone();
+        let (header, body) = sealed.split_once('\n').unwrap();
+        assert_eq!(header, format!("{:08x}", crc32fast::hash(body.as_bytes())));
+        assert_eq!(serde_json::from_str::<Snapshot>(body).unwrap().order_offset, 2);
+
+        // a damaged body fails its checksum and the previous snapshot is restored
+        let damaged = sealed.replacen("\"order_offset\":2", "\"order_offset\":3", 1);
+        assert_ne!(damaged, sealed);
+        snapshot_store.snapshots.insert(latest, damaged);
+        let engine = Engine::new(&product, &mut snapshot_store).await;
+        assert_eq!(engine.order_offset, 1);
+    }
+
+    #[tokio::test]
+    async fn test_engine_publishes_book() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore::default();
+        let mut order_source = MemoryOrderSource {
+            stop_at_end: true,
+            ..MemoryOrderSource::new_memory_order_source(vec![
+                make_order(1, Side::SideBuy),
+                make_order(2, Side::SideBuy),
+            ])
+        };
+        let mut log_sink = MemoryLogSink::default();
+        let mut book_publisher = MemoryBookPublisher::default();
+
+        let mut engine = Engine::new(&product, &mut snapshot_store).await;
+        let book_rx = engine.publish_book(Duration::from_millis(50));
+        join!(
+            engine.start(&mut snapshot_store, &mut order_source, &mut log_sink),
+            Engine::run_book_publisher(&product.id, book_rx, &mut book_publisher),
+        );
+
+        // the final book is published on stop, a consumer continues from the log after it
+        let book = book_publisher.books.last().unwrap();
+        assert_eq!(book.log_seq, 2);
+        let bids: Vec<u64> = book.bids.iter().map(|o| o.order_id).collect();
+        assert_eq!(bids, vec![1, 2]);
+        assert!(book.asks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_engine_publishes_market_data() {
+        let product = Product {
+            id: "BTC-USD".to_string(),
+            base_scale: 4,
+            quote_scale: 2,
+            ..Default::default()
+        };
+        let mut snapshot_store = MemorySnapshotStore::default();
+        let mut order_source = MemoryOrderSource {
+            stop_at_end: true,
+            ..MemoryOrderSource::new_memory_order_source(vec![
+                make_order(1, Side::SideBuy),
+                make_order(2, Side::SideSell),
+            ])
+        };
+        let mut log_sink = MarketDataLogSink::new_market_data_log_sink(
+            &product.id,
+            MemoryLogSink::default(),
+            MemoryMarketDataPublisher::default(),
+        );
+
+        let mut engine = Engine::new(&product, &mut snapshot_store).await;
+        engine.start(&mut snapshot_store, &mut order_source, &mut log_sink).await;
+
+        // the logs still reach the inner sink, the trade is folded once
+        assert_eq!(log_sink.log_sink.logs.len(), 4);
+        let ticker = log_sink.publisher.tickers.last().unwrap();
+        assert_eq!((ticker.sequence, ticker.trade_seq), (2, 1));
+        assert!(matches!(ticker.side, Side::SideSell));
+        assert_eq!(log_sink.publisher.candles.len(), CANDLE_GRANULARITIES.len());
+        assert!(log_sink.publisher.candles.iter().all(|c| c.trade_count == 1));
+    }
+}
@@ -1,8 +1,20 @@
+pub mod auction;
+pub mod codec;
 pub mod depth;
 pub mod engine;
+pub mod expiry_index;
+pub mod file_store;
+pub mod kafka_book;
 pub mod kafka_log;
+pub mod kafka_market_data;
 pub mod kafka_order;
+pub mod ledger;
 pub mod log;
+pub mod market_data;
+pub mod memory_store;
 pub mod order_book;
 pub mod ordering;
 pub mod redis_snapshot;
+pub mod replay;
+pub mod stop_book;
+pub mod store;
@@ -1,20 +1,30 @@
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::ops::{Div, Mul, Sub};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use log::info;
 use rust_decimal::prelude::Zero;
 use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 
-use crate::matching::depth::{AskDepth, BidDepth};
-use crate::matching::log::{new_done_log, new_match_log, new_open_log, LogTrait};
-use crate::matching::ordering::{PriceOrderIdKeyAsc, PriceOrderIdKeyDesc};
+use crate::matching::auction::{find_equilibrium, Equilibrium};
+use crate::matching::depth::{AskDepth, BidDepth, PriceLevel};
+use crate::matching::expiry_index::ExpiryIndex;
+use crate::matching::ledger::{Asset, Ledger, LedgerDelta, LedgerSnapshot};
+use crate::matching::log::{
+    new_auction_log, new_change_log, new_done_log, new_l2update_log, new_match_log, new_open_log,
+    new_received_log, new_status_log, new_transfer_log, new_trigger_log, LogTrait,
+};
+use crate::matching::stop_book::StopBook;
 use crate::models::models::{Order, Product};
 use crate::models::types::*;
-use crate::utils::time_window::{TimeWindow, TimeWindowSnapshot, SNOWFLAKE_EPOCH};
+use crate::utils::time_window::{
+    extract_timestamp_from_id, TimeWindow, TimeWindowSnapshot, SNOWFLAKE_EPOCH,
+};
+
+/// How far the `created_at` of a message may run ahead of the time in its snowflake id
+/// before it stops moving the order stream clock, in nanoseconds.
+pub const MAX_CLOCK_SKEW: u64 = 60 * 1_000_000_000;
 
 /// Normalize price to the specified scale using rounding (matches Go version's Round behavior)
 fn normalize_price(price: Decimal, scale: u32) -> Decimal {
@@ -26,16 +36,6 @@ fn normalize_size(size: Decimal, scale: u32) -> Decimal {
     size.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero)
 }
 
-/// Get current time in milliseconds relative to Snowflake epoch.
-/// This is used for time-based deduplication window.
-/// Returns: (Unix timestamp ms) - SNOWFLAKE_EPOCH
-fn current_time_since_snowflake_epoch() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_millis() as i64 - SNOWFLAKE_EPOCH)
-        .unwrap_or(0)
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BookOrder {
     pub order_id: u64,
@@ -43,6 +43,17 @@ pub struct BookOrder {
     pub size: Decimal,
     pub funds: Decimal,
     pub price: Decimal,
+    #[serde(default)]
+    pub stop_price: Decimal,
+    // size of each visible slice of an iceberg order, zero for a regular order
+    #[serde(default)]
+    pub display_size: Decimal,
+    // iceberg reserve not shown on the book yet
+    #[serde(default)]
+    pub hidden_size: Decimal,
+    // time priority within a price level, see Depth::add
+    #[serde(default)]
+    pub priority: u64,
     #[serde(serialize_with = "serialize_side")]
     #[serde(deserialize_with = "deserialize_side")]
     pub side: Side,
@@ -52,6 +63,20 @@ pub struct BookOrder {
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub time_in_force: TimeInForceType,
+    // when a GTT or GTD order leaves the book in timestamp_nanos, zero if it does not expire
+    #[serde(default)]
+    pub expire_time: u64,
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_self_trade_prevention")]
+    #[serde(deserialize_with = "deserialize_self_trade_prevention")]
+    pub stp: SelfTradePrevention,
+    // fee overrides of the order, the fees of the product apply if not set
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maker_fee_bps: Option<Decimal>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taker_fee_bps: Option<Decimal>,
 }
 
 impl Default for BookOrder {
@@ -62,9 +87,17 @@ impl Default for BookOrder {
             size: Default::default(),
             funds: Default::default(),
             price: Default::default(),
+            stop_price: Default::default(),
+            display_size: Default::default(),
+            hidden_size: Default::default(),
+            priority: 0,
             side: Side::SideBuy,
             r#type: OrderType::OrderTypeLimit,
             time_in_force: TimeInForceType::GoodTillCanceled,
+            expire_time: 0,
+            stp: SelfTradePrevention::SelfTradePreventionNone,
+            maker_fee_bps: None,
+            taker_fee_bps: None,
         }
     }
 }
@@ -77,13 +110,29 @@ impl BookOrder {
             size: order.size,
             funds: order.funds,
             price: order.price,
+            stop_price: order.stop_price,
+            display_size: order.display_size,
+            hidden_size: Decimal::ZERO,
+            priority: order.id,
             side: order.side.clone(),
             r#type: order.r#type.clone(),
             time_in_force: order.time_in_force.clone(),
+            expire_time: order.time_in_force.expiry(order.expire_time),
+            stp: order.stp.clone(),
+            maker_fee_bps: order.maker_fee_bps,
+            taker_fee_bps: order.taker_fee_bps,
         }
     }
 }
 
+/// What the matching loop should do after a self-trade prevention check.
+enum SelfTradeAction {
+    /// The maker was cancelled (or decremented), move on to the next maker
+    SkipMaker,
+    /// The taker must not trade any further
+    CancelTaker,
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct OrderBookSnapshot {
     pub product_id: String,
@@ -94,35 +143,138 @@ pub struct OrderBookSnapshot {
     pub time_window: TimeWindowSnapshot,
     #[serde(default)]
     pub last_trade_price: Decimal,
+    #[serde(default)]
+    pub stop_orders: Vec<BookOrder>,
+    #[serde(default)]
+    pub halted_until: u64,
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_market_state")]
+    #[serde(deserialize_with = "deserialize_market_state")]
+    pub market_state: MarketState,
+    #[serde(default)]
+    pub order_time: u64,
+    // balances and holds of the risk ledger, empty without one
+    #[serde(default)]
+    pub ledger: LedgerSnapshot,
+}
+
+/// The orders that changed since the previous snapshot, with the rest of the book state in
+/// full. A chain of deltas on top of an `OrderBookSnapshot` gives the book at the last one.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+pub struct OrderBookDelta {
+    pub product_id: String,
+    // orders added to or changed on the book
+    pub orders: Vec<BookOrder>,
+    // orders added to the stop book
+    #[serde(default)]
+    pub stop_orders: Vec<BookOrder>,
+    // orders that left both the book and the stop book
+    #[serde(default)]
+    pub removed: Vec<u64>,
+    pub trade_seq: u64,
+    pub log_seq: u64,
+    #[serde(default)]
+    pub time_window: TimeWindowSnapshot,
+    #[serde(default)]
+    pub last_trade_price: Decimal,
+    #[serde(default)]
+    pub halted_until: u64,
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_market_state")]
+    #[serde(deserialize_with = "deserialize_market_state")]
+    pub market_state: MarketState,
+    #[serde(default)]
+    pub order_time: u64,
+    #[serde(default)]
+    pub ledger: LedgerDelta,
+}
+
+impl OrderBookSnapshot {
+    /// Bring the snapshot forward to the state recorded by `delta`.
+    pub fn apply_delta(&mut self, delta: &OrderBookDelta) {
+        let changed: HashSet<u64> = delta
+            .orders
+            .iter()
+            .chain(delta.stop_orders.iter())
+            .map(|o| o.order_id)
+            .chain(delta.removed.iter().copied())
+            .collect();
+        self.orders.retain(|o| !changed.contains(&o.order_id));
+        self.stop_orders.retain(|o| !changed.contains(&o.order_id));
+        self.orders.extend(delta.orders.iter().cloned());
+        self.stop_orders.extend(delta.stop_orders.iter().cloned());
+
+        self.trade_seq = delta.trade_seq;
+        self.log_seq = delta.log_seq;
+        self.time_window = delta.time_window.clone();
+        self.last_trade_price = delta.last_trade_price;
+        self.halted_until = delta.halted_until;
+        self.market_state = delta.market_state.clone();
+        self.order_time = delta.order_time;
+        self.ledger.apply_delta(&delta.ledger);
+    }
+}
+
+/// One resting order as market data sees it, its visible size only.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct L3Order {
+    pub order_id: u64,
+    pub price: Decimal,
+    pub size: Decimal,
+}
+
+/// Every resting order in queue order, best price and earliest first on each side. It
+/// includes the logs up to `log_seq`, so a consumer continues with the log after it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct L3Snapshot {
+    pub product_id: String,
+    pub log_seq: u64,
+    pub time: u64,
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
 }
 
 pub struct OrderBook {
     pub product: Product,
     pub ask_depths: AskDepth,
     pub bid_depths: BidDepth,
+    pub stop_book: StopBook,
+    pub expiry_index: ExpiryIndex,
     pub trade_seq: u64,
     pub log_seq: u64,
     time_window: TimeWindow,
     last_trade_price: Decimal,
+    // order time a circuit breaker halt ends at, zero when trading
+    halted_until: u64,
+    // state set by control messages, a circuit breaker halt takes precedence over it
+    market_state: MarketState,
+    // last indicative uncross announced during the call phase of an auction
+    indicative: Option<Equilibrium>,
+    // latest created_at seen on the order stream, the only clock the book reads so that a
+    // replay from a snapshot produces the same logs
+    order_time: u64,
+    // balances of the users, only kept when the product has a risk ledger
+    pub ledger: Ledger,
 }
 
 impl OrderBook {
     pub fn new_order_book(product: &Product) -> Self {
         OrderBook {
             product: product.clone(),
-            ask_depths: AskDepth {
-                orders: HashMap::<u64, BookOrder>::new(),
-                queue: BTreeMap::<PriceOrderIdKeyAsc, u64>::new(),
-            },
-            bid_depths: BidDepth {
-                orders: HashMap::<u64, BookOrder>::new(),
-                queue: BTreeMap::<PriceOrderIdKeyDesc, u64>::new(),
-            },
+            ask_depths: AskDepth::default(),
+            bid_depths: BidDepth::default(),
+            stop_book: StopBook::default(),
+            expiry_index: ExpiryIndex::default(),
 
             trade_seq: 0,
             log_seq: 0,
             time_window: TimeWindow::new(),
             last_trade_price: Decimal::ZERO,
+            halted_until: 0,
+            market_state: MarketState::MarketStateContinuous,
+            indicative: None,
+            order_time: 0,
+            ledger: Ledger::default(),
         }
     }
 
@@ -178,88 +330,134 @@ impl OrderBook {
             }
         }
 
-        match taker_order.side {
-            Side::SideBuy => {
-                for v in self.ask_depths.queue.values() {
-                    let maker_order = self.ask_depths.orders.get(v).unwrap();
-
-                    // check whether there is price crossing between the taker and the maker
-                    if Ordering::Less == Decimal::cmp(&taker_order.price, &maker_order.price) {
-                        break;
-                    }
-
-                    match taker_order.r#type {
-                        OrderType::OrderTypeLimit => {
-                            if taker_order.size.is_zero() {
-                                break;
-                            }
-
-                            // Take the minimum size of taker and maker as trade size
-                            let size = Decimal::min(taker_order.size, maker_order.size);
-
-                            // adjust the size of taker order
-                            taker_order.size = taker_order.size.sub(size);
-                        }
-                        OrderType::OrderTypeMarket => {
-                            if taker_order.funds.is_zero() {
-                                break;
-                            }
+        let (by_size, by_funds) = Self::taker_bounds(&taker_order);
+        if !by_size && !by_funds {
+            return false;
+        }
 
-                            // calculate the size of taker at current price
-                            let taker_size = taker_order
-                                .funds
-                                .div(maker_order.price)
-                                .trunc_with_scale(self.product.base_scale as u32);
-                            if taker_size.is_zero() {
-                                break;
-                            }
+        let makers: Vec<&BookOrder> = match taker_order.side {
+            Side::SideBuy => self
+                .ask_depths
+                .queue
+                .values()
+                .map(|v| &self.ask_depths.orders[v])
+                .collect(),
+            Side::SideSell => self
+                .bid_depths
+                .queue
+                .values()
+                .map(|v| &self.bid_depths.orders[v])
+                .collect(),
+        };
 
-                            // Take the minimum size of taker and maker as trade size
-                            let size = Decimal::min(taker_size, maker_order.size);
-                            let funds = size.mul(maker_order.price);
+        for maker_order in makers {
+            // check whether there is price crossing between the taker and the maker
+            let crossed = match taker_order.side {
+                Side::SideBuy => Ordering::Less != Decimal::cmp(&taker_order.price, &maker_order.price),
+                Side::SideSell => Ordering::Greater != Decimal::cmp(&taker_order.price, &maker_order.price),
+            };
+            if !crossed {
+                break;
+            }
 
-                            // adjust the funds of taker order
-                            taker_order.funds = taker_order.funds.sub(funds);
-                        }
-                    }
+            if Self::is_self_trade(&taker_order, maker_order) {
+                // cancel-oldest removes the maker and keeps matching, every other mode
+                // stops the taker before it can fill any further
+                match taker_order.stp {
+                    SelfTradePrevention::SelfTradePreventionCancelOldest => continue,
+                    _ => break,
                 }
             }
-            Side::SideSell => {
-                for v in self.bid_depths.queue.values() {
-                    let maker_order = self.bid_depths.orders.get(v).unwrap();
 
-                    // check whether there is price crossing between the taker and the maker
-                    if Ordering::Greater == Decimal::cmp(&taker_order.price, &maker_order.price) {
-                        break;
-                    }
+            // the hidden reserve of an iceberg maker can be filled too
+            let size = self.trade_size(
+                &taker_order,
+                by_size,
+                by_funds,
+                &maker_order.price,
+                &(maker_order.size + maker_order.hidden_size),
+            );
+            if size.is_zero() {
+                // taker cannot afford any more at this price, its budget is used up
+                return true;
+            }
 
-                    if taker_order.size.is_zero() {
-                        break;
-                    }
+            Self::take_from_taker(&mut taker_order, by_size, by_funds, &size, &maker_order.price);
+        }
 
-                    // Take the minimum size of taker and maker as trade size
-                    let size = Decimal::min(taker_order.size, maker_order.size);
+        Self::is_taker_exhausted(&taker_order, by_size, by_funds)
+    }
 
-                    // adjust the size of taker order
-                    taker_order.size = taker_order.size.sub(size);
-                }
+    /// Which budgets bound a taker: a limit order is bounded by its size, a market order by
+    /// its size and/or its funds, whichever of them is set.
+    fn taker_bounds(taker_order: &BookOrder) -> (bool, bool) {
+        match taker_order.r#type {
+            OrderType::OrderTypeLimit | OrderType::OrderTypeStopLimit => (true, false),
+            OrderType::OrderTypeMarket | OrderType::OrderTypeStop => {
+                (!taker_order.size.is_zero(), !taker_order.funds.is_zero())
             }
         }
+    }
 
-        if let OrderType::OrderTypeLimit = taker_order.r#type
-            && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero()) {
-                return false;
-            }
+    /// Size the taker can trade against `maker_size` at `price` within its remaining budget.
+    fn trade_size(
+        &self,
+        taker_order: &BookOrder,
+        by_size: bool,
+        by_funds: bool,
+        price: &Decimal,
+        maker_size: &Decimal,
+    ) -> Decimal {
+        if !by_size && !by_funds {
+            return Decimal::ZERO;
+        }
+
+        let mut size = *maker_size;
+        if by_size {
+            size = Decimal::min(size, taker_order.size);
+        }
+        if by_funds {
+            // calculate the size of taker at current price
+            let funds_size = taker_order
+                .funds
+                .div(price)
+                .trunc_with_scale(self.product.base_scale as u32);
+            size = Decimal::min(size, funds_size);
+        }
+        size
+    }
+
+    fn take_from_taker(
+        taker_order: &mut BookOrder,
+        by_size: bool,
+        by_funds: bool,
+        size: &Decimal,
+        price: &Decimal,
+    ) {
+        if by_size {
+            taker_order.size = taker_order.size.sub(size);
+        }
+        if by_funds {
+            taker_order.funds = taker_order.funds.sub(size.mul(price));
+        }
+    }
 
-        true
+    fn is_taker_exhausted(taker_order: &BookOrder, by_size: bool, by_funds: bool) -> bool {
+        (by_size && taker_order.size.is_zero()) || (by_funds && taker_order.funds.is_zero())
     }
 
+    /// Put a new order on the book, matching it first, and settle its trades.
     pub fn apply_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let logs = self.place_order(order);
+        self.settled(logs)
+    }
+
+    fn place_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
         let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
 
         // Prevent orders from being submitted repeatedly to the matching engine
         // Get current time in milliseconds since snowflake epoch
-        let now_time = current_time_since_snowflake_epoch();
+        let now_time = self.time_since_snowflake_epoch();
 
         if let Err(e) = self.time_window.put(order.id, now_time) {
             // Check if this is an "expired" order (window has moved past this order's time)
@@ -269,8 +467,9 @@ impl OrderBook {
             // so we should process it anyway (it will generate an empty result if already completed).
             let found_in_buy = self.bid_depths.orders.contains_key(&order.id);
             let found_in_sell = self.ask_depths.orders.contains_key(&order.id);
+            let found_in_stop = self.stop_book.orders.contains_key(&order.id);
 
-            if found_in_buy || found_in_sell {
+            if found_in_buy || found_in_sell || found_in_stop {
                 // Order is already in the book, this is a duplicate - skip it
                 info!("expired order {} already in order book, skipping", order.id);
                 return logs;
@@ -280,11 +479,156 @@ impl OrderBook {
             info!("expired order {} not in order book, processing anyway", order.id);
         }
 
-        let mut taker_order = BookOrder::new_book_order(order);
+        let taker_order = BookOrder::new_book_order(order);
+
+        // an order that expired before it got here never reaches the book
+        if taker_order.expire_time != 0 && taker_order.expire_time <= self.order_time {
+            logs.push(Box::new(new_done_log(
+                self.next_log_seq(),
+                self.order_time,
+                &self.product.id,
+                &taker_order,
+                &taker_order.size,
+                &DONE_REASON_EXPIRED,
+            )));
+            return logs;
+        }
+
+        // the order holds what it may pay until it is done, see settled
+        if self.product.risk_ledger {
+            let amount = self.required_hold(&taker_order, &taker_order.price, &taker_order.size);
+            if let Err(reason) = self.ledger.set_hold(
+                taker_order.order_id,
+                taker_order.user_id,
+                &taker_order.side,
+                &amount,
+            ) {
+                let remaining_size = taker_order.size;
+                self.push_reject_log(&taker_order, &remaining_size, &reason, &mut logs);
+                return logs;
+            }
+        }
+
+        if taker_order.r#type.is_stop() {
+            // Hold the order in the trigger book until the last trade price crosses it
+            if !StopBook::is_triggered(
+                &taker_order.side,
+                &taker_order.stop_price,
+                &self.last_trade_price,
+            ) {
+                self.stop_book.add(&taker_order);
+                logs.push(Box::new(new_received_log(
+                    self.next_log_seq(),
+                    self.order_time,
+                    &self.product.id,
+                    &taker_order,
+                )));
+                return logs;
+            }
+
+            self.trigger_order(taker_order, &mut logs);
+        } else {
+            self.match_order(taker_order, false, &mut logs);
+        }
+
+        self.trigger_stop_orders(&mut logs);
+
+        logs
+    }
+
+    /// Release every stop order triggered by the last trade price. Triggered orders can
+    /// trade and move the price again, so keep going until nothing else is triggered.
+    fn trigger_stop_orders(&mut self, logs: &mut Vec<Box<dyn LogTrait>>) {
+        loop {
+            let triggered = self.stop_book.take_triggered(&self.last_trade_price);
+            if triggered.is_empty() {
+                break;
+            }
+            for order in triggered {
+                // a triggered order may trip the circuit breaker, the rest wait for the resume
+                if self.is_halted() {
+                    self.stop_book.add(&order);
+                    continue;
+                }
+                self.trigger_order(order, logs);
+            }
+            if self.is_halted() {
+                break;
+            }
+        }
+    }
+
+    /// Turn a stop order into its market or limit counterpart and match it. A triggered
+    /// IOC order does not rest on the book.
+    fn trigger_order(&mut self, mut order: BookOrder, logs: &mut Vec<Box<dyn LogTrait>>) {
+        logs.push(Box::new(new_trigger_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &order,
+            &self.last_trade_price,
+        )));
 
-        // Normalize price and size to product scales
-        taker_order.price = normalize_price(taker_order.price, self.product.quote_scale as u32);
-        taker_order.size = normalize_size(taker_order.size, self.product.base_scale as u32);
+        order.r#type = order.r#type.triggered();
+        // a stop-limit is banded against the trade that triggered it
+        if let OrderType::OrderTypeLimit = order.r#type
+            && self.is_outside_price_band(&order.price)
+        {
+            let remaining_size = order.size;
+            self.push_reject_log(&order, &remaining_size, &RejectReason::RejectReasonPriceBand, logs);
+            return;
+        }
+        let (order_id, side, time_in_force) =
+            (order.order_id, order.side.clone(), order.time_in_force.clone());
+        self.match_order(order, false, logs);
+
+        if let TimeInForceType::ImmediateOrCancel = time_in_force {
+            let o = match side {
+                Side::SideBuy => self.bid_depths.remove(order_id),
+                Side::SideSell => self.ask_depths.remove(order_id),
+            };
+            if let Some(o) = o {
+                logs.push(Box::new(new_done_log(
+                    self.next_log_seq(),
+                    self.order_time,
+                    &self.product.id,
+                    &o,
+                    &(o.size + o.hidden_size),
+                    &DONE_REASON_CANCELLED,
+                )));
+            }
+        }
+    }
+
+    /// Match a taker order against the book, then rest or finish it. An amended order is
+    /// `is_open` already, so resting it again is not announced with another open log.
+    fn match_order(
+        &mut self,
+        mut taker_order: BookOrder,
+        is_open: bool,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        // during the call phase of an auction orders only rest, they trade at the uncross
+        if self.market_state() == MarketState::MarketStateAuction
+            && let OrderType::OrderTypeLimit = taker_order.r#type
+        {
+            self.rest_order(taker_order, is_open, logs);
+            return;
+        }
+
+        if let OrderType::OrderTypeMarket = taker_order.r#type
+            && let Some(sweep_price) = self.breaching_sweep_price(&taker_order)
+        {
+            self.halt(&sweep_price, logs);
+            let remaining_size = taker_order.size;
+            self.push_reject_log(
+                &taker_order,
+                &remaining_size,
+                &RejectReason::RejectReasonCircuitBreaker,
+                logs,
+            );
+            return;
+        }
 
         // If it's a Market-Buy order, set price to infinite high, and if it's market-sell,
         // set price to zero, which ensures that prices will cross.
@@ -295,184 +639,224 @@ impl OrderBook {
             }
         }
 
+        let (by_size, by_funds) = Self::taker_bounds(&taker_order);
+
+        // set when self-trade prevention cancels the rest of the taker
+        let mut taker_stp_cancelled = false;
+        // set when a buy taker cannot pay for its next fill
+        let mut taker_unfunded = false;
+
         match taker_order.side {
             Side::SideBuy => {
-                // Collect order IDs to match first to avoid borrow issues
-                let order_ids: Vec<u64> = self.ask_depths.queue.values().copied().collect();
-
-                for order_id in order_ids {
-                    let maker_order = match self.ask_depths.orders.get(&order_id) {
-                        Some(o) => o.clone(),
-                        None => continue,
-                    };
+                // Always match against the head of the queue, an iceberg maker that shows a
+                // new slice moves to the back of its price level
+                while let Some((_, order_id)) = self.ask_depths.queue.first_key_value() {
+                    let maker_order = self.ask_depths.orders[order_id].clone();
 
                     // check whether there is price crossing between the taker and the maker
                     if Ordering::Less == Decimal::cmp(&taker_order.price, &maker_order.price) {
                         break;
                     }
 
-                    let mut size = Decimal::default();
+                    // Take the minimum size of taker and maker as trade size
+                    let size = self.trade_size(
+                        &taker_order,
+                        by_size,
+                        by_funds,
+                        &maker_order.price,
+                        &maker_order.size,
+                    );
+                    if size.is_zero() {
+                        break;
+                    }
 
-                    match taker_order.r#type {
-                        OrderType::OrderTypeLimit => {
-                            if taker_order.size.is_zero() {
+                    if Self::is_self_trade(&taker_order, &maker_order) {
+                        match self.prevent_self_trade(&mut taker_order, &maker_order, logs) {
+                            SelfTradeAction::SkipMaker => continue,
+                            SelfTradeAction::CancelTaker => {
+                                taker_stp_cancelled = true;
                                 break;
                             }
-
-                            // Take the minimum size of taker and maker as trade size
-                            size = Decimal::min(taker_order.size, maker_order.size);
-
-                            // adjust the size of taker order
-                            taker_order.size = taker_order.size.sub(size);
                         }
-                        OrderType::OrderTypeMarket => {
-                            if taker_order.funds.is_zero() {
-                                break;
-                            }
-
-                            // calculate the size of taker at current price
-                            let taker_size = taker_order
-                                .funds
-                                .div(maker_order.price)
-                                .trunc_with_scale(self.product.base_scale as u32);
-
-                            if taker_size.is_zero() {
-                                break;
-                            }
-
-                            // Take the minimum size of taker and maker as trade size
-                            size = Decimal::min(taker_size, maker_order.size);
-                            let funds = size.mul(maker_order.price);
+                    }
 
-                            // adjust the funds of taker order
-                            taker_order.funds = taker_order.funds.sub(funds);
-                        }
+                    // a buy taker that cannot pay its next fill stops here
+                    if !self.can_pay(&taker_order, &maker_order, &maker_order.price, &size) {
+                        taker_unfunded = true;
+                        break;
                     }
 
+                    // adjust the size/funds of taker order
+                    Self::take_from_taker(
+                        &mut taker_order,
+                        by_size,
+                        by_funds,
+                        &size,
+                        &maker_order.price,
+                    );
+
                     // adjust the size of maker order
                     if let Err(e) = self.ask_depths.decr_size(maker_order.order_id, &size) {
                         panic!("{}", e);
                     }
+                    let remaining = self.ask_depths.orders.get(&maker_order.order_id).cloned();
                     let mut maker_order = maker_order;
                     maker_order.size = maker_order.size.sub(size);
 
                     // matched, new match log
-                    let (log_seq, trade_seq) = (self.next_log_seq(), self.next_trade_seq());
-                    logs.push(Box::new(new_match_log(
-                        log_seq,
-                        &self.product.id,
-                        trade_seq,
-                        &taker_order,
-                        &maker_order,
-                        &maker_order.price,
-                        &size,
-                    )));
+                    self.push_match_log(&taker_order, &maker_order, &maker_order.price, &size, logs);
 
                     self.last_trade_price = maker_order.price;
 
-                    // maker is filled
-                    if maker_order.size.is_zero() {
-                        logs.push(Box::new(new_done_log(
-                            self.next_log_seq(),
-                            &self.product.id,
-                            &maker_order,
-                            &maker_order.size,
-                            &DONE_REASON_FILLED,
-                        )));
+                    match remaining {
+                        // maker is filled
+                        None => {
+                            self.expiry_index.remove(&maker_order);
+                            logs.push(Box::new(new_done_log(
+                                self.next_log_seq(),
+                                self.order_time,
+                                &self.product.id,
+                                &maker_order,
+                                &maker_order.size,
+                                &DONE_REASON_FILLED,
+                            )));
+                        }
+                        // iceberg maker showed its next slice
+                        Some(o) if o.priority != maker_order.priority => {
+                            logs.push(Box::new(new_open_log(
+                                self.next_log_seq(),
+                                self.order_time,
+                                &self.product.id,
+                                &o,
+                            )));
+                        }
+                        Some(_) => {}
                     }
 
                     // check if taker is exhausted after this match
-                    match taker_order.r#type {
-                        OrderType::OrderTypeLimit => {
-                            if taker_order.size.is_zero() {
-                                break;
-                            }
-                        }
-                        OrderType::OrderTypeMarket => {
-                            if taker_order.funds.is_zero() {
-                                break;
-                            }
-                        }
+                    if Self::is_taker_exhausted(&taker_order, by_size, by_funds) {
+                        break;
                     }
                 }
             }
             Side::SideSell => {
-                // Collect order IDs to match first to avoid borrow issues
-                let order_ids: Vec<u64> = self.bid_depths.queue.values().copied().collect();
-
-                for order_id in order_ids {
-                    let maker_order = match self.bid_depths.orders.get(&order_id) {
-                        Some(o) => o.clone(),
-                        None => continue,
-                    };
+                // Always match against the head of the queue, an iceberg maker that shows a
+                // new slice moves to the back of its price level
+                while let Some((_, order_id)) = self.bid_depths.queue.first_key_value() {
+                    let maker_order = self.bid_depths.orders[order_id].clone();
 
                     // check whether there is price crossing between the taker and the maker
                     if Ordering::Greater == Decimal::cmp(&taker_order.price, &maker_order.price) {
                         break;
                     }
 
-                    if taker_order.size.is_zero() {
+                    // Take the minimum size of taker and maker as trade size
+                    let size = self.trade_size(
+                        &taker_order,
+                        by_size,
+                        by_funds,
+                        &maker_order.price,
+                        &maker_order.size,
+                    );
+                    if size.is_zero() {
                         break;
                     }
 
-                    // Take the minimum size of taker and maker as trade size
-                    let size = Decimal::min(taker_order.size, maker_order.size);
+                    if Self::is_self_trade(&taker_order, &maker_order) {
+                        match self.prevent_self_trade(&mut taker_order, &maker_order, logs) {
+                            SelfTradeAction::SkipMaker => continue,
+                            SelfTradeAction::CancelTaker => {
+                                taker_stp_cancelled = true;
+                                break;
+                            }
+                        }
+                    }
 
-                    // adjust the size of taker order
-                    taker_order.size = taker_order.size.sub(size);
+                    // a buy maker that cannot pay is cancelled and the taker goes on
+                    if !self.can_pay(&taker_order, &maker_order, &maker_order.price, &size) {
+                        self.cancel_resting(&maker_order, &DONE_REASON_CANCELLED, logs);
+                        continue;
+                    }
+
+                    // adjust the size/funds of taker order
+                    Self::take_from_taker(
+                        &mut taker_order,
+                        by_size,
+                        by_funds,
+                        &size,
+                        &maker_order.price,
+                    );
 
                     // adjust the size of maker order
                     if let Err(e) = self.bid_depths.decr_size(maker_order.order_id, &size) {
                         panic!("{}", e);
                     }
+                    let remaining = self.bid_depths.orders.get(&maker_order.order_id).cloned();
                     let mut maker_order = maker_order;
                     maker_order.size = maker_order.size.sub(size);
 
                     // matched, new match log
-                    let (log_seq, trade_seq) = (self.next_log_seq(), self.next_trade_seq());
-                    logs.push(Box::new(new_match_log(
-                        log_seq,
-                        &self.product.id,
-                        trade_seq,
-                        &taker_order,
-                        &maker_order,
-                        &maker_order.price,
-                        &size,
-                    )));
+                    self.push_match_log(&taker_order, &maker_order, &maker_order.price, &size, logs);
 
                     self.last_trade_price = maker_order.price;
 
-                    // maker is filled
-                    if maker_order.size.is_zero() {
-                        logs.push(Box::new(new_done_log(
-                            self.next_log_seq(),
-                            &self.product.id,
-                            &maker_order,
-                            &maker_order.size,
-                            &DONE_REASON_FILLED,
-                        )));
+                    match remaining {
+                        // maker is filled
+                        None => {
+                            self.expiry_index.remove(&maker_order);
+                            logs.push(Box::new(new_done_log(
+                                self.next_log_seq(),
+                                self.order_time,
+                                &self.product.id,
+                                &maker_order,
+                                &maker_order.size,
+                                &DONE_REASON_FILLED,
+                            )));
+                        }
+                        // iceberg maker showed its next slice
+                        Some(o) if o.priority != maker_order.priority => {
+                            logs.push(Box::new(new_open_log(
+                                self.next_log_seq(),
+                                self.order_time,
+                                &self.product.id,
+                                &o,
+                            )));
+                        }
+                        Some(_) => {}
                     }
 
                     // check if taker is exhausted after this match
-                    if taker_order.size.is_zero() {
+                    if Self::is_taker_exhausted(&taker_order, by_size, by_funds) {
                         break;
                     }
                 }
             }
         }
 
-        if let OrderType::OrderTypeLimit = taker_order.r#type
+        if taker_stp_cancelled || taker_unfunded {
+            let mut remaining_size = taker_order.size;
+            if let OrderType::OrderTypeMarket = taker_order.r#type {
+                taker_order.price = Decimal::zero();
+                remaining_size = Decimal::zero();
+            }
+
+            let reason = if taker_stp_cancelled {
+                DONE_REASON_SELF_TRADE_PREVENTED
+            } else {
+                DONE_REASON_CANCELLED
+            };
+            logs.push(Box::new(new_done_log(
+                self.next_log_seq(),
+                self.order_time,
+                &self.product.id,
+                &taker_order,
+                &remaining_size,
+                &reason,
+            )));
+        } else if let OrderType::OrderTypeLimit = taker_order.r#type
             && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero()) {
             // If taker has an uncompleted size, put taker in orderBook
-            match taker_order.side {
-                Side::SideBuy => {
-                    self.bid_depths.add(&taker_order);
-                }
-                Side::SideSell => {
-                    self.ask_depths.add(&taker_order);
-                }
-            }
-            logs.push(Box::new(new_open_log(self.next_log_seq(), &self.product.id, &taker_order)));
+            self.rest_order(taker_order, is_open, logs);
         } else {
             let mut remaining_size = taker_order.size;
             let mut reason = DONE_REASON_FILLED;
@@ -481,88 +865,1112 @@ impl OrderBook {
                 taker_order.price = Decimal::zero();
                 remaining_size = Decimal::zero();
 
-                if let Side::SideSell = taker_order.side && Ordering::Greater == Decimal::cmp(&taker_order.size, &Decimal::zero()) {
-                    reason = DONE_REASON_CANCELLED;
-                } else if let Side::SideBuy = taker_order.side && Ordering::Greater == Decimal::cmp(&taker_order.funds, &Decimal::zero()) {
+                // a market order is filled once any of its budgets is used up, otherwise the
+                // book ran out and the rest is cancelled
+                if !Self::is_taker_exhausted(&taker_order, by_size, by_funds) {
                     reason = DONE_REASON_CANCELLED;
                 }
             }
 
             logs.push(Box::new(new_done_log(
                 self.next_log_seq(),
+                self.order_time,
                 &self.product.id,
                 &taker_order,
                 &remaining_size,
                 &reason,
             )));
         }
-
-        logs
     }
 
-    pub fn cancel_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
-        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
-
-        // Mark order as seen in time window
-        let now_time = current_time_since_snowflake_epoch();
-        let _ = self.time_window.put(order.id, now_time);
-
+    /// Put the rest of a limit order on the book.
+    fn rest_order(&mut self, mut order: BookOrder, is_open: bool, logs: &mut Vec<Box<dyn LogTrait>>) {
+        Self::show_first_slice(&mut order);
         match order.side {
             Side::SideBuy => {
-                if let Some(r) = self.bid_depths.orders.get(&order.id) {
-                    let o = r.clone();
-                    let remaining_size = o.size;
-                    match self.bid_depths.decr_size(order.id, &o.size) {
-                        Err(e) => {
-                            panic!("{}", e);
-                        }
-                        Ok(()) => {
-                            logs.push(Box::new(new_done_log(
-                                self.next_log_seq(),
-                                &self.product.id,
-                                &o,
-                                &remaining_size,
-                                &DONE_REASON_CANCELLED,
-                            )));
-                        }
+                self.bid_depths.add(&order);
+            }
+            Side::SideSell => {
+                self.ask_depths.add(&order);
+            }
+        }
+        self.expiry_index.add(&order);
+        if is_open {
+            return;
+        }
+        logs.push(Box::new(new_open_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &order,
+        )));
+    }
+
+    /// An iceberg order only shows its first slice and keeps the rest in reserve.
+    fn show_first_slice(order: &mut BookOrder) {
+        if order.display_size > Decimal::ZERO && order.size > order.display_size {
+            order.hidden_size = order.size.sub(order.display_size);
+            order.size = order.display_size;
+        }
+    }
+
+    /// Whether matching `taker_order` against `maker_order` would be a wash trade that the
+    /// taker's self-trade prevention mode asks us to avoid.
+    fn is_self_trade(taker_order: &BookOrder, maker_order: &BookOrder) -> bool {
+        taker_order.user_id == maker_order.user_id
+            && taker_order.stp != SelfTradePrevention::SelfTradePreventionNone
+    }
+
+    /// Apply the taker's self-trade prevention mode against a resting maker of the same user.
+    /// Cancelled makers get a done log with the stp reason here, a cancelled taker is
+    /// reported by the caller once matching stops.
+    fn prevent_self_trade(
+        &mut self,
+        taker_order: &mut BookOrder,
+        maker_order: &BookOrder,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) -> SelfTradeAction {
+        match taker_order.stp {
+            SelfTradePrevention::SelfTradePreventionCancelOldest => {
+                self.cancel_resting(maker_order, &DONE_REASON_SELF_TRADE_PREVENTED, logs);
+                SelfTradeAction::SkipMaker
+            }
+            SelfTradePrevention::SelfTradePreventionCancelBoth => {
+                self.cancel_resting(maker_order, &DONE_REASON_SELF_TRADE_PREVENTED, logs);
+                SelfTradeAction::CancelTaker
+            }
+            SelfTradePrevention::SelfTradePreventionDecrementAndCancel => {
+                // a market order bounded by funds only has no size to decrement
+                if let OrderType::OrderTypeMarket = taker_order.r#type
+                    && taker_order.size.is_zero()
+                {
+                    return SelfTradeAction::CancelTaker;
+                }
+
+                // cancel the smaller order and decrement the larger one by its size,
+                // if both have the same size, cancel both
+                let maker_size = maker_order.size + maker_order.hidden_size;
+                let size = Decimal::min(taker_order.size, maker_size);
+                if maker_size == size {
+                    self.cancel_resting(maker_order, &DONE_REASON_SELF_TRADE_PREVENTED, logs);
+                } else {
+                    let result = match maker_order.side {
+                        Side::SideBuy => self.bid_depths.reduce_size(maker_order.order_id, &size),
+                        Side::SideSell => self.ask_depths.reduce_size(maker_order.order_id, &size),
+                    };
+                    if let Err(e) = result {
+                        panic!("{}", e);
                     }
+                    logs.push(Box::new(new_change_log(
+                        self.next_log_seq(),
+                        self.order_time,
+                        &self.product.id,
+                        maker_order,
+                        &maker_size,
+                        &maker_size.sub(size),
+                        &maker_order.price,
+                    )));
+                }
+
+                if taker_order.size == size {
+                    SelfTradeAction::CancelTaker
+                } else {
+                    let old_size = taker_order.size;
+                    taker_order.size = taker_order.size.sub(size);
+                    logs.push(Box::new(new_change_log(
+                        self.next_log_seq(),
+                        self.order_time,
+                        &self.product.id,
+                        taker_order,
+                        &old_size,
+                        &taker_order.size,
+                        &taker_order.price,
+                    )));
+                    SelfTradeAction::SkipMaker
+                }
+            }
+            SelfTradePrevention::SelfTradePreventionCancelNewest
+            | SelfTradePrevention::SelfTradePreventionNone => SelfTradeAction::CancelTaker,
+        }
+    }
+
+    /// Take a resting order off the book and report it done for `reason`.
+    fn cancel_resting(
+        &mut self,
+        order: &BookOrder,
+        reason: &DoneReason,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        let removed = match order.side {
+            Side::SideBuy => self.bid_depths.remove(order.order_id),
+            Side::SideSell => self.ask_depths.remove(order.order_id),
+        };
+        if removed.is_none() {
+            panic!("order {} not found on book", order.order_id);
+        }
+        self.expiry_index.remove(order);
+
+        logs.push(Box::new(new_done_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            order,
+            &(order.size + order.hidden_size),
+            reason,
+        )));
+    }
+
+    /// Report a fill with a match log. With a risk ledger it is paid from the holds of both
+    /// orders right away, so the next fill is checked against what they have left.
+    fn push_match_log(
+        &mut self,
+        taker_order: &BookOrder,
+        maker_order: &BookOrder,
+        price: &Decimal,
+        size: &Decimal,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        let (log_seq, trade_seq) = (self.next_log_seq(), self.next_trade_seq());
+        let match_log = new_match_log(
+            log_seq,
+            self.order_time,
+            &self.product,
+            trade_seq,
+            taker_order,
+            maker_order,
+            price,
+            size,
+        );
+        if self.product.risk_ledger {
+            self.ledger.settle(&match_log);
+        }
+        logs.push(Box::new(match_log));
+    }
+
+    /// Whether the buy order of a fill still holds its notional and fee. Each fill is
+    /// rounded to quote_scale on its own, so the fills of an order can cost more than the
+    /// hold taken for it as a whole, and a fill past the hold is never made.
+    fn can_pay(
+        &self,
+        taker_order: &BookOrder,
+        maker_order: &BookOrder,
+        price: &Decimal,
+        size: &Decimal,
+    ) -> bool {
+        if !self.product.risk_ledger {
+            return true;
+        }
+        let product = &self.product;
+        let (buyer, fee_bps) = match maker_order.side {
+            Side::SideBuy => (maker_order, maker_order.maker_fee_bps.unwrap_or(product.maker_fee_bps)),
+            Side::SideSell => (taker_order, taker_order.taker_fee_bps.unwrap_or(product.taker_fee_bps)),
+        };
+        let cost = product.notional(price, size) + product.fee(price, size, &fee_bps);
+        cost <= self.ledger.order_hold(buyer.order_id)
+    }
+
+    /// Remove an order from the book or the trigger book and release its hold.
+    pub fn cancel_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let logs = self.remove_order(order);
+        self.settled(logs)
+    }
+
+    fn remove_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+
+        // Mark order as seen in time window
+        let now_time = self.time_since_snowflake_epoch();
+        let _ = self.time_window.put(order.id, now_time);
+
+        match order.side {
+            Side::SideBuy => {
+                // remove the whole order, including the reserve of an iceberg order
+                if let Some(o) = self.bid_depths.remove(order.id) {
+                    self.expiry_index.remove(&o);
+                    let remaining_size = o.size + o.hidden_size;
+                    logs.push(Box::new(new_done_log(
+                        self.next_log_seq(),
+                        self.order_time,
+                        &self.product.id,
+                        &o,
+                        &remaining_size,
+                        &DONE_REASON_CANCELLED,
+                    )));
+                }
+            }
+            Side::SideSell => {
+                // remove the whole order, including the reserve of an iceberg order
+                if let Some(o) = self.ask_depths.remove(order.id) {
+                    self.expiry_index.remove(&o);
+                    let remaining_size = o.size + o.hidden_size;
+                    logs.push(Box::new(new_done_log(
+                        self.next_log_seq(),
+                        self.order_time,
+                        &self.product.id,
+                        &o,
+                        &remaining_size,
+                        &DONE_REASON_CANCELLED,
+                    )));
                 }
             }
-            Side::SideSell => {
-                if let Some(r) = self.ask_depths.orders.get(&order.id) {
-                    let o = r.clone();
-                    let remaining_size = o.size;
-                    match self.ask_depths.decr_size(order.id, &o.size) {
-                        Err(e) => {
-                            panic!("{}", e);
-                        }
-                        Ok(()) => {
-                            logs.push(Box::new(new_done_log(
-                                self.next_log_seq(),
-                                &self.product.id,
-                                &o,
-                                &remaining_size,
-                                &DONE_REASON_CANCELLED,
-                            )));
-                        }
-                    }
+        };
+
+        // the order may still be waiting in the trigger book
+        if logs.is_empty()
+            && let Some(o) = self.stop_book.remove(order.id)
+        {
+            logs.push(Box::new(new_done_log(
+                self.next_log_seq(),
+                self.order_time,
+                &self.product.id,
+                &o,
+                &o.size,
+                &DONE_REASON_CANCELLED,
+            )));
+        }
+
+        logs
+    }
+
+    /// Change the price and/or size of a resting order. `order.size` is the new remaining
+    /// size. Reducing the size at the same price keeps the order's time priority, any other
+    /// change re-queues it behind the orders resting at its (new) price. If the new price
+    /// crosses the book, the order trades as a taker first.
+    pub fn amend_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let logs = self.change_order(order);
+        self.settled(logs)
+    }
+
+    fn change_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+
+        // Mark order as seen in time window
+        let now_time = self.time_since_snowflake_epoch();
+        let _ = self.time_window.put(order.id, now_time);
+
+        let resting = match order.side {
+            Side::SideBuy => self.bid_depths.orders.get(&order.id).cloned(),
+            Side::SideSell => self.ask_depths.orders.get(&order.id).cloned(),
+        };
+        let resting = match resting {
+            Some(o) => o,
+            // a stop order has no place on the book to amend until it is triggered
+            None => match self.stop_book.orders.get(&order.id).cloned() {
+                Some(o) => {
+                    self.push_amend_reject_log(&o, &RejectReason::RejectReasonNotAmendable, &mut logs);
+                    return logs;
+                }
+                None => {
+                    let book_order = BookOrder::new_book_order(order);
+                    self.push_amend_reject_log(
+                        &book_order,
+                        &RejectReason::RejectReasonUnknownOrder,
+                        &mut logs,
+                    );
+                    return logs;
+                }
+            },
+        };
+
+        // the new price and size must follow the same trading rules as a new order, checked
+        // on the resting order rather than on the other fields of the amend message
+        let candidate = Order {
+            id: resting.order_id,
+            user_id: resting.user_id,
+            price: order.price,
+            size: order.size,
+            funds: resting.funds,
+            stop_price: resting.stop_price,
+            display_size: resting.display_size,
+            r#type: resting.r#type.clone(),
+            side: resting.side.clone(),
+            time_in_force: resting.time_in_force.clone(),
+            expire_time: resting.expire_time,
+            stp: resting.stp.clone(),
+            ..order.clone()
+        };
+        if let Err(reason) = self.check_order(&candidate) {
+            self.push_amend_reject_log(&resting, &reason, &mut logs);
+            return logs;
+        }
+        // a post-only order must not become a taker through an amend either
+        if let TimeInForceType::GoodTillCrossing = resting.time_in_force
+            && !self.is_order_will_not_match(&candidate)
+        {
+            self.push_amend_reject_log(&resting, &RejectReason::RejectReasonPostOnly, &mut logs);
+            return logs;
+        }
+        let (new_price, new_size) = (order.price, order.size);
+
+        let old_size = resting.size + resting.hidden_size;
+        if new_price == resting.price && new_size == old_size {
+            return logs;
+        }
+
+        if self.product.risk_ledger {
+            let amount = self.required_hold(&resting, &new_price, &new_size);
+            if let Err(reason) =
+                self.ledger
+                    .set_hold(order.id, resting.user_id, &resting.side, &amount)
+            {
+                self.push_amend_reject_log(&resting, &reason, &mut logs);
+                return logs;
+            }
+        }
+
+        logs.push(Box::new(new_change_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &resting,
+            &old_size,
+            &new_size,
+            &new_price,
+        )));
+
+        // Size-down in place, the order keeps its place in the queue
+        if new_price == resting.price && new_size < old_size {
+            let result = match order.side {
+                Side::SideBuy => self.bid_depths.reduce_size(order.id, &old_size.sub(new_size)),
+                Side::SideSell => self.ask_depths.reduce_size(order.id, &old_size.sub(new_size)),
+            };
+            if let Err(e) = result {
+                panic!("{}", e);
+            }
+            return logs;
+        }
+
+        let mut amended = match order.side {
+            Side::SideBuy => self.bid_depths.remove(order.id),
+            Side::SideSell => self.ask_depths.remove(order.id),
+        }
+        .unwrap();
+        // the amended order is indexed again once it rests
+        self.expiry_index.remove(&amended);
+        amended.price = new_price;
+        amended.size = new_size;
+        amended.hidden_size = Decimal::ZERO;
+        amended.priority = match order.side {
+            Side::SideBuy => self.bid_depths.next_priority(&new_price, amended.priority),
+            Side::SideSell => self.ask_depths.next_priority(&new_price, amended.priority),
+        };
+
+        let crossed = match order.side {
+            Side::SideBuy => self
+                .ask_depths
+                .queue
+                .first_key_value()
+                .is_some_and(|(k, _)| new_price >= k.price),
+            Side::SideSell => self
+                .bid_depths
+                .queue
+                .first_key_value()
+                .is_some_and(|(k, _)| new_price <= k.price),
+        };
+
+        if crossed {
+            self.match_order(amended, true, &mut logs);
+            self.push_amended_slice_log(order, &mut logs);
+            self.trigger_stop_orders(&mut logs);
+        } else {
+            Self::show_first_slice(&mut amended);
+            self.expiry_index.add(&amended);
+            match order.side {
+                Side::SideBuy => self.bid_depths.add(&amended),
+                Side::SideSell => self.ask_depths.add(&amended),
+            }
+            self.push_amended_slice_log(order, &mut logs);
+        }
+
+        logs
+    }
+
+    /// An amended iceberg rests again with only its first slice shown, announce the slice
+    /// the way a replenished one is.
+    fn push_amended_slice_log(&mut self, order: &Order, logs: &mut Vec<Box<dyn LogTrait>>) {
+        let rested = match order.side {
+            Side::SideBuy => self.bid_depths.orders.get(&order.id).cloned(),
+            Side::SideSell => self.ask_depths.orders.get(&order.id).cloned(),
+        };
+        if let Some(o) = rested
+            && !o.hidden_size.is_zero()
+        {
+            logs.push(Box::new(new_open_log(
+                self.next_log_seq(),
+                self.order_time,
+                &self.product.id,
+                &o,
+            )));
+        }
+    }
+
+    /// An order already seen inside the time window. It is resting, done or was rejected,
+    /// so applying it again would only repeat its logs.
+    pub fn is_duplicate(&self, order: &Order) -> bool {
+        self.time_window.contains(order.id)
+    }
+
+    /// Round prices to `quote_scale` and sizes to `base_scale` where the product sets no
+    /// tick or lot size, as before trading rules existed. With a rule set, nothing is
+    /// rounded and check_order rejects what breaks it.
+    pub fn normalize_order(&self, order: &Order) -> Order {
+        let mut order = order.clone();
+        if self.product.tick_size.is_zero() {
+            order.price = normalize_price(order.price, self.product.quote_scale as u32);
+            order.stop_price = normalize_price(order.stop_price, self.product.quote_scale as u32);
+        }
+        if self.product.lot_size.is_zero() {
+            order.size = normalize_size(order.size, self.product.base_scale as u32);
+            order.display_size = normalize_size(order.display_size, self.product.base_scale as u32);
+        }
+        order
+    }
+
+    /// Check an order against the product's trading rules. Orders of a product with a tick
+    /// or lot size are never re-rounded, anything off the tick or lot size is rejected.
+    pub fn check_order(&self, order: &Order) -> Result<(), RejectReason> {
+        match self.market_state() {
+            MarketState::MarketStateContinuous => {}
+            MarketState::MarketStateHalted => return Err(RejectReason::RejectReasonHalted),
+            MarketState::MarketStateCancelOnly => return Err(RejectReason::RejectReasonCancelOnly),
+            MarketState::MarketStateAuction => {
+                // orders are collected until the uncross, only resting limit orders make sense
+                if matches!(order.r#type, OrderType::OrderTypeMarket)
+                    || matches!(
+                        order.time_in_force,
+                        TimeInForceType::ImmediateOrCancel | TimeInForceType::FillOrKill
+                    )
+                {
+                    return Err(RejectReason::RejectReasonAuction);
+                }
+            }
+            MarketState::MarketStatePostOnly => {
+                // stop orders only wait in the trigger book, everything else must not trade
+                if !order.r#type.is_stop()
+                    && (matches!(order.r#type, OrderType::OrderTypeMarket)
+                        || !self.is_order_will_not_match(order))
+                {
+                    return Err(RejectReason::RejectReasonPostOnly);
+                }
+            }
+        }
+
+        let product = &self.product;
+        let tick_size = product.effective_tick_size();
+        let lot_size = product.effective_lot_size();
+
+        let is_limit = !matches!(
+            order.r#type,
+            OrderType::OrderTypeMarket | OrderType::OrderTypeStop
+        );
+        if is_limit {
+            if order.price <= Decimal::ZERO {
+                return Err(RejectReason::RejectReasonInvalidPrice);
+            }
+            if !(order.price % tick_size).is_zero() {
+                return Err(RejectReason::RejectReasonTickSize);
+            }
+        }
+        // the limit price of a stop-limit order is only banded once it is triggered, see
+        // trigger_order
+        if let OrderType::OrderTypeLimit = order.r#type
+            && self.is_outside_price_band(&order.price)
+        {
+            return Err(RejectReason::RejectReasonPriceBand);
+        }
+        if order.r#type.is_stop() {
+            if order.stop_price <= Decimal::ZERO {
+                return Err(RejectReason::RejectReasonInvalidPrice);
+            }
+            if !(order.stop_price % tick_size).is_zero() {
+                return Err(RejectReason::RejectReasonTickSize);
+            }
+            // time in force is applied when a stop order gets triggered, only GTC and IOC
+            // make sense for an order that waits first
+            if !matches!(
+                order.time_in_force,
+                TimeInForceType::GoodTillCanceled | TimeInForceType::ImmediateOrCancel
+            ) {
+                return Err(RejectReason::RejectReasonInvalidTimeInForce);
+            }
+        }
+
+        if matches!(
+            order.time_in_force,
+            TimeInForceType::GoodTillTime | TimeInForceType::GoodTillDate
+        ) && order.expire_time == 0
+        {
+            return Err(RejectReason::RejectReasonInvalidExpireTime);
+        }
+
+        // a market order needs a size, funds or both
+        if order.size < Decimal::ZERO
+            || order.funds < Decimal::ZERO
+            || (is_limit && order.size.is_zero())
+            || (order.size.is_zero() && order.funds.is_zero())
+        {
+            return Err(RejectReason::RejectReasonInvalidSize);
+        }
+        // with a risk ledger what a market order pays must be bounded to hold it up front
+        if product.risk_ledger && !is_limit {
+            let unbounded = match order.side {
+                Side::SideBuy => order.funds.is_zero(),
+                Side::SideSell => order.size.is_zero(),
+            };
+            if unbounded {
+                return Err(RejectReason::RejectReasonInvalidSize);
+            }
+        }
+        if !order.size.is_zero() {
+            if !(order.size % lot_size).is_zero() {
+                return Err(RejectReason::RejectReasonLotSize);
+            }
+            if !product.min_size.is_zero() && order.size < product.min_size {
+                return Err(RejectReason::RejectReasonMinSize);
+            }
+            if !product.max_size.is_zero() && order.size > product.max_size {
+                return Err(RejectReason::RejectReasonMaxSize);
+            }
+        }
+        if order.display_size < Decimal::ZERO || !(order.display_size % lot_size).is_zero() {
+            return Err(RejectReason::RejectReasonLotSize);
+        }
+
+        // the notional of a market order is only known up front when it is bounded by funds
+        let notional = if is_limit {
+            Some(order.price * order.size)
+        } else if !order.funds.is_zero() {
+            Some(order.funds)
+        } else {
+            None
+        };
+        if let Some(notional) = notional {
+            if !product.min_notional.is_zero() && notional < product.min_notional {
+                return Err(RejectReason::RejectReasonMinNotional);
+            }
+            if !product.max_notional.is_zero() && notional > product.max_notional {
+                return Err(RejectReason::RejectReasonMaxNotional);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a limit price is more than `price_band` percent away from the last trade
+    /// price. Nothing is banded before the first trade.
+    fn is_outside_price_band(&self, price: &Decimal) -> bool {
+        !self.product.price_band.is_zero()
+            && !self.last_trade_price.is_zero()
+            && (price - self.last_trade_price).abs() * Decimal::ONE_HUNDRED
+                > self.last_trade_price * self.product.price_band
+    }
+
+    /// Reject an order that breaks the trading rules without it touching the book.
+    pub fn reject_order(&mut self, order: &Order, reason: &RejectReason) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+
+        // Mark order as seen in time window
+        let now_time = self.time_since_snowflake_epoch();
+        let _ = self.time_window.put(order.id, now_time);
+
+        let book_order = BookOrder::new_book_order(order);
+        self.push_reject_log(&book_order, &order.size, reason, &mut logs);
+
+        logs
+    }
+
+    fn push_reject_log(
+        &mut self,
+        order: &BookOrder,
+        remaining_size: &Decimal,
+        reason: &RejectReason,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        info!("reject order {}: {}", order.order_id, reason.as_str());
+
+        let mut done_log = new_done_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            order,
+            remaining_size,
+            &DONE_REASON_REJECTED,
+        );
+        done_log.reject_reason = Some(reason.clone());
+        logs.push(Box::new(done_log));
+    }
+
+    /// Refuse an amend with a change log that leaves the order as it is.
+    fn push_amend_reject_log(
+        &mut self,
+        order: &BookOrder,
+        reason: &RejectReason,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        info!("reject amend of order {}: {}", order.order_id, reason.as_str());
+
+        let size = order.size + order.hidden_size;
+        let mut change_log = new_change_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            order,
+            &size,
+            &size,
+            &order.price,
+        );
+        change_log.reject_reason = Some(reason.clone());
+        logs.push(Box::new(change_log));
+    }
+
+    /// The state orders are handled in: halted during a circuit breaker halt, otherwise
+    /// whatever the last control message set.
+    pub fn market_state(&self) -> MarketState {
+        if self.halted_until != 0 {
+            MarketState::MarketStateHalted
+        } else {
+            self.market_state.clone()
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.market_state() == MarketState::MarketStateHalted
+    }
+
+    /// Apply the market state of a control message. Setting a state also ends a circuit
+    /// breaker halt, so an admin can resume trading early.
+    pub fn set_market_state(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let logs = self.change_market_state(order);
+        self.settled(logs)
+    }
+
+    fn change_market_state(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+
+        let market_state = match &order.market_state {
+            Some(market_state) => market_state.clone(),
+            None => {
+                info!("ignoring control message {} without market state", order.id);
+                return logs;
+            }
+        };
+
+        let old_state = self.market_state();
+        self.market_state = market_state;
+        self.halted_until = 0;
+        // orders collected during an auction call phase are uncrossed once the book trades
+        // again, also when it was halted in between. The state is set first, so stop orders
+        // triggered by the uncross trade in the new state.
+        if !matches!(
+            self.market_state,
+            MarketState::MarketStateAuction | MarketState::MarketStateHalted
+        ) && self.is_crossed()
+        {
+            self.uncross(&mut logs);
+        }
+        if old_state == self.market_state {
+            return logs;
+        }
+
+        info!(
+            "market state of {}: {} -> {}",
+            self.product.id,
+            old_state.as_str(),
+            self.market_state.as_str()
+        );
+        let (market_state, last_trade_price) = (self.market_state.clone(), self.last_trade_price);
+        logs.push(Box::new(new_status_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &market_state,
+            &last_trade_price,
+            &Decimal::ZERO,
+            0,
+        )));
+
+        logs
+    }
+
+    /// The price a market taker would sweep the book to, if that is further from the last
+    /// trade price than the circuit breaker allows. Self-trade prevention is not taken into
+    /// account, so this errs on the side of halting.
+    fn breaching_sweep_price(&self, taker_order: &BookOrder) -> Option<Decimal> {
+        if self.product.circuit_breaker.is_zero() || self.last_trade_price.is_zero() {
+            return None;
+        }
+
+        let (by_size, by_funds) = Self::taker_bounds(taker_order);
+        let makers: Vec<&BookOrder> = match taker_order.side {
+            Side::SideBuy => self
+                .ask_depths
+                .queue
+                .values()
+                .map(|v| &self.ask_depths.orders[v])
+                .collect(),
+            Side::SideSell => self
+                .bid_depths
+                .queue
+                .values()
+                .map(|v| &self.bid_depths.orders[v])
+                .collect(),
+        };
+
+        let mut taker_order = taker_order.clone();
+        let mut sweep_price = None;
+        for maker_order in makers {
+            let size = self.trade_size(
+                &taker_order,
+                by_size,
+                by_funds,
+                &maker_order.price,
+                &(maker_order.size + maker_order.hidden_size),
+            );
+            if size.is_zero() {
+                break;
+            }
+            sweep_price = Some(maker_order.price);
+            Self::take_from_taker(&mut taker_order, by_size, by_funds, &size, &maker_order.price);
+        }
+
+        sweep_price.filter(|price| {
+            (price - self.last_trade_price).abs() * Decimal::ONE_HUNDRED
+                > self.last_trade_price * self.product.circuit_breaker
+        })
+    }
+
+    fn halt(&mut self, trigger_price: &Decimal, logs: &mut Vec<Box<dyn LogTrait>>) {
+        self.halted_until =
+            self.order_time + self.product.effective_halt_duration() * 1_000_000_000;
+        info!(
+            "halt {} until {}: sweep to {} from {}",
+            self.product.id, self.halted_until, trigger_price, self.last_trade_price
+        );
+
+        let last_trade_price = self.last_trade_price;
+        logs.push(Box::new(new_status_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &MarketState::MarketStateHalted,
+            &last_trade_price,
+            trigger_price,
+            self.halted_until,
+        )));
+    }
+
+    /// Announce the indicative uncross price and volume during the call phase of an auction
+    /// whenever they change. Should be called after each order is handled.
+    pub fn indicate_auction(&mut self) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        if self.market_state() != MarketState::MarketStateAuction {
+            self.indicative = None;
+            return logs;
+        }
+
+        let indicative =
+            find_equilibrium(&self.bid_depths, &self.ask_depths, &self.last_trade_price);
+        if indicative == self.indicative {
+            return logs;
+        }
+        self.indicative = indicative;
+
+        let (price, volume, imbalance) = match &self.indicative {
+            Some(e) => (e.price, e.volume, e.imbalance),
+            None => (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO),
+        };
+        logs.push(Box::new(new_auction_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &price,
+            &volume,
+            &imbalance,
+        )));
+
+        logs
+    }
+
+    /// The best `n` price levels of each side, bids and then asks.
+    pub fn top_levels(&self, n: usize) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        (self.bid_depths.top_levels(n), self.ask_depths.top_levels(n))
+    }
+
+    pub fn l3_snapshot(&self) -> L3Snapshot {
+        let l3_order = |o: &BookOrder| L3Order {
+            order_id: o.order_id,
+            price: o.price,
+            size: o.size,
+        };
+        L3Snapshot {
+            product_id: self.product.id.clone(),
+            log_seq: self.log_seq,
+            time: self.order_time,
+            bids: self
+                .bid_depths
+                .queue
+                .values()
+                .map(|v| l3_order(&self.bid_depths.orders[v]))
+                .collect(),
+            asks: self
+                .ask_depths
+                .queue
+                .values()
+                .map(|v| l3_order(&self.ask_depths.orders[v]))
+                .collect(),
+        }
+    }
+
+    /// An l2update log with the levels changed since the last call, if the product
+    /// publishes them and any level changed.
+    pub fn l2_update(&mut self) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        let bids = self.bid_depths.take_changed_levels();
+        let asks = self.ask_depths.take_changed_levels();
+        if !self.product.l2_update || (bids.is_empty() && asks.is_empty()) {
+            return logs;
+        }
+
+        logs.push(Box::new(new_l2update_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            bids,
+            asks,
+        )));
+        logs
+    }
+
+    /// Whether the best bid is at or above the best ask, which only an auction leaves behind.
+    fn is_crossed(&self) -> bool {
+        match (
+            self.bid_depths.queue.first_key_value(),
+            self.ask_depths.queue.first_key_value(),
+        ) {
+            (Some((_, bid_id)), Some((_, ask_id))) => {
+                self.bid_depths.orders[bid_id].price >= self.ask_depths.orders[ask_id].price
+            }
+            _ => false,
+        }
+    }
+
+    /// Execute every crossed order at the single equilibrium price, in price-time priority
+    /// on both sides. The order that arrived later is reported as the taker of each match.
+    fn uncross(&mut self, logs: &mut Vec<Box<dyn LogTrait>>) {
+        self.indicative = None;
+        let equilibrium =
+            find_equilibrium(&self.bid_depths, &self.ask_depths, &self.last_trade_price);
+        let price = match equilibrium {
+            Some(e) => e.price,
+            None => return,
+        };
+        info!("uncross {} at {}", self.product.id, price);
+
+        while let (Some((_, bid_id)), Some((_, ask_id))) = (
+            self.bid_depths.queue.first_key_value(),
+            self.ask_depths.queue.first_key_value(),
+        ) {
+            let bid_order = self.bid_depths.orders[bid_id].clone();
+            let ask_order = self.ask_depths.orders[ask_id].clone();
+            if bid_order.price < price || ask_order.price > price {
+                break;
+            }
+
+            let size = Decimal::min(bid_order.size, ask_order.size);
+            let (taker_order, maker_order) = if bid_order.order_id > ask_order.order_id {
+                (&bid_order, &ask_order)
+            } else {
+                (&ask_order, &bid_order)
+            };
+            if Self::is_self_trade(taker_order, maker_order) {
+                self.prevent_auction_self_trade(taker_order, maker_order, logs);
+                continue;
+            }
+
+            // the bid pays, one that cannot is cancelled
+            if !self.can_pay(taker_order, maker_order, &price, &size) {
+                self.cancel_resting(&bid_order, &DONE_REASON_CANCELLED, logs);
+                continue;
+            }
+
+            self.push_match_log(taker_order, maker_order, &price, &size, logs);
+            self.fill_resting_order(&bid_order, &size, logs);
+            self.fill_resting_order(&ask_order, &size, logs);
+        }
+
+        self.last_trade_price = price;
+        self.trigger_stop_orders(logs);
+    }
+
+    /// Apply the self-trade prevention mode of the later order at the uncross. Both orders
+    /// rest on the book there, so a cancelled or decremented taker is taken off the book too.
+    fn prevent_auction_self_trade(
+        &mut self,
+        taker_order: &BookOrder,
+        maker_order: &BookOrder,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        let taker_size = taker_order.size + taker_order.hidden_size;
+        let mut taker = taker_order.clone();
+        taker.size = taker_size;
+        match self.prevent_self_trade(&mut taker, maker_order, logs) {
+            SelfTradeAction::CancelTaker => self.cancel_resting(taker_order, &DONE_REASON_SELF_TRADE_PREVENTED, logs),
+            SelfTradeAction::SkipMaker => {
+                let size = taker_size.sub(taker.size);
+                if size.is_zero() {
+                    return;
+                }
+                let result = match taker_order.side {
+                    Side::SideBuy => self.bid_depths.reduce_size(taker_order.order_id, &size),
+                    Side::SideSell => self.ask_depths.reduce_size(taker_order.order_id, &size),
+                };
+                if let Err(e) = result {
+                    panic!("{}", e);
                 }
             }
+        }
+    }
+
+    /// Take `size` off a resting order and report it filled, or the next iceberg slice.
+    fn fill_resting_order(
+        &mut self,
+        order: &BookOrder,
+        size: &Decimal,
+        logs: &mut Vec<Box<dyn LogTrait>>,
+    ) {
+        let result = match order.side {
+            Side::SideBuy => self.bid_depths.decr_size(order.order_id, size),
+            Side::SideSell => self.ask_depths.decr_size(order.order_id, size),
+        };
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+        let remaining = match order.side {
+            Side::SideBuy => self.bid_depths.orders.get(&order.order_id).cloned(),
+            Side::SideSell => self.ask_depths.orders.get(&order.order_id).cloned(),
         };
 
+        match remaining {
+            None => {
+                self.expiry_index.remove(order);
+                logs.push(Box::new(new_done_log(
+                    self.next_log_seq(),
+                    self.order_time,
+                    &self.product.id,
+                    order,
+                    &Decimal::ZERO,
+                    &DONE_REASON_FILLED,
+                )));
+            }
+            Some(o) if o.priority != order.priority => {
+                logs.push(Box::new(new_open_log(
+                    self.next_log_seq(),
+                    self.order_time,
+                    &self.product.id,
+                    &o,
+                )));
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// The time a message may move the clock to: its `created_at`, but no further than
+    /// MAX_CLOCK_SKEW past the time in its snowflake id. The id is stamped by the order
+    /// service as well, so a single message with a far-future `created_at` cannot expire
+    /// every resting order at once.
+    pub fn clock_time(order: &Order) -> u64 {
+        let id_time = (extract_timestamp_from_id(order.id) + SNOWFLAKE_EPOCH) as u64 * 1_000_000;
+        let limit = id_time.saturating_add(MAX_CLOCK_SKEW);
+        if order.created_at > limit {
+            info!(
+                "order {} created_at {} is ahead of its id time {}, clamped",
+                order.id, order.created_at, id_time
+            );
+            return limit;
+        }
+        order.created_at
+    }
+
+    /// Move the order stream clock forward to `created_at`, resume trading if a circuit
+    /// breaker halt has run out and cancel every resting order that has expired by then.
+    /// Time only comes from the order stream, so a replay stamps its logs with the same
+    /// times and expires the same orders at the same point. Should be called before each
+    /// order is handled.
+    pub fn advance_time(&mut self, created_at: u64) -> Vec<Box<dyn LogTrait>> {
+        let logs = self.move_clock(created_at);
+        self.settled(logs)
+    }
+
+    fn move_clock(&mut self, created_at: u64) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        if created_at <= self.order_time {
+            return logs;
+        }
+        self.order_time = created_at;
+        self.cleanup_time_window();
+
+        self.resume_if_due(&mut logs);
+        self.expire_orders(&mut logs);
+
         logs
     }
 
+    fn expire_orders(&mut self, logs: &mut Vec<Box<dyn LogTrait>>) {
+        for (order_id, side) in self.expiry_index.take_expired(self.order_time) {
+            let order = match side {
+                Side::SideBuy => self.bid_depths.remove(order_id),
+                Side::SideSell => self.ask_depths.remove(order_id),
+            };
+            if let Some(o) = order {
+                info!("expire order {}", order_id);
+                logs.push(Box::new(new_done_log(
+                    self.next_log_seq(),
+                    self.order_time,
+                    &self.product.id,
+                    &o,
+                    &(o.size + o.hidden_size),
+                    &DONE_REASON_EXPIRED,
+                )));
+            }
+        }
+    }
+
+    /// Resume trading once a circuit breaker halt has run out.
+    fn resume_if_due(&mut self, logs: &mut Vec<Box<dyn LogTrait>>) {
+        if self.halted_until == 0 || self.order_time < self.halted_until {
+            return;
+        }
+
+        info!("resume {}", self.product.id);
+        self.halted_until = 0;
+
+        // back to the state set by the last control message
+        let (market_state, last_trade_price) = (self.market_state.clone(), self.last_trade_price);
+        if market_state == MarketState::MarketStateHalted {
+            return;
+        }
+        logs.push(Box::new(new_status_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            &market_state,
+            &last_trade_price,
+            &Decimal::ZERO,
+            0,
+        )));
+    }
+
     pub fn nullify_order(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
         let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
 
         // Mark order as seen in time window
-        let now_time = current_time_since_snowflake_epoch();
+        let now_time = self.time_since_snowflake_epoch();
         let _ = self.time_window.put(order.id, now_time);
 
         let book_order = BookOrder::new_book_order(order);
         logs.push(Box::new(new_done_log(
             self.next_log_seq(),
+            self.order_time,
             &self.product.id,
             &book_order,
             &order.size,
@@ -572,6 +1980,104 @@ impl OrderBook {
         logs
     }
 
+    /// What an order of `size` at `price` pays at most: the size for a sell, and for a buy
+    /// its notional, or funds if it is a market order, plus the higher of its two fees,
+    /// rounded up to quote_scale.
+    fn required_hold(&self, order: &BookOrder, price: &Decimal, size: &Decimal) -> Decimal {
+        let product = &self.product;
+        match order.side {
+            Side::SideSell => *size,
+            Side::SideBuy => {
+                let notional = match order.r#type {
+                    OrderType::OrderTypeMarket | OrderType::OrderTypeStop => order.funds,
+                    OrderType::OrderTypeLimit | OrderType::OrderTypeStopLimit => price.mul(size),
+                };
+                let fee_bps = Decimal::max(
+                    order.maker_fee_bps.unwrap_or(product.maker_fee_bps),
+                    order.taker_fee_bps.unwrap_or(product.taker_fee_bps),
+                )
+                .max(Decimal::ZERO);
+                (notional + notional.mul(fee_bps).div(Decimal::from(10_000)))
+                    .round_dp_with_strategy(product.quote_scale as u32, RoundingStrategy::AwayFromZero)
+            }
+        }
+    }
+
+    /// Release the holds of the orders that are done in `logs`, when the product has a risk
+    /// ledger. Trades are paid as they are made, see push_match_log. Every public method
+    /// that can finish an order that holds funds passes its logs through here before it
+    /// returns them.
+    fn settled(&mut self, logs: Vec<Box<dyn LogTrait>>) -> Vec<Box<dyn LogTrait>> {
+        if !self.product.risk_ledger {
+            return logs;
+        }
+        for log in &logs {
+            if let Some(d) = log.as_done() {
+                self.ledger.release(d.order_id);
+            }
+        }
+        logs
+    }
+
+    /// Deposit to or withdraw from the available balance of the user of a control message.
+    pub fn transfer(&mut self, order: &Order) -> Vec<Box<dyn LogTrait>> {
+        let mut logs: Vec<Box<dyn LogTrait>> = Vec::new();
+        let Some(transfer) = &order.transfer else {
+            return logs;
+        };
+        if !self.product.risk_ledger {
+            info!("ignoring transfer {}: {} has no risk ledger", order.id, self.product.id);
+            return logs;
+        }
+
+        let product = &self.product;
+        let (asset, scale) = if transfer.currency == product.base_currency {
+            (Some(Asset::AssetBase), product.base_scale)
+        } else if transfer.currency == product.quote_currency {
+            (Some(Asset::AssetQuote), product.quote_scale)
+        } else {
+            (None, 0)
+        };
+        let result = match &asset {
+            None => Err(RejectReason::RejectReasonInvalidCurrency),
+            Some(_) if transfer.amount <= Decimal::ZERO
+                || transfer.amount.normalize().scale() > scale as u32 =>
+            {
+                Err(RejectReason::RejectReasonInvalidSize)
+            }
+            Some(asset) => match transfer.r#type {
+                TransferType::TransferTypeDeposit => {
+                    self.ledger.deposit(order.user_id, asset, &transfer.amount);
+                    Ok(())
+                }
+                TransferType::TransferTypeWithdraw => {
+                    self.ledger.withdraw(order.user_id, asset, &transfer.amount)
+                }
+            },
+        };
+
+        let account = self.ledger.account(order.user_id);
+        let balance = match &asset {
+            Some(asset) => account.balance(asset).clone(),
+            None => Default::default(),
+        };
+        let mut transfer_log = new_transfer_log(
+            self.next_log_seq(),
+            self.order_time,
+            &self.product.id,
+            order.id,
+            order.user_id,
+            transfer,
+            &balance,
+        );
+        if let Err(reason) = result {
+            info!("reject transfer {}: {}", order.id, reason.as_str());
+            transfer_log.reject_reason = Some(reason);
+        }
+        logs.push(Box::new(transfer_log));
+        logs
+    }
+
     pub fn snapshot(&self) -> OrderBookSnapshot {
         let mut snapshot = OrderBookSnapshot {
             product_id: self.product.id.clone(),
@@ -580,6 +2086,11 @@ impl OrderBook {
             log_seq: self.log_seq,
             time_window: self.time_window.snapshot(),
             last_trade_price: self.last_trade_price,
+            stop_orders: self.stop_book.orders.values().cloned().collect(),
+            halted_until: self.halted_until,
+            market_state: self.market_state.clone(),
+            order_time: self.order_time,
+            ledger: self.ledger.snapshot(),
         };
         snapshot
             .orders
@@ -595,10 +2106,65 @@ impl OrderBook {
         snapshot
     }
 
+    /// The orders changed since the last call to `clear_changes`, without touching the rest
+    /// of the book.
+    pub fn delta(&self) -> OrderBookDelta {
+        let mut delta = OrderBookDelta {
+            product_id: self.product.id.clone(),
+            orders: Vec::new(),
+            stop_orders: Vec::new(),
+            removed: Vec::new(),
+            trade_seq: self.trade_seq,
+            log_seq: self.log_seq,
+            time_window: self.time_window.snapshot(),
+            last_trade_price: self.last_trade_price,
+            halted_until: self.halted_until,
+            market_state: self.market_state.clone(),
+            order_time: self.order_time,
+            ledger: self.ledger.delta(),
+        };
+
+        let changed: HashSet<u64> = self
+            .ask_depths
+            .changed
+            .iter()
+            .chain(self.bid_depths.changed.iter())
+            .chain(self.stop_book.changed.iter())
+            .copied()
+            .collect();
+        for order_id in changed {
+            if let Some(o) = self
+                .ask_depths
+                .orders
+                .get(&order_id)
+                .or_else(|| self.bid_depths.orders.get(&order_id))
+            {
+                delta.orders.push(o.clone());
+            } else if let Some(o) = self.stop_book.orders.get(&order_id) {
+                delta.stop_orders.push(o.clone());
+            } else {
+                delta.removed.push(order_id);
+            }
+        }
+
+        delta
+    }
+
+    /// Start recording changes for the next delta, after a snapshot was taken.
+    pub fn clear_changes(&mut self) {
+        self.ask_depths.changed.clear();
+        self.bid_depths.changed.clear();
+        self.stop_book.changed.clear();
+        self.ledger.clear_changes();
+    }
+
     pub fn restore(&mut self, snapshot: &OrderBookSnapshot) {
         self.log_seq = snapshot.log_seq;
         self.trade_seq = snapshot.trade_seq;
         self.last_trade_price = snapshot.last_trade_price;
+        self.halted_until = snapshot.halted_until;
+        self.market_state = snapshot.market_state.clone();
+        self.order_time = snapshot.order_time;
 
         // Restore time window
         self.time_window.restore(&snapshot.time_window);
@@ -612,16 +2178,32 @@ impl OrderBook {
                     self.ask_depths.add(o);
                 }
             }
+            self.expiry_index.add(o);
         }
+
+        for o in &snapshot.stop_orders {
+            self.stop_book.add(o);
+        }
+        self.ledger.restore(&snapshot.ledger);
+        self.clear_changes();
+        // restoring is not a change subscribers see
+        self.bid_depths.take_changed_levels();
+        self.ask_depths.take_changed_levels();
     }
 
-    /// Cleanup expired orders from the time window.
-    /// This should be called periodically to prevent memory leaks when there are no new orders.
-    pub fn cleanup_time_window(&mut self) {
-        let now_time = current_time_since_snowflake_epoch();
+    /// Cleanup expired orders from the time window. Called whenever the order clock moves,
+    /// so the window is pruned on a clock tick even when there are no new orders.
+    fn cleanup_time_window(&mut self) {
+        let now_time = self.time_since_snowflake_epoch();
         self.time_window.cleanup(now_time);
     }
 
+    /// Order time in milliseconds relative to the Snowflake epoch, the time base of the
+    /// deduplication window.
+    fn time_since_snowflake_epoch(&self) -> i64 {
+        (self.order_time / 1_000_000) as i64 - SNOWFLAKE_EPOCH
+    }
+
     pub fn next_log_seq(&mut self) -> u64 {
         self.log_seq += 1;
         self.log_seq
@@ -632,3 +2214,1145 @@ impl OrderBook {
         self.trade_seq
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    use crate::matching::log::LogTrait;
+    use crate::matching::order_book::{
+        L3Order, L3Snapshot, OrderBook, OrderBookSnapshot, MAX_CLOCK_SKEW,
+    };
+    use crate::models::models::{Order, Product};
+    use crate::models::types::{
+        MarketState, OrderStatus, OrderType, RejectReason, SelfTradePrevention, Side,
+        TimeInForceType,
+    };
+    use crate::utils::time_window::SNOWFLAKE_EPOCH;
+
+    fn make_product() -> Product {
+        Product {
+            id: "BTC-USD".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "USD".to_string(),
+            base_scale: 6,
+            quote_scale: 2,
+            ..Default::default()
+        }
+    }
+
+    fn make_order(id: u64, user_id: u64, side: Side, size: &str, price: &str) -> Order {
+        Order::new_test_order(id, user_id, side, size, price)
+    }
+
+    fn to_json(logs: &[Box<dyn LogTrait>]) -> Vec<serde_json::Value> {
+        logs.iter()
+            .map(|l| serde_json::to_value(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_self_trade_allowed_without_stp() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 7, Side::SideSell, "1", "100"));
+
+        let logs = to_json(&book.apply_order(&make_order(2, 7, Side::SideBuy, "1", "100")));
+        assert_eq!(logs[0]["base"]["type"], "match");
+        assert_eq!(logs[0]["taker_user_id"], logs[0]["maker_user_id"]);
+    }
+
+    #[test]
+    fn test_stp_cancel_newest() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 7, Side::SideSell, "1", "100"));
+
+        let mut taker = make_order(2, 7, Side::SideBuy, "1", "100");
+        taker.stp = SelfTradePrevention::SelfTradePreventionCancelNewest;
+        let logs = to_json(&book.apply_order(&taker));
+
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["order_id"], 2);
+        assert_eq!(logs[0]["reason"], "stp");
+        assert!(book.ask_depths.orders.contains_key(&1));
+        assert!(!book.bid_depths.orders.contains_key(&2));
+    }
+
+    #[test]
+    fn test_stp_cancel_oldest_keeps_matching() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 7, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 8, Side::SideSell, "1", "100"));
+
+        let mut taker = make_order(3, 7, Side::SideBuy, "1", "100");
+        taker.stp = SelfTradePrevention::SelfTradePreventionCancelOldest;
+        let logs = to_json(&book.apply_order(&taker));
+
+        assert_eq!(logs[0]["base"]["type"], "done");
+        assert_eq!(logs[0]["order_id"], 1);
+        assert_eq!(logs[0]["reason"], "stp");
+        assert_eq!(logs[1]["base"]["type"], "match");
+        assert_eq!(logs[1]["maker_order_id"], 2);
+        assert!(book.ask_depths.orders.is_empty());
+    }
+
+    #[test]
+    fn test_stp_cancel_both() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 7, Side::SideBuy, "1", "100"));
+
+        let mut taker = make_order(2, 7, Side::SideSell, "2", "100");
+        taker.stp = SelfTradePrevention::SelfTradePreventionCancelBoth;
+        let logs = to_json(&book.apply_order(&taker));
+
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0]["order_id"], 1);
+        assert_eq!(logs[0]["reason"], "stp");
+        assert_eq!(logs[1]["order_id"], 2);
+        assert_eq!(logs[1]["reason"], "stp");
+        assert_eq!(logs[1]["remaining_size"], "2");
+        assert!(book.bid_depths.orders.is_empty());
+        assert!(book.ask_depths.orders.is_empty());
+    }
+
+    #[test]
+    fn test_stp_decrement_and_cancel() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 7, Side::SideSell, "3", "100"));
+
+        let mut taker = make_order(2, 7, Side::SideBuy, "1", "100");
+        taker.stp = SelfTradePrevention::SelfTradePreventionDecrementAndCancel;
+        let logs = to_json(&book.apply_order(&taker));
+
+        // the smaller taker is cancelled and the maker is decremented by its size
+        assert_eq!(logs.last().unwrap()["order_id"], 2);
+        assert_eq!(logs.last().unwrap()["reason"], "stp");
+        assert_eq!(
+            book.ask_depths.orders.get(&1).unwrap().size,
+            Decimal::from_str("2").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stp_fill_or_kill_precheck() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 7, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 8, Side::SideSell, "1", "100"));
+
+        let mut taker = make_order(3, 7, Side::SideBuy, "1", "100");
+        taker.time_in_force = TimeInForceType::FillOrKill;
+        taker.stp = SelfTradePrevention::SelfTradePreventionCancelNewest;
+        assert!(!book.is_order_will_full_match(&taker));
+
+        taker.stp = SelfTradePrevention::SelfTradePreventionCancelOldest;
+        assert!(book.is_order_will_full_match(&taker));
+    }
+
+    fn make_stop_order(id: u64, side: Side, size: &str, price: &str, stop_price: &str) -> Order {
+        let mut order = make_order(id, 9, side, size, price);
+        order.r#type = OrderType::OrderTypeStopLimit;
+        order.stop_price = Decimal::from_str(stop_price).unwrap();
+        order
+    }
+
+    #[test]
+    fn test_stop_limit_waits_for_trigger() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "1", "100"));
+
+        // last trade is 100, a buy stop at 105 must not trigger yet, it is only acknowledged
+        let logs = to_json(&book.apply_order(&make_stop_order(3, Side::SideBuy, "1", "106", "105")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "received");
+        assert_eq!(logs[0]["stop_price"], "105");
+        assert!(book.stop_book.orders.contains_key(&3));
+        assert!(!book.bid_depths.orders.contains_key(&3));
+
+        // a trade at 105 releases it, it rests at its limit price
+        book.apply_order(&make_order(4, 1, Side::SideSell, "1", "105"));
+        let logs = to_json(&book.apply_order(&make_order(5, 2, Side::SideBuy, "1", "105")));
+
+        let trigger = logs
+            .iter()
+            .find(|l| l["base"]["type"] == "trigger")
+            .unwrap();
+        assert_eq!(trigger["order_id"], 3);
+        assert_eq!(trigger["order_type"], "stop_limit");
+        assert_eq!(logs.last().unwrap()["base"]["type"], "open");
+        assert!(book.stop_book.orders.is_empty());
+        assert!(book.bid_depths.orders.contains_key(&3));
+    }
+
+    #[test]
+    fn test_stop_order_time_in_force_checked() {
+        let book = OrderBook::new_order_book(&make_product());
+        let mut stop = make_stop_order(1, Side::SideBuy, "1", "106", "105");
+        stop.time_in_force = TimeInForceType::ImmediateOrCancel;
+        assert_eq!(book.check_order(&stop), Ok(()));
+
+        for time_in_force in [
+            TimeInForceType::GoodTillCrossing,
+            TimeInForceType::FillOrKill,
+            TimeInForceType::GoodTillTime,
+        ] {
+            stop.time_in_force = time_in_force;
+            stop.expire_time = 1;
+            assert_eq!(
+                book.check_order(&stop),
+                Err(RejectReason::RejectReasonInvalidTimeInForce)
+            );
+        }
+    }
+
+    #[test]
+    fn test_stop_order_cascade_and_cancel() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "1", "100"));
+        book.apply_order(&make_order(2, 1, Side::SideBuy, "1", "95"));
+        book.apply_order(&make_order(3, 2, Side::SideSell, "0.5", "100"));
+
+        // sell stops at 100 and 90: the first one sweeps to 95, which does not reach 90
+        let mut stop = make_stop_order(4, Side::SideSell, "1", "0", "100");
+        stop.r#type = OrderType::OrderTypeStop;
+        let logs = to_json(&book.apply_order(&stop));
+        assert_eq!(logs[0]["base"]["type"], "trigger");
+        assert_eq!(logs[1]["maker_order_id"], 1);
+
+        book.apply_order(&make_stop_order(5, Side::SideSell, "1", "80", "90"));
+        assert!(book.stop_book.orders.contains_key(&5));
+
+        let logs = to_json(&book.cancel_order(&make_stop_order(5, Side::SideSell, "1", "80", "90")));
+        assert_eq!(logs[0]["reason"], "cancelled");
+        assert!(book.stop_book.orders.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_restores_stop_orders() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_stop_order(1, Side::SideBuy, "1", "106", "105"));
+
+        let snapshot = book.snapshot();
+        let mut restored = OrderBook::new_order_book(&make_product());
+        restored.restore(&snapshot);
+        assert!(restored.stop_book.orders.contains_key(&1));
+        assert_eq!(restored.stop_book.buy_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_iceberg_shows_visible_size_only() {
+        let mut book = OrderBook::new_order_book(&make_product());
+
+        let mut iceberg = make_order(1, 1, Side::SideSell, "5", "100");
+        iceberg.display_size = Decimal::from_str("2").unwrap();
+        let logs = to_json(&book.apply_order(&iceberg));
+        assert_eq!(logs[0]["base"]["type"], "open");
+        assert_eq!(logs[0]["remaining_size"], "2");
+
+        let snapshot = book.snapshot();
+        assert_eq!(snapshot.orders[0].hidden_size, Decimal::from_str("3").unwrap());
+
+        // a taker larger than the slice keeps eating the replenished slices
+        let logs = to_json(&book.apply_order(&make_order(2, 2, Side::SideBuy, "3", "100")));
+        let matched: Vec<&serde_json::Value> = logs
+            .iter()
+            .filter(|l| l["base"]["type"] == "match")
+            .collect();
+        assert_eq!(matched.len(), 2);
+        assert_eq!(matched[0]["size"], "2");
+        assert_eq!(matched[1]["size"], "1");
+
+        let resting = book.ask_depths.orders.get(&1).unwrap();
+        assert_eq!(resting.size, Decimal::from_str("1").unwrap());
+        assert_eq!(resting.hidden_size, Decimal::from_str("1").unwrap());
+    }
+
+    /// Apply one log to an L3 book the way a market data consumer would.
+    fn apply_to_l3(l3: &mut L3Snapshot, log: &serde_json::Value) {
+        let decimal = |v: &serde_json::Value| Decimal::from_str(v.as_str().unwrap()).unwrap();
+        let remove = |l3: &mut L3Snapshot, order_id: u64| -> Option<L3Order> {
+            for orders in [&mut l3.bids, &mut l3.asks] {
+                if let Some(i) = orders.iter().position(|o| o.order_id == order_id) {
+                    return Some(orders.remove(i));
+                }
+            }
+            None
+        };
+        // a new or re-queued order goes behind every order at its price
+        let push = |l3: &mut L3Snapshot, side: &str, order: L3Order| {
+            let orders = if side == "buy" { &mut l3.bids } else { &mut l3.asks };
+            let at = orders
+                .iter()
+                .position(|o| match side {
+                    "buy" => o.price < order.price,
+                    _ => o.price > order.price,
+                })
+                .unwrap_or(orders.len());
+            orders.insert(at, order);
+        };
+
+        match log["base"]["type"].as_str().unwrap() {
+            "open" => {
+                let order_id = log["order_id"].as_u64().unwrap();
+                remove(l3, order_id);
+                let order = L3Order {
+                    order_id,
+                    price: decimal(&log["price"]),
+                    size: decimal(&log["remaining_size"]),
+                };
+                push(l3, log["side"].as_str().unwrap(), order);
+            }
+            "match" => {
+                for key in ["maker_order_id", "taker_order_id"] {
+                    let order_id = log[key].as_u64().unwrap();
+                    for orders in [&mut l3.bids, &mut l3.asks] {
+                        if let Some(o) = orders.iter_mut().find(|o| o.order_id == order_id) {
+                            o.size -= decimal(&log["size"]);
+                        }
+                        orders.retain(|o| !o.size.is_zero());
+                    }
+                }
+            }
+            "done" => {
+                remove(l3, log["order_id"].as_u64().unwrap());
+            }
+            "change" if log["reject_reason"].is_null() => {
+                let order_id = log["order_id"].as_u64().unwrap();
+                let (old_price, new_price) = (decimal(&log["old_price"]), decimal(&log["new_price"]));
+                let new_size = decimal(&log["new_size"]);
+                if old_price == new_price && new_size < decimal(&log["old_size"]) {
+                    // reduced in place, an iceberg gives up its reserve first
+                    for orders in [&mut l3.bids, &mut l3.asks] {
+                        if let Some(o) = orders.iter_mut().find(|o| o.order_id == order_id) {
+                            o.size = Decimal::min(o.size, new_size);
+                        }
+                    }
+                } else if remove(l3, order_id).is_some() {
+                    let order = L3Order {
+                        order_id,
+                        price: new_price,
+                        size: new_size,
+                    };
+                    push(l3, log["side"].as_str().unwrap(), order);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_l3_snapshot_follows_logs() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        let mut iceberg = make_order(1, 1, Side::SideSell, "4", "101");
+        iceberg.display_size = Decimal::from_str("1").unwrap();
+        book.apply_order(&iceberg);
+        book.apply_order(&make_order(2, 2, Side::SideSell, "1", "101"));
+        let mut l3 = book.l3_snapshot();
+
+        let mut stp_taker = make_order(6, 1, Side::SideBuy, "0.2", "100");
+        stp_taker.stp = SelfTradePrevention::SelfTradePreventionDecrementAndCancel;
+        let mut cancel = make_order(1, 1, Side::SideSell, "1", "100");
+        cancel.status = OrderStatus::OrderStatusCancelling;
+        let orders = vec![
+            // fills a slice of the iceberg, which shows its next one behind order 2
+            make_order(3, 3, Side::SideBuy, "2", "101"),
+            // re-queued at a new price with a new slice
+            make_amend(1, Side::SideSell, "2", "102"),
+            make_order(4, 4, Side::SideBuy, "1", "100"),
+            // crosses, trades and rests with a new slice
+            make_amend(1, Side::SideSell, "3", "100"),
+            // reduced in place from the reserve
+            make_amend(1, Side::SideSell, "1.5", "100"),
+            // decremented by self-trade prevention, from the reserve as well
+            stp_taker,
+            cancel,
+        ];
+        for order in orders {
+            let logs = match order.status {
+                OrderStatus::OrderStatusAmending => book.amend_order(&order),
+                OrderStatus::OrderStatusCancelling => book.cancel_order(&order),
+                _ => book.apply_order(&order),
+            };
+            for log in to_json(&logs) {
+                apply_to_l3(&mut l3, &log);
+            }
+
+            let next = book.l3_snapshot();
+            assert_eq!(l3.bids, next.bids, "bids after order {}", order.id);
+            assert_eq!(l3.asks, next.asks, "asks after order {}", order.id);
+        }
+    }
+
+    fn make_amend(id: u64, side: Side, size: &str, price: &str) -> Order {
+        let mut order = make_order(id, 1, side, size, price);
+        order.status = OrderStatus::OrderStatusAmending;
+        order
+    }
+
+    #[test]
+    fn test_amend_size_down_keeps_priority() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "5", "100"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "5", "100"));
+
+        let logs = to_json(&book.amend_order(&make_amend(1, Side::SideBuy, "3", "100")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "change");
+        assert_eq!(logs[0]["old_size"], "5");
+        assert_eq!(logs[0]["new_size"], "3");
+        assert_eq!(logs[0]["old_price"], logs[0]["new_price"]);
+
+        let ids: Vec<u64> = book.bid_depths.queue.values().copied().collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(
+            book.bid_depths.orders.get(&1).unwrap().size,
+            Decimal::from_str("3").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_amend_requeued_iceberg_shows_new_slice() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        let mut iceberg = make_order(1, 1, Side::SideSell, "4", "101");
+        iceberg.display_size = Decimal::from_str("1").unwrap();
+        book.apply_order(&iceberg);
+
+        let logs = to_json(&book.amend_order(&make_amend(1, Side::SideSell, "3", "102")));
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0]["base"]["type"], "change");
+        assert_eq!(logs[1]["base"]["type"], "open");
+        assert_eq!(logs[1]["remaining_size"], "1");
+        assert_eq!(logs[1]["price"], "102");
+
+        let resting = book.ask_depths.orders.get(&1).unwrap();
+        assert_eq!(resting.size, Decimal::from_str("1").unwrap());
+        assert_eq!(resting.hidden_size, Decimal::from_str("2").unwrap());
+    }
+
+    #[test]
+    fn test_amend_size_up_or_price_change_requeues() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "5", "100"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "5", "100"));
+        book.apply_order(&make_order(3, 3, Side::SideBuy, "5", "99"));
+
+        book.amend_order(&make_amend(1, Side::SideBuy, "6", "100"));
+        let ids: Vec<u64> = book.bid_depths.queue.values().copied().collect();
+        assert_eq!(ids, vec![2, 1, 3]);
+
+        book.amend_order(&make_amend(2, Side::SideBuy, "5", "99"));
+        let ids: Vec<u64> = book.bid_depths.queue.values().copied().collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_amend_crossing_price_trades() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "101"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "3", "100"));
+
+        let logs = to_json(&book.amend_order(&make_amend(2, Side::SideBuy, "3", "101")));
+        let types: Vec<&str> = logs
+            .iter()
+            .map(|l| l["base"]["type"].as_str().unwrap())
+            .collect();
+        // the order is open already, the change log is all that announces its new place
+        assert_eq!(types, vec!["change", "match", "done"]);
+        assert!(book.ask_depths.orders.is_empty());
+        assert_eq!(book.bid_depths.orders[&2].size, Decimal::from_str("2").unwrap());
+    }
+
+    #[test]
+    fn test_amend_refused_with_change_log() {
+        let mut book = OrderBook::new_order_book(&make_ruled_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "1", "100"));
+        book.apply_order(&make_stop_order(2, Side::SideBuy, "1", "106", "105"));
+
+        // off the tick size, the order keeps its size and price
+        let logs = to_json(&book.amend_order(&make_amend(1, Side::SideBuy, "1", "100.25")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["base"]["type"], "change");
+        assert_eq!(logs[0]["reject_reason"], "tick_size");
+        assert_eq!(logs[0]["new_price"], "100");
+        assert_eq!(book.bid_depths.orders[&1].price, Decimal::from_str("100").unwrap());
+
+        let logs = to_json(&book.amend_order(&make_amend(2, Side::SideBuy, "1", "106")));
+        assert_eq!(logs[0]["reject_reason"], "not_amendable");
+        let logs = to_json(&book.amend_order(&make_amend(3, Side::SideBuy, "1", "100")));
+        assert_eq!(logs[0]["reject_reason"], "unknown_order");
+    }
+
+    #[test]
+    fn test_amend_checked_as_resting_order() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "101"));
+        let mut post_only = make_order(2, 2, Side::SideBuy, "1", "100");
+        post_only.time_in_force = TimeInForceType::GoodTillCrossing;
+        book.apply_order(&post_only);
+
+        // the fields of the message other than price and size do not matter
+        let mut amend = make_amend(2, Side::SideBuy, "2", "100");
+        amend.time_in_force = TimeInForceType::GoodTillTime;
+        let logs = to_json(&book.amend_order(&amend));
+        assert!(logs[0]["reject_reason"].is_null());
+        assert_eq!(book.bid_depths.orders[&2].size, Decimal::from_str("2").unwrap());
+
+        // a post-only order is refused a price that would take liquidity
+        let logs = to_json(&book.amend_order(&make_amend(2, Side::SideBuy, "2", "101")));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["reject_reason"], "post_only");
+        assert_eq!(book.bid_depths.orders[&2].price, Decimal::from_str("100").unwrap());
+        assert_eq!(book.ask_depths.orders[&1].size, Decimal::from_str("1").unwrap());
+    }
+
+    fn make_market_order(id: u64, side: Side, size: &str, funds: &str) -> Order {
+        let mut order = make_order(id, 9, side, size, "0");
+        order.r#type = OrderType::OrderTypeMarket;
+        order.funds = Decimal::from_str(funds).unwrap();
+        order
+    }
+
+    #[test]
+    fn test_market_buy_by_size() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 1, Side::SideSell, "1", "101"));
+
+        let logs = to_json(&book.apply_order(&make_market_order(3, Side::SideBuy, "1.5", "0")));
+        assert_eq!(logs[0]["size"], "1");
+        assert_eq!(logs[2]["size"], "0.5");
+        assert_eq!(logs.last().unwrap()["reason"], "filled");
+        assert_eq!(
+            book.ask_depths.orders.get(&2).unwrap().size,
+            Decimal::from_str("0.5").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_market_sell_by_funds() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "1", "100"));
+        book.apply_order(&make_order(2, 1, Side::SideBuy, "1", "50"));
+
+        // receive 125 of quote: 1 @ 100 and 0.5 @ 50
+        let logs = to_json(&book.apply_order(&make_market_order(3, Side::SideSell, "0", "125")));
+        assert_eq!(logs[0]["size"], "1");
+        assert_eq!(logs[1]["base"]["type"], "done");
+        assert_eq!(logs[2]["size"], "0.500000");
+        assert_eq!(logs.last().unwrap()["reason"], "filled");
+    }
+
+    #[test]
+    fn test_market_order_cancelled_when_book_runs_out() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "1", "100"));
+
+        // size and funds both set: the size is the tighter bound but the book runs out first
+        let mut order = make_market_order(2, Side::SideSell, "2", "1000");
+        order.time_in_force = TimeInForceType::FillOrKill;
+        assert!(!book.is_order_will_full_match(&order));
+
+        let logs = to_json(&book.apply_order(&order));
+        assert_eq!(logs.last().unwrap()["reason"], "cancelled");
+    }
+
+    #[test]
+    fn test_market_fill_or_kill_by_funds() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+
+        let mut order = make_market_order(2, Side::SideBuy, "0", "100");
+        order.time_in_force = TimeInForceType::FillOrKill;
+        assert!(book.is_order_will_full_match(&order));
+
+        order.funds = Decimal::from_str("150").unwrap();
+        assert!(!book.is_order_will_full_match(&order));
+    }
+
+    fn make_ruled_product() -> Product {
+        Product {
+            tick_size: Decimal::from_str("0.5").unwrap(),
+            lot_size: Decimal::from_str("0.01").unwrap(),
+            min_size: Decimal::from_str("0.01").unwrap(),
+            max_size: Decimal::from_str("100").unwrap(),
+            min_notional: Decimal::from_str("10").unwrap(),
+            max_notional: Decimal::from_str("100000").unwrap(),
+            ..make_product()
+        }
+    }
+
+    #[test]
+    fn test_check_order_trading_rules() {
+        let book = OrderBook::new_order_book(&make_ruled_product());
+        let check = |order: &Order| book.check_order(order);
+
+        assert_eq!(check(&make_order(1, 1, Side::SideBuy, "1", "100.5")), Ok(()));
+        assert_eq!(
+            check(&make_order(1, 1, Side::SideBuy, "1", "100.25")),
+            Err(RejectReason::RejectReasonTickSize)
+        );
+        assert_eq!(
+            check(&make_order(1, 1, Side::SideBuy, "1.005", "100")),
+            Err(RejectReason::RejectReasonLotSize)
+        );
+        assert_eq!(
+            check(&make_order(1, 1, Side::SideBuy, "200", "100")),
+            Err(RejectReason::RejectReasonMaxSize)
+        );
+        assert_eq!(
+            check(&make_order(1, 1, Side::SideBuy, "0.05", "100")),
+            Err(RejectReason::RejectReasonMinNotional)
+        );
+        assert_eq!(
+            check(&make_order(1, 1, Side::SideBuy, "1", "0")),
+            Err(RejectReason::RejectReasonInvalidPrice)
+        );
+        assert_eq!(
+            check(&make_market_order(1, Side::SideBuy, "0", "5")),
+            Err(RejectReason::RejectReasonMinNotional)
+        );
+        assert_eq!(
+            check(&make_market_order(1, Side::SideBuy, "0", "0")),
+            Err(RejectReason::RejectReasonInvalidSize)
+        );
+        assert_eq!(
+            check(&make_stop_order(1, Side::SideBuy, "1", "100", "99.9")),
+            Err(RejectReason::RejectReasonTickSize)
+        );
+    }
+
+    #[test]
+    fn test_reject_order_does_not_touch_book() {
+        let mut book = OrderBook::new_order_book(&make_ruled_product());
+        let order = make_order(1, 1, Side::SideBuy, "1", "100.25");
+
+        let reason = book.check_order(&order).unwrap_err();
+        let logs = to_json(&book.reject_order(&order, &reason));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["reason"], "rejected");
+        assert_eq!(logs[0]["reject_reason"], "tick_size");
+        assert!(book.bid_depths.orders.is_empty());
+    }
+
+    fn make_banded_product() -> Product {
+        Product {
+            price_band: Decimal::from_str("10").unwrap(),
+            circuit_breaker: Decimal::from_str("5").unwrap(),
+            ..make_product()
+        }
+    }
+
+    #[test]
+    fn test_price_band_rejects_far_limit_orders() {
+        let mut book = OrderBook::new_order_book(&make_banded_product());
+
+        // no reference price before the first trade
+        assert_eq!(book.check_order(&make_order(1, 1, Side::SideSell, "1", "100")), Ok(()));
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "1", "100"));
+
+        assert_eq!(book.check_order(&make_order(3, 1, Side::SideBuy, "1", "110")), Ok(()));
+        assert_eq!(
+            book.check_order(&make_order(3, 1, Side::SideBuy, "1", "110.01")),
+            Err(RejectReason::RejectReasonPriceBand)
+        );
+        assert_eq!(
+            book.check_order(&make_order(3, 1, Side::SideSell, "1", "89.99")),
+            Err(RejectReason::RejectReasonPriceBand)
+        );
+    }
+
+    #[test]
+    fn test_price_band_rejects_far_triggered_stop_limit() {
+        let mut book = OrderBook::new_order_book(&make_banded_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "1", "100"));
+
+        // the limit price is not banded while the order waits for its trigger
+        let logs = to_json(&book.apply_order(&make_stop_order(3, Side::SideBuy, "1", "120", "101")));
+        assert_eq!(logs[0]["base"]["type"], "received");
+
+        // 120 is more than 10% away from the trade at 101 that triggers it
+        book.apply_order(&make_order(4, 1, Side::SideSell, "1", "101"));
+        let logs = to_json(&book.apply_order(&make_order(5, 2, Side::SideBuy, "1", "101")));
+        let types: Vec<&str> = logs
+            .iter()
+            .map(|l| l["base"]["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(types, vec!["match", "done", "done", "trigger", "done"]);
+        assert_eq!(logs[4]["order_id"], 3);
+        assert_eq!(logs[4]["reason"], "rejected");
+        assert_eq!(logs[4]["reject_reason"], "price_band");
+        assert!(book.stop_book.orders.is_empty());
+        assert!(!book.bid_depths.orders.contains_key(&3));
+    }
+
+    #[test]
+    fn test_circuit_breaker_halts_and_resumes() {
+        let mut book = OrderBook::new_order_book(&make_banded_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "1", "100"));
+        book.apply_order(&make_order(3, 1, Side::SideSell, "1", "101"));
+        book.apply_order(&make_order(4, 1, Side::SideSell, "1", "110"));
+
+        // within 5% of the last trade price
+        let logs = to_json(&book.apply_order(&make_market_order(5, Side::SideBuy, "1", "0")));
+        assert_eq!(logs[0]["base"]["type"], "match");
+        assert!(!book.is_halted());
+
+        // sweeping up to 110 is more than 5% away from 101
+        let logs = to_json(&book.apply_order(&make_market_order(6, Side::SideBuy, "1", "0")));
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0]["base"]["type"], "status");
+        assert_eq!(logs[0]["status"], "halted");
+        assert_eq!(logs[0]["trigger_price"], "110");
+        assert_eq!(logs[1]["reason"], "rejected");
+        assert_eq!(logs[1]["reject_reason"], "circuit_breaker");
+        assert!(book.ask_depths.orders.contains_key(&4));
+
+        assert_eq!(
+            book.check_order(&make_order(7, 2, Side::SideBuy, "1", "100")),
+            Err(RejectReason::RejectReasonHalted)
+        );
+        assert!(book.advance_time(1).is_empty());
+
+        // the halt runs out on the order time
+        let halted_until = book.halted_until;
+        assert!(book.advance_time(halted_until - 1).is_empty());
+        let logs = to_json(&book.advance_time(halted_until));
+        assert_eq!(logs[0]["status"], "continuous");
+        assert!(!book.is_halted());
+        assert_eq!(book.check_order(&make_order(7, 2, Side::SideBuy, "1", "100")), Ok(()));
+    }
+
+    fn make_control(id: u64, market_state: MarketState) -> Order {
+        let mut order = make_order(id, 0, Side::SideBuy, "0", "0");
+        order.status = OrderStatus::OrderStatusControl;
+        order.market_state = Some(market_state);
+        order
+    }
+
+    #[test]
+    fn test_market_state_control() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "100"));
+
+        let logs = to_json(&book.set_market_state(&make_control(2, MarketState::MarketStatePostOnly)));
+        assert_eq!(logs[0]["base"]["type"], "status");
+        assert_eq!(logs[0]["status"], "post_only");
+        // setting the same state again is not announced
+        assert!(book.set_market_state(&make_control(3, MarketState::MarketStatePostOnly)).is_empty());
+
+        assert_eq!(book.check_order(&make_order(4, 2, Side::SideBuy, "1", "99")), Ok(()));
+        assert_eq!(
+            book.check_order(&make_order(4, 2, Side::SideBuy, "1", "100")),
+            Err(RejectReason::RejectReasonPostOnly)
+        );
+        assert_eq!(
+            book.check_order(&make_market_order(4, Side::SideBuy, "1", "0")),
+            Err(RejectReason::RejectReasonPostOnly)
+        );
+
+        book.set_market_state(&make_control(5, MarketState::MarketStateCancelOnly));
+        assert_eq!(
+            book.check_order(&make_order(6, 2, Side::SideBuy, "1", "99")),
+            Err(RejectReason::RejectReasonCancelOnly)
+        );
+        let logs = to_json(&book.cancel_order(&make_order(1, 1, Side::SideSell, "1", "100")));
+        assert_eq!(logs[0]["reason"], "cancelled");
+
+        book.set_market_state(&make_control(7, MarketState::MarketStateHalted));
+        assert_eq!(
+            book.check_order(&make_order(8, 2, Side::SideBuy, "1", "99")),
+            Err(RejectReason::RejectReasonHalted)
+        );
+
+        let mut restored = OrderBook::new_order_book(&make_product());
+        restored.restore(&book.snapshot());
+        assert_eq!(restored.market_state(), MarketState::MarketStateHalted);
+    }
+
+    #[test]
+    fn test_control_ends_circuit_breaker_halt() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.halted_until = u64::MAX;
+        assert!(book.is_halted());
+
+        let logs = to_json(&book.set_market_state(&make_control(1, MarketState::MarketStateContinuous)));
+        assert_eq!(logs[0]["status"], "continuous");
+        assert!(!book.is_halted());
+    }
+
+    #[test]
+    fn test_auction_collects_then_uncrosses() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.set_market_state(&make_control(1, MarketState::MarketStateAuction));
+
+        // crossing orders rest instead of trading
+        let logs = to_json(&book.apply_order(&make_order(2, 1, Side::SideBuy, "2", "101")));
+        assert_eq!(logs[0]["base"]["type"], "open");
+        let logs = to_json(&book.apply_order(&make_order(3, 2, Side::SideSell, "1", "100")));
+        assert_eq!(logs[0]["base"]["type"], "open");
+        book.apply_order(&make_order(4, 2, Side::SideSell, "2", "102"));
+
+        let logs = to_json(&book.indicate_auction());
+        assert_eq!(logs[0]["base"]["type"], "auction");
+        assert_eq!(logs[0]["indicative_price"], "101");
+        assert_eq!(logs[0]["indicative_volume"], "1");
+        assert_eq!(logs[0]["imbalance"], "1");
+        // nothing changed, nothing to announce
+        assert!(book.indicate_auction().is_empty());
+
+        assert_eq!(
+            book.check_order(&make_market_order(5, Side::SideBuy, "1", "0")),
+            Err(RejectReason::RejectReasonAuction)
+        );
+
+        let logs = to_json(&book.set_market_state(&make_control(6, MarketState::MarketStateContinuous)));
+        assert_eq!(logs[0]["base"]["type"], "match");
+        assert_eq!(logs[0]["price"], "101");
+        assert_eq!(logs[0]["size"], "1");
+        assert_eq!(logs[0]["taker_order_id"], 3);
+        assert_eq!(logs[0]["maker_order_id"], 2);
+        assert_eq!(logs[1]["base"]["type"], "done");
+        assert_eq!(logs[1]["order_id"], 3);
+        assert_eq!(logs.last().unwrap()["status"], "continuous");
+
+        assert_eq!(
+            book.bid_depths.orders.get(&2).unwrap().size,
+            Decimal::from_str("1").unwrap()
+        );
+        assert!(book.ask_depths.orders.contains_key(&4));
+    }
+
+    #[test]
+    fn test_auction_uncrosses_after_halt_and_releases_stops() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.set_market_state(&make_control(1, MarketState::MarketStateAuction));
+        book.apply_order(&make_order(2, 1, Side::SideBuy, "2", "101"));
+        book.apply_order(&make_order(3, 2, Side::SideSell, "1", "100"));
+        book.apply_order(&make_order(4, 2, Side::SideSell, "2", "102"));
+        book.apply_order(&make_stop_order(5, Side::SideBuy, "1", "102", "101"));
+
+        // halting does not uncross, the collected orders wait
+        let logs = to_json(&book.set_market_state(&make_control(6, MarketState::MarketStateHalted)));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["status"], "halted");
+        assert!(book.ask_depths.orders.contains_key(&3));
+
+        // trading again uncrosses, the stop released by it trades in the new state
+        let logs = to_json(&book.set_market_state(&make_control(7, MarketState::MarketStateContinuous)));
+        let types: Vec<&str> = logs.iter().map(|l| l["base"]["type"].as_str().unwrap()).collect();
+        assert_eq!(types, vec!["match", "done", "trigger", "match", "done", "status"]);
+        assert_eq!(logs[0]["taker_order_id"], 3);
+        assert_eq!(logs[0]["price"], "101");
+        assert_eq!(logs[3]["taker_order_id"], 5);
+        assert_eq!(logs[3]["maker_order_id"], 4);
+        assert_eq!(logs[3]["price"], "102");
+        assert!(!book.bid_depths.orders.contains_key(&5));
+        assert_eq!(logs[5]["status"], "continuous");
+    }
+
+    #[test]
+    fn test_auction_uncross_prevents_self_trade() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.set_market_state(&make_control(1, MarketState::MarketStateAuction));
+        book.apply_order(&make_order(2, 7, Side::SideBuy, "1", "101"));
+        let mut later = make_order(3, 7, Side::SideSell, "3", "100");
+        later.stp = SelfTradePrevention::SelfTradePreventionDecrementAndCancel;
+        book.apply_order(&later);
+        book.apply_order(&make_order(4, 8, Side::SideBuy, "1", "101"));
+
+        // the later order of user 7 decides, its smaller bid is cancelled and the ask
+        // is decremented by that size before it trades with the other user
+        let logs = to_json(&book.set_market_state(&make_control(5, MarketState::MarketStateContinuous)));
+        let types: Vec<&str> = logs.iter().map(|l| l["base"]["type"].as_str().unwrap()).collect();
+        assert_eq!(types, vec!["done", "change", "match", "done", "status"]);
+        assert_eq!(logs[0]["order_id"], 2);
+        assert_eq!(logs[0]["reason"], "stp");
+        assert_eq!(logs[1]["order_id"], 3);
+        assert_eq!(logs[1]["new_size"], "2");
+        assert_eq!(logs[2]["taker_order_id"], 4);
+        assert_eq!(logs[2]["maker_order_id"], 3);
+        assert_eq!(logs[2]["size"], "1");
+
+        assert!(book.bid_depths.orders.is_empty());
+        assert_eq!(
+            book.ask_depths.orders.get(&3).unwrap().size,
+            Decimal::from_str("1").unwrap()
+        );
+    }
+
+    fn make_gtt_order(id: u64, side: Side, price: &str, expire_time: u64) -> Order {
+        let mut order = make_order(id, 1, side, "1", price);
+        order.time_in_force = TimeInForceType::GoodTillTime;
+        order.expire_time = expire_time;
+        order
+    }
+
+    #[test]
+    fn test_gtt_orders_expire_on_order_time() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.advance_time(1000);
+        book.apply_order(&make_gtt_order(1, Side::SideBuy, "99", 2000));
+        book.apply_order(&make_gtt_order(2, Side::SideBuy, "98", 3000));
+        book.apply_order(&make_gtt_order(3, Side::SideBuy, "97", 3000));
+        book.cancel_order(&make_order(3, 1, Side::SideBuy, "1", "97"));
+
+        assert!(book.advance_time(1999).is_empty());
+        let logs = to_json(&book.advance_time(2000));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["order_id"], 1);
+        assert_eq!(logs[0]["reason"], "expired");
+        assert_eq!(logs[0]["remaining_size"], "1");
+
+        // time does not go backwards, and the cancelled order is not expired again
+        assert!(book.advance_time(1500).is_empty());
+        let logs = to_json(&book.advance_time(5000));
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["order_id"], 2);
+        assert!(book.bid_depths.orders.is_empty());
+
+        // already expired when it arrives
+        let logs = to_json(&book.apply_order(&make_gtt_order(4, Side::SideBuy, "99", 4000)));
+        assert_eq!(logs[0]["reason"], "expired");
+        assert!(book.bid_depths.orders.is_empty());
+    }
+
+    #[test]
+    fn test_expiry_index_forgets_done_orders() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.advance_time(1000);
+        book.apply_order(&make_gtt_order(1, Side::SideBuy, "99", 2000));
+        book.apply_order(&make_gtt_order(2, Side::SideBuy, "98", 2000));
+        book.apply_order(&make_gtt_order(3, Side::SideBuy, "97", 2000));
+        assert_eq!(book.expiry_index.len(), 3);
+
+        // filled, cancelled and amended orders leave nothing stale behind
+        book.apply_order(&make_order(4, 2, Side::SideSell, "1", "99"));
+        book.cancel_order(&make_order(2, 1, Side::SideBuy, "1", "98"));
+        book.amend_order(&make_amend(3, Side::SideBuy, "2", "96"));
+        assert_eq!(book.expiry_index.len(), 1);
+        assert_eq!(to_json(&book.advance_time(2000))[0]["order_id"], 3);
+        assert!(book.expiry_index.is_empty());
+    }
+
+    #[test]
+    fn test_clock_time_clamped_to_id_time() {
+        let created_at = 1695783003020967000u64;
+        let id = (created_at / 1_000_000 - SNOWFLAKE_EPOCH as u64) << 22;
+        let mut order = make_order(id, 1, Side::SideBuy, "1", "100");
+        order.created_at = created_at;
+        assert_eq!(OrderBook::clock_time(&order), created_at);
+
+        // a timestamp from far in the future only moves the clock up to the skew
+        order.created_at = created_at * 2;
+        let id_time = created_at / 1_000_000 * 1_000_000;
+        assert_eq!(OrderBook::clock_time(&order), id_time + MAX_CLOCK_SKEW);
+    }
+
+    #[test]
+    fn test_clock_prunes_time_window() {
+        let created_at = 1695783003020967000u64;
+        let id = (created_at / 1_000_000 - SNOWFLAKE_EPOCH as u64) << 22;
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.advance_time(created_at);
+        book.apply_order(&make_order(id, 1, Side::SideBuy, "1", "100"));
+        assert_eq!(book.time_window.size(), 1);
+
+        // only the order clock prunes the window, a tick past its duration leaves it empty
+        book.advance_time(created_at + 29_000_000_000);
+        assert_eq!(book.time_window.size(), 1);
+        book.advance_time(created_at + 31_000_000_000);
+        assert_eq!(book.time_window.size(), 0);
+    }
+
+    #[test]
+    fn test_gtd_expires_at_end_of_day_and_survives_restore() {
+        const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+        let mut book = OrderBook::new_order_book(&make_product());
+        let mut order = make_gtt_order(1, Side::SideSell, "100", DAY + 5);
+        order.time_in_force = TimeInForceType::GoodTillDate;
+        book.apply_order(&order);
+        assert_eq!(book.ask_depths.orders.get(&1).unwrap().expire_time, 2 * DAY);
+
+        let mut restored = OrderBook::new_order_book(&make_product());
+        restored.restore(&book.snapshot());
+        assert!(restored.advance_time(2 * DAY - 1).is_empty());
+        assert_eq!(restored.advance_time(2 * DAY).len(), 1);
+
+        order.expire_time = 0;
+        assert_eq!(
+            book.check_order(&order),
+            Err(RejectReason::RejectReasonInvalidExpireTime)
+        );
+    }
+
+    #[test]
+    fn test_replay_from_snapshot_is_byte_identical() {
+        let orders: Vec<Order> = (1..=6)
+            .map(|i| {
+                let side = if i % 2 == 0 { Side::SideBuy } else { Side::SideSell };
+                let mut order = make_order(i, i, side, "1", "100");
+                order.created_at = 1_700_000_000_000_000_000 + i * 1_000_000;
+                order
+            })
+            .collect();
+        let run = |book: &mut OrderBook, orders: &[Order]| -> String {
+            let mut out = String::new();
+            for order in orders {
+                let mut logs = book.advance_time(order.created_at);
+                logs.extend(book.apply_order(order));
+                for log in logs {
+                    out.push_str(&serde_json::to_string(&log).unwrap());
+                }
+            }
+            out
+        };
+
+        let mut book = OrderBook::new_order_book(&make_product());
+        run(&mut book, &orders[..2]);
+        let snapshot = book.snapshot();
+        let original = run(&mut book, &orders[2..]);
+
+        let mut replayed = OrderBook::new_order_book(&make_product());
+        replayed.restore(&snapshot);
+        assert_eq!(run(&mut replayed, &orders[2..]), original);
+        assert!(original.contains("\"time\":1700000000003000000"));
+    }
+
+    #[test]
+    fn test_delta_chain_restores_book() {
+        let sorted = |snapshot: &OrderBookSnapshot| -> String {
+            let mut snapshot = snapshot.clone();
+            snapshot.orders.sort_by_key(|o| o.order_id);
+            snapshot.stop_orders.sort_by_key(|o| o.order_id);
+            serde_json::to_string(&snapshot).unwrap()
+        };
+
+        let mut book = OrderBook::new_order_book(&make_product());
+        for i in 1..=4 {
+            book.apply_order(&make_order(i, i, Side::SideSell, "1", &(100 + i).to_string()));
+        }
+        let mut chain = book.snapshot();
+        book.clear_changes();
+
+        // a partial fill, a full fill, a new order and a cancel
+        book.apply_order(&make_order(5, 5, Side::SideBuy, "1.5", "102"));
+        book.apply_order(&make_order(6, 6, Side::SideBuy, "1", "90"));
+        let delta = book.delta();
+        assert_eq!(delta.removed, vec![1]);
+        assert_eq!(delta.orders.len(), 2);
+        chain.apply_delta(&delta);
+        book.clear_changes();
+
+        let mut cancel = make_order(4, 4, Side::SideSell, "1", "104");
+        cancel.status = OrderStatus::OrderStatusCancelling;
+        book.cancel_order(&cancel);
+        let mut stop = make_order(7, 7, Side::SideBuy, "1", "110");
+        stop.r#type = OrderType::OrderTypeStop;
+        stop.stop_price = Decimal::from_str("105").unwrap();
+        book.apply_order(&stop);
+        chain.apply_delta(&book.delta());
+
+        assert_eq!(sorted(&chain), sorted(&book.snapshot()));
+
+        let mut restored = OrderBook::new_order_book(&make_product());
+        restored.restore(&chain);
+        assert_eq!(sorted(&restored.snapshot()), sorted(&book.snapshot()));
+        assert!(restored.delta().orders.is_empty());
+    }
+
+    #[test]
+    fn test_price_levels_and_l2update() {
+        let product = Product {
+            l2_update: true,
+            ..make_product()
+        };
+        let mut book = OrderBook::new_order_book(&product);
+        book.apply_order(&make_order(1, 1, Side::SideSell, "1", "101"));
+        book.apply_order(&make_order(2, 2, Side::SideSell, "2", "101"));
+        book.apply_order(&make_order(3, 3, Side::SideSell, "1", "102"));
+        book.apply_order(&make_order(4, 4, Side::SideBuy, "1", "99"));
+        book.l2_update();
+
+        let (bids, asks) = book.top_levels(1);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(asks[0].price, Decimal::from_str("101").unwrap());
+        assert_eq!(asks[0].size, Decimal::from_str("3").unwrap());
+        assert_eq!(asks[0].count, 2);
+
+        // a buy taking the whole 101 level and part of 102
+        book.apply_order(&make_order(5, 5, Side::SideBuy, "3.5", "102"));
+        let logs = to_json(&book.l2_update());
+        assert_eq!(logs[0]["base"]["type"], "l2update");
+        assert_eq!(logs[0]["asks"][0]["price"], "101");
+        assert_eq!(logs[0]["asks"][0]["count"], 0);
+        assert_eq!(logs[0]["asks"][1]["size"], "0.5");
+        assert!(logs[0]["bids"].as_array().unwrap().is_empty());
+
+        // nothing changed since
+        assert!(book.l2_update().is_empty());
+        let (_, asks) = book.top_levels(5);
+        assert_eq!(asks.len(), 1);
+        assert_eq!(asks[0].count, 1);
+    }
+
+    #[test]
+    fn test_l3_snapshot_in_queue_order() {
+        let mut book = OrderBook::new_order_book(&make_product());
+        book.apply_order(&make_order(1, 1, Side::SideBuy, "1", "99"));
+        book.apply_order(&make_order(2, 2, Side::SideBuy, "2", "100"));
+        book.apply_order(&make_order(3, 3, Side::SideBuy, "3", "100"));
+        book.apply_order(&make_order(4, 4, Side::SideSell, "1", "102"));
+        book.apply_order(&make_order(5, 5, Side::SideSell, "1", "101"));
+
+        let l3 = book.l3_snapshot();
+        assert_eq!(l3.log_seq, book.log_seq);
+        let bids: Vec<u64> = l3.bids.iter().map(|o| o.order_id).collect();
+        let asks: Vec<u64> = l3.asks.iter().map(|o| o.order_id).collect();
+        assert_eq!(bids, vec![2, 3, 1]);
+        assert_eq!(asks, vec![5, 4]);
+        assert_eq!(l3.bids[1].size, Decimal::from_str("3").unwrap());
+    }
+
+    #[test]
+    fn test_match_fees() {
+        let product = Product {
+            maker_fee_bps: Decimal::from_str("-1").unwrap(),
+            taker_fee_bps: Decimal::from_str("5").unwrap(),
+            ..make_product()
+        };
+        let mut book = OrderBook::new_order_book(&product);
+        book.apply_order(&make_order(1, 1, Side::SideSell, "2", "1000.05"));
+
+        let logs = to_json(&book.apply_order(&make_order(2, 2, Side::SideBuy, "2", "1000.05")));
+        assert_eq!(logs[0]["base"]["type"], "match");
+        assert_eq!(logs[0]["notional"], "2000.10");
+        assert_eq!(logs[0]["maker_fee"], "-0.20");
+        assert_eq!(logs[0]["taker_fee"], "1.00");
+
+        // the tiers of the orders override the product, the maker keeps its own while resting
+        let mut maker = make_order(3, 1, Side::SideSell, "1", "1000");
+        maker.maker_fee_bps = Some(Decimal::from_str("2").unwrap());
+        maker.taker_fee_bps = Some(Decimal::ZERO);
+        book.apply_order(&maker);
+        let mut taker = make_order(4, 2, Side::SideBuy, "1", "1000");
+        taker.maker_fee_bps = Some(Decimal::ZERO);
+        taker.taker_fee_bps = Some(Decimal::from_str("0.25").unwrap());
+        let logs = to_json(&book.apply_order(&taker));
+        assert_eq!(logs[0]["notional"], "1000.00");
+        assert_eq!(logs[0]["maker_fee"], "0.20");
+        // 0.025 rounds half away from zero
+        assert_eq!(logs[0]["taker_fee"], "0.03");
+
+        // the fee comes from the exact notional 1.996, not from the rounded 2.00
+        book.apply_order(&make_order(5, 1, Side::SideSell, "1.996", "1"));
+        let mut taker = make_order(6, 2, Side::SideBuy, "1.996", "1");
+        taker.taker_fee_bps = Some(Decimal::from_str("25").unwrap());
+        let logs = to_json(&book.apply_order(&taker));
+        assert_eq!(logs[0]["notional"], "2.00");
+        assert_eq!(logs[0]["taker_fee"], "0.00");
+    }
+}
@@ -5,28 +5,48 @@ use std::cmp::Ordering;
 
 pub trait OrderingTrait {
     fn new(price: &Decimal, order_id: u64) -> Self;
+    // priority breaks ties at the same price, it is the order id unless the order was re-queued
+    fn with_priority(price: &Decimal, priority: u64, order_id: u64) -> Self;
+    fn price(&self) -> &Decimal;
+    fn priority(&self) -> u64;
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct PriceOrderIdKeyAsc {
     pub price: Decimal,
+    pub priority: u64,
     pub order_id: u64,
 }
 
 impl OrderingTrait for PriceOrderIdKeyAsc {
     fn new(price: &Decimal, order_id: u64) -> Self {
+        Self::with_priority(price, order_id, order_id)
+    }
+
+    fn with_priority(price: &Decimal, priority: u64, order_id: u64) -> Self {
         PriceOrderIdKeyAsc {
             price: *price,
+            priority,
             order_id,
         }
     }
+
+    fn price(&self) -> &Decimal {
+        &self.price
+    }
+
+    fn priority(&self) -> u64 {
+        self.priority
+    }
 }
 
 impl Eq for PriceOrderIdKeyAsc {}
 
 impl PartialEq<Self> for PriceOrderIdKeyAsc {
     fn eq(&self, other: &Self) -> bool {
-        self.price.eq(&other.price) && self.order_id.eq(&other.order_id)
+        self.price.eq(&other.price)
+            && self.priority.eq(&other.priority)
+            && self.order_id.eq(&other.order_id)
     }
 }
 
@@ -41,10 +61,10 @@ impl Ord for PriceOrderIdKeyAsc {
         match self.price.cmp(&other.price) {
             Ordering::Less => Ordering::Less,
             Ordering::Greater => Ordering::Greater,
-            Ordering::Equal => match self.order_id.cmp(&other.order_id) {
+            Ordering::Equal => match self.priority.cmp(&other.priority) {
                 Ordering::Less => Ordering::Less,
                 Ordering::Greater => Ordering::Greater,
-                Ordering::Equal => Ordering::Equal,
+                Ordering::Equal => self.order_id.cmp(&other.order_id),
             },
         }
     }
@@ -53,23 +73,39 @@ impl Ord for PriceOrderIdKeyAsc {
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct PriceOrderIdKeyDesc {
     pub price: Decimal,
+    pub priority: u64,
     pub order_id: u64,
 }
 
 impl OrderingTrait for PriceOrderIdKeyDesc {
     fn new(price: &Decimal, order_id: u64) -> Self {
+        Self::with_priority(price, order_id, order_id)
+    }
+
+    fn with_priority(price: &Decimal, priority: u64, order_id: u64) -> Self {
         PriceOrderIdKeyDesc {
             price: *price,
+            priority,
             order_id,
         }
     }
+
+    fn price(&self) -> &Decimal {
+        &self.price
+    }
+
+    fn priority(&self) -> u64 {
+        self.priority
+    }
 }
 
 impl Eq for PriceOrderIdKeyDesc {}
 
 impl PartialEq<Self> for PriceOrderIdKeyDesc {
     fn eq(&self, other: &Self) -> bool {
-        self.price.eq(&other.price) && self.order_id.eq(&other.order_id)
+        self.price.eq(&other.price)
+            && self.priority.eq(&other.priority)
+            && self.order_id.eq(&other.order_id)
     }
 }
 
@@ -84,10 +120,10 @@ impl Ord for PriceOrderIdKeyDesc {
         match self.price.cmp(&other.price) {
             Ordering::Less => Ordering::Greater,
             Ordering::Greater => Ordering::Less,
-            Ordering::Equal => match self.order_id.cmp(&other.order_id) {
+            Ordering::Equal => match self.priority.cmp(&other.priority) {
                 Ordering::Less => Ordering::Less,
                 Ordering::Greater => Ordering::Greater,
-                Ordering::Equal => Ordering::Equal,
+                Ordering::Equal => self.order_id.cmp(&other.order_id),
             },
         }
     }
@@ -1,17 +1,30 @@
 use redis::Client;
 use redis::AsyncCommands;
+use redis::aio::MultiplexedConnection;
+use std::collections::HashMap;
 use std::result::Result;
 
 use crate::matching::engine::Snapshot;
+use crate::matching::store::{open_snapshot, seal_snapshot, SnapshotInfo, SnapshotStore};
 use crate::utils::error::CustomError;
 use crate::utils::redis::new_redis_client;
 
 const TOPIC_SNAPSHOT_PREFIX: &str = "matching_snapshot_";
 
+/// Keeps the last `retention` snapshots of a product with the chains they build on, or all
+/// if 0. Each one is sealed with a checksum
+/// in `{snapshot_key}:{order_offset}-{log_seq}`, and the sorted set `{snapshot_key}:history`
+/// indexes them by order offset.
 pub struct RedisSnapshotStore {
     pub product_id: String,
+    // holds a single unsealed snapshot in stores written before the history, still read
+    // until the first new snapshot is stored
     pub snapshot_key: String,
+    pub history_key: String,
+    pub retention: usize,
     pub redis_client: Client,
+    // the base of each snapshot stored or loaded, so pruning does not load them again
+    bases: HashMap<SnapshotInfo, Option<SnapshotInfo>>,
 }
 
 impl RedisSnapshotStore {
@@ -19,33 +32,36 @@ impl RedisSnapshotStore {
         product_id: &str,
         ip: &str,
         port: u16,
+        retention: usize,
     ) -> Result<RedisSnapshotStore, CustomError> {
         match new_redis_client(ip, port).await {
-            Ok(c) => Ok(RedisSnapshotStore {
-                product_id: product_id.to_string(),
-                snapshot_key: [TOPIC_SNAPSHOT_PREFIX, product_id].join(""),
-                redis_client: c,
-            }),
+            Ok(c) => {
+                let snapshot_key = [TOPIC_SNAPSHOT_PREFIX, product_id].join("");
+                Ok(RedisSnapshotStore {
+                    product_id: product_id.to_string(),
+                    history_key: [snapshot_key.as_str(), ":history"].join(""),
+                    snapshot_key,
+                    retention,
+                    redis_client: c,
+                    bases: HashMap::new(),
+                })
+            }
             Err(e) => Err(CustomError::from_string(format!("{}", e))),
         }
     }
 
-    pub async fn store(&mut self, snapshot: &Snapshot) -> Result<(), CustomError> {
-        let s = serde_json::to_string(snapshot)
-            .map_err(|e| CustomError::new(&e))?;
-
-        let mut conn = self.redis_client.get_multiplexed_async_connection().await
-            .map_err(|e| CustomError::from_string(format!("{}", e)))?;
-
-        conn.set::<_, _, ()>(&self.snapshot_key, &s).await
-            .map_err(|e| CustomError::from_string(format!("{}", e)))?;
+    fn entry_key(&self, info: &SnapshotInfo) -> String {
+        format!("{}:{}", self.snapshot_key, info.id())
+    }
 
-        Ok(())
+    async fn connection(&self) -> Result<MultiplexedConnection, CustomError> {
+        self.redis_client.get_multiplexed_async_connection().await
+            .map_err(|e| CustomError::from_string(format!("{}", e)))
     }
 
-    pub async fn get_latest(&mut self) -> Result<Option<Snapshot>, CustomError> {
-        let mut conn = self.redis_client.get_multiplexed_async_connection().await
-            .map_err(|e| CustomError::from_string(format!("{}", e)))?;
+    /// The snapshot under the key used before the history, if there is one.
+    async fn get_unsealed(&self) -> Result<Option<Snapshot>, CustomError> {
+        let mut conn = self.connection().await?;
 
         let result: Option<String> = conn.get(&self.snapshot_key).await
             .map_err(|e| CustomError::from_string(format!("{}", e)))?;
@@ -60,3 +76,78 @@ impl RedisSnapshotStore {
         }
     }
 }
+
+impl SnapshotStore for RedisSnapshotStore {
+    async fn store(&mut self, snapshot: &Snapshot) -> Result<(), CustomError> {
+        let s = seal_snapshot(snapshot)?;
+        let info = SnapshotInfo::of(snapshot);
+
+        let mut conn = self.connection().await?;
+
+        redis::pipe()
+            .atomic()
+            .set(self.entry_key(&info), &s)
+            .ignore()
+            .zadd(&self.history_key, info.id(), info.order_offset)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|e| CustomError::from_string(format!("{}", e)))?;
+
+        self.bases.insert(info, snapshot.base.clone());
+
+        // drop the oldest chains past the retention
+        let expired = self.expired(self.retention).await?;
+        if !expired.is_empty() {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for info in &expired {
+                pipe.del(self.entry_key(info)).ignore();
+                self.bases.remove(info);
+            }
+            let ids: Vec<String> = expired.iter().map(SnapshotInfo::id).collect();
+            pipe.zrem(&self.history_key, &ids).ignore();
+            pipe.query_async::<()>(&mut conn)
+                .await
+                .map_err(|e| CustomError::from_string(format!("{}", e)))?;
+        }
+
+        Ok(())
+    }
+
+    async fn list(&mut self) -> Result<Vec<SnapshotInfo>, CustomError> {
+        let mut conn = self.connection().await?;
+
+        let ids: Vec<String> = conn.zrevrange(&self.history_key, 0, -1).await
+            .map_err(|e| CustomError::from_string(format!("{}", e)))?;
+        if ids.is_empty() {
+            return Ok(self.get_unsealed().await?.iter().map(SnapshotInfo::of).collect());
+        }
+
+        Ok(ids.iter().filter_map(|id| SnapshotInfo::from_id(id)).collect())
+    }
+
+    async fn get(&mut self, info: &SnapshotInfo) -> Result<Snapshot, CustomError> {
+        let mut conn = self.connection().await?;
+
+        let result: Option<String> = conn.get(self.entry_key(info)).await
+            .map_err(|e| CustomError::from_string(format!("{}", e)))?;
+
+        let snapshot = match result {
+            Some(s) => open_snapshot(&s)?,
+            None => match self.get_unsealed().await? {
+                Some(snapshot) if SnapshotInfo::of(&snapshot) == *info => snapshot,
+                _ => return Err(CustomError::from_string(format!("snapshot {} not found", info.id()))),
+            },
+        };
+        self.bases.insert(info.clone(), snapshot.base.clone());
+        Ok(snapshot)
+    }
+
+    async fn base_of(&mut self, info: &SnapshotInfo) -> Result<Option<SnapshotInfo>, CustomError> {
+        match self.bases.get(info) {
+            Some(base) => Ok(base.clone()),
+            None => Ok(self.get(info).await?.base),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs;
+use std::result::Result;
+
+use serde_json::Value;
+
+use crate::matching::engine::{Engine, Snapshot};
+use crate::matching::file_store::decode_snapshot_file;
+use crate::matching::store::open_snapshot;
+use crate::models::models::Order;
+use crate::utils::error::CustomError;
+
+/// The first place where a replay and a captured log dump disagree. `line` counts from 1
+/// over the compared logs, and a missing side means one of them ran out first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+/// Read a full snapshot, either a file of the file snapshot store, sealed as in a Redis
+/// snapshot history or as plain JSON.
+pub fn read_snapshot(path: &str) -> Result<Snapshot, CustomError> {
+    let snapshot = read_any_snapshot(path)?;
+    if snapshot.is_delta() {
+        return Err(CustomError::from_string(format!(
+            "{} is a delta snapshot, restore it from its store",
+            path
+        )));
+    }
+    Ok(snapshot)
+}
+
+fn read_any_snapshot(path: &str) -> Result<Snapshot, CustomError> {
+    let bytes = fs::read(path).map_err(|e| CustomError::new(&e))?;
+    if let Ok(snapshot) = decode_snapshot_file(&bytes) {
+        return Ok(snapshot);
+    }
+    let s = String::from_utf8(bytes).map_err(|e| CustomError::new(&e))?;
+    match open_snapshot(&s) {
+        Ok(snapshot) => Ok(snapshot),
+        Err(_) => serde_json::from_str(&s).map_err(|e| CustomError::new(&e)),
+    }
+}
+
+/// Read one JSON value per line, skipping blank lines.
+fn read_lines<T: serde::de::DeserializeOwned>(path: &str) -> Result<Vec<T>, CustomError> {
+    let s = fs::read_to_string(path).map_err(|e| CustomError::new(&e))?;
+    let mut values: Vec<T> = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value = serde_json::from_str(line)
+            .map_err(|e| CustomError::from_string(format!("{}:{}: {}", path, i + 1, e)))?;
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Read orders in the format of the order topic, one per line.
+pub fn read_orders(path: &str) -> Result<Vec<Order>, CustomError> {
+    read_lines(path)
+}
+
+/// Read a `matching_message_*` dump, one log per line.
+pub fn read_logs(path: &str) -> Result<Vec<Value>, CustomError> {
+    read_lines(path)
+}
+
+/// Apply the orders in sequence and return every log they produce, serialized the way
+/// they are sent to the log topic.
+pub fn replay(engine: &mut Engine, orders: &[Order]) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for order in orders {
+        for log in engine.apply(order) {
+            lines.push(serde_json::to_string(&log).unwrap());
+        }
+    }
+    lines
+}
+
+/// Compare replayed logs with a captured dump. Logs in the dump with a sequence at or
+/// below `after_seq` were written before the snapshot and are skipped, the same way the
+/// committer discards logs it already stored. Logs are compared as JSON values so the
+/// field order of the dump does not matter.
+pub fn find_divergence(actual: &[String], expected: &[Value], after_seq: u64) -> Option<Divergence> {
+    let expected: Vec<&Value> = expected
+        .iter()
+        .filter(|v| v["base"]["sequence"].as_u64().unwrap_or(0) > after_seq)
+        .collect();
+
+    for i in 0..usize::max(actual.len(), expected.len()) {
+        let a: Option<Value> = actual.get(i).map(|s| serde_json::from_str(s).unwrap());
+        let e: Option<&Value> = expected.get(i).copied();
+        if a.as_ref() != e {
+            return Some(Divergence {
+                line: i + 1,
+                expected: e.map(|v| v.to_string()),
+                actual: actual.get(i).cloned(),
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::matching::replay::find_divergence;
+
+    fn log(seq: u64, size: &str) -> String {
+        format!(r#"{{"base":{{"type":"open","sequence":{},"product_id":"BTC-USD","time":0}},"remaining_size":"{}"}}"#, seq, size)
+    }
+
+    fn parse(lines: &[String]) -> Vec<Value> {
+        lines.iter().map(|s| serde_json::from_str(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_find_divergence() {
+        let actual = vec![log(3, "1"), log(4, "2")];
+
+        // logs before the snapshot are skipped, field order is ignored
+        let mut expected = parse(&[log(2, "9"), log(3, "1"), log(4, "2")]);
+        expected[1] = serde_json::from_str(r#"{"remaining_size":"1","base":{"time":0,"product_id":"BTC-USD","sequence":3,"type":"open"}}"#).unwrap();
+        assert_eq!(find_divergence(&actual, &expected, 2), None);
+
+        let expected = parse(&[log(3, "1"), log(4, "3")]);
+        let d = find_divergence(&actual, &expected, 2).unwrap();
+        assert_eq!(d.line, 2);
+        assert_eq!(d.actual, Some(log(4, "2")));
+
+        // the dump has a log the replay did not produce
+        let expected = parse(&[log(3, "1"), log(4, "2"), log(5, "1")]);
+        let d = find_divergence(&actual, &expected, 2).unwrap();
+        assert_eq!(d.line, 3);
+        assert_eq!(d.actual, None);
+        assert!(d.expected.is_some());
+    }
+}
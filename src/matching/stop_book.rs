@@ -0,0 +1,92 @@
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::matching::order_book::BookOrder;
+use crate::matching::ordering::{OrderingTrait, PriceOrderIdKeyAsc, PriceOrderIdKeyDesc};
+use crate::models::types::Side;
+
+/// StopBook holds stop and stop-limit orders until the last trade price crosses their
+/// stop price. Orders are keyed by stop price rather than limit price.
+#[derive(Default)]
+pub struct StopBook {
+    pub orders: HashMap<u64, BookOrder>,
+    // buy stops trigger when the price rises, lowest stop price first
+    pub buy_queue: BTreeMap<PriceOrderIdKeyAsc, u64>,
+    // sell stops trigger when the price falls, highest stop price first
+    pub sell_queue: BTreeMap<PriceOrderIdKeyDesc, u64>,
+    // orders added or removed since the last snapshot, see OrderBook::delta
+    pub changed: HashSet<u64>,
+}
+
+impl StopBook {
+    pub fn add(&mut self, order: &BookOrder) {
+        self.orders.insert(order.order_id, order.clone());
+        self.changed.insert(order.order_id);
+        match order.side {
+            Side::SideBuy => {
+                self.buy_queue.insert(
+                    PriceOrderIdKeyAsc::new(&order.stop_price, order.order_id),
+                    order.order_id,
+                );
+            }
+            Side::SideSell => {
+                self.sell_queue.insert(
+                    PriceOrderIdKeyDesc::new(&order.stop_price, order.order_id),
+                    order.order_id,
+                );
+            }
+        }
+    }
+
+    pub fn remove(&mut self, order_id: u64) -> Option<BookOrder> {
+        let order = self.orders.remove(&order_id)?;
+        self.changed.insert(order_id);
+        match order.side {
+            Side::SideBuy => {
+                self.buy_queue
+                    .remove(&PriceOrderIdKeyAsc::new(&order.stop_price, order_id));
+            }
+            Side::SideSell => {
+                self.sell_queue
+                    .remove(&PriceOrderIdKeyDesc::new(&order.stop_price, order_id));
+            }
+        }
+        Some(order)
+    }
+
+    /// Whether a stop order on `side` with `stop_price` is triggered at `last_trade_price`.
+    pub fn is_triggered(side: &Side, stop_price: &Decimal, last_trade_price: &Decimal) -> bool {
+        // no trade yet, there is no reference price to trigger on
+        if last_trade_price.is_zero() {
+            return false;
+        }
+        match side {
+            Side::SideBuy => last_trade_price >= stop_price,
+            Side::SideSell => last_trade_price <= stop_price,
+        }
+    }
+
+    /// Remove and return every order triggered at `last_trade_price`, buy stops first and
+    /// then sell stops, each in stop price priority.
+    pub fn take_triggered(&mut self, last_trade_price: &Decimal) -> Vec<BookOrder> {
+        let mut order_ids: Vec<u64> = Vec::new();
+
+        for (k, v) in &self.buy_queue {
+            if !Self::is_triggered(&Side::SideBuy, &k.price, last_trade_price) {
+                break;
+            }
+            order_ids.push(*v);
+        }
+        for (k, v) in &self.sell_queue {
+            if !Self::is_triggered(&Side::SideSell, &k.price, last_trade_price) {
+                break;
+            }
+            order_ids.push(*v);
+        }
+
+        order_ids
+            .into_iter()
+            .filter_map(|order_id| self.remove(order_id))
+            .collect()
+    }
+}
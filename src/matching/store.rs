@@ -0,0 +1,218 @@
+use std::future::Future;
+use std::result::Result;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::matching::engine::Snapshot;
+use crate::matching::log::LogTrait;
+use crate::matching::market_data::{Candle, Ticker};
+use crate::matching::order_book::L3Snapshot;
+use crate::models::models::Order;
+use crate::utils::error::CustomError;
+
+/// Where the engine reads orders from. Offsets identify an order in the source and are
+/// what snapshots record as `order_offset`.
+pub trait OrderSource {
+    /// Position the source right after `order_offset`, or at the first order if it is 0.
+    fn seek(&mut self, order_offset: u64) -> impl Future<Output = Result<(), CustomError>> + Send;
+
+    /// Wait for the next order and return it with its offset. `None` is a message that
+    /// carries no order and is skipped.
+    fn fetch_order(
+        &mut self,
+    ) -> impl Future<Output = Result<(i64, Option<Order>), CustomError>> + Send;
+
+    /// Whether every order has been fetched and no more will come, which stops the engine
+    /// once it has handled them. A source that waits for new orders never is.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+/// Where the engine writes its logs. A batch that returns `Ok` must be durable, the
+/// engine only moves its committed sequence forward after that.
+pub trait LogSink {
+    /// Store a batch holding every log of the orders up to `order_offset` that was not
+    /// stored before. A sink that tracks consumption can commit the offset with the batch,
+    /// which is why a batch is also stored when its orders gave no new logs.
+    fn store(
+        &mut self,
+        logs: &[Box<dyn LogTrait>],
+        order_offset: u64,
+    ) -> impl Future<Output = Result<(), CustomError>> + Send;
+
+    /// Sequence of the last log already stored, 0 if there is none. A restarted engine
+    /// replays from its snapshot and skips logs up to here instead of writing them twice.
+    fn last_seq(&mut self) -> impl Future<Output = Result<u64, CustomError>> + Send;
+}
+
+/// Where order-by-order snapshots of the book go for market data.
+pub trait BookPublisher {
+    fn publish(&mut self, book: &L3Snapshot) -> impl Future<Output = Result<(), CustomError>> + Send;
+}
+
+/// Where the ticker and candles folded from match logs go.
+pub trait MarketDataPublisher {
+    fn publish(
+        &mut self,
+        ticker: &Ticker,
+        candles: &[Candle],
+    ) -> impl Future<Output = Result<(), CustomError>> + Send;
+}
+
+/// Identifies one snapshot in the history of a store.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub order_offset: u64,
+    pub log_seq: u64,
+}
+
+impl SnapshotInfo {
+    pub fn of(snapshot: &Snapshot) -> SnapshotInfo {
+        SnapshotInfo {
+            order_offset: snapshot.order_offset,
+            log_seq: snapshot.log_seq(),
+        }
+    }
+
+    /// `{order_offset}-{log_seq}`, used in keys and file names.
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.order_offset, self.log_seq)
+    }
+
+    pub fn from_id(id: &str) -> Option<SnapshotInfo> {
+        let (order_offset, log_seq) = id.split_once('-')?;
+        Some(SnapshotInfo {
+            order_offset: order_offset.parse().ok()?,
+            log_seq: log_seq.parse().ok()?,
+        })
+    }
+}
+
+/// Seal a snapshot for storage: the CRC-32 of its JSON as 8 hex digits, a newline, then
+/// the JSON itself.
+pub fn seal_snapshot(snapshot: &Snapshot) -> Result<String, CustomError> {
+    let body = serde_json::to_string(snapshot).map_err(|e| CustomError::new(&e))?;
+    Ok(format!("{:08x}\n{}", crc32fast::hash(body.as_bytes()), body))
+}
+
+/// Check the checksum of a sealed snapshot and deserialize it.
+pub fn open_snapshot(s: &str) -> Result<Snapshot, CustomError> {
+    let (header, body) = s
+        .split_once('\n')
+        .ok_or_else(|| CustomError::from_string("snapshot is not sealed".to_string()))?;
+    let stored = u32::from_str_radix(header, 16)
+        .map_err(|_| CustomError::from_string(format!("bad snapshot checksum {:?}", header)))?;
+    let checksum = crc32fast::hash(body.as_bytes());
+    if checksum != stored {
+        return Err(CustomError::from_string(format!(
+            "snapshot checksum mismatch: stored {:08x}, computed {:08x}",
+            stored, checksum
+        )));
+    }
+    serde_json::from_str(body).map_err(|e| CustomError::new(&e))
+}
+
+// guards against a cycle of deltas in a damaged store
+const MAX_DELTA_CHAIN: usize = 10_000;
+
+/// Where the engine keeps its snapshots. Each store keeps a bounded history, so a bad
+/// snapshot never loses the recovery point. A delta snapshot needs every snapshot of its
+/// chain back to a full one, so the history is only ever pruned by whole chains.
+pub trait SnapshotStore: Send {
+    /// Add a snapshot to the history, dropping the snapshots `expired` leaves past the
+    /// retention.
+    fn store(&mut self, snapshot: &Snapshot) -> impl Future<Output = Result<(), CustomError>> + Send;
+
+    /// The snapshots in the history, newest first.
+    fn list(&mut self) -> impl Future<Output = Result<Vec<SnapshotInfo>, CustomError>> + Send;
+
+    /// Load one snapshot of the history, failing if it is gone or corrupted.
+    fn get(
+        &mut self,
+        info: &SnapshotInfo,
+    ) -> impl Future<Output = Result<Snapshot, CustomError>> + Send;
+
+    /// The snapshot a delta builds on, `None` for a full snapshot. Stores that remember it
+    /// avoid loading the snapshot.
+    fn base_of(
+        &mut self,
+        info: &SnapshotInfo,
+    ) -> impl Future<Output = Result<Option<SnapshotInfo>, CustomError>> + Send {
+        async move { Ok(self.get(info).await?.base) }
+    }
+
+    /// The snapshots to drop so that the newest `retention` are kept, each with its whole
+    /// chain: only snapshots older than the full snapshot the oldest kept one builds on go.
+    /// A retention of 0 keeps every snapshot.
+    fn expired(
+        &mut self,
+        retention: usize,
+    ) -> impl Future<Output = Result<Vec<SnapshotInfo>, CustomError>> + Send {
+        async move {
+            let infos = self.list().await?;
+            if retention == 0 || infos.len() <= retention {
+                return Ok(Vec::new());
+            }
+
+            // a link that cannot be loaded breaks the chain, nothing older is of use to it
+            let mut oldest = infos[retention - 1].clone();
+            for _ in 0..MAX_DELTA_CHAIN {
+                match self.base_of(&oldest).await {
+                    Ok(Some(base)) => oldest = base,
+                    _ => break,
+                }
+            }
+            Ok(infos.into_iter().filter(|info| *info < oldest).collect())
+        }
+    }
+
+    /// Load one snapshot and, if it is a delta, the chain back to the full snapshot it
+    /// builds on, folded into one full snapshot.
+    fn get_full(
+        &mut self,
+        info: &SnapshotInfo,
+    ) -> impl Future<Output = Result<Snapshot, CustomError>> + Send {
+        async move {
+            let mut chain = vec![self.get(info).await?];
+            while let Some(base) = chain.last().and_then(|s| s.base.clone()) {
+                if chain.len() > MAX_DELTA_CHAIN {
+                    return Err(CustomError::from_string(format!(
+                        "delta chain of snapshot {} is too long",
+                        info.id()
+                    )));
+                }
+                chain.push(self.get(&base).await?);
+            }
+
+            let mut snapshot = chain.pop().unwrap();
+            while let Some(delta) = chain.pop() {
+                snapshot.apply_delta(&delta)?;
+            }
+            Ok(snapshot)
+        }
+    }
+
+    /// The newest snapshot that loads with its delta chain, skipping corrupted ones. `None` if there is none at
+    /// all, an error if there are snapshots but none of them loads.
+    fn get_latest(&mut self) -> impl Future<Output = Result<Option<Snapshot>, CustomError>> + Send {
+        async move {
+            let infos = self.list().await?;
+            for info in &infos {
+                match self.get_full(info).await {
+                    Ok(snapshot) => return Ok(Some(snapshot)),
+                    Err(e) => error!("skip snapshot {}: {}", info.id(), e),
+                }
+            }
+            if infos.is_empty() {
+                Ok(None)
+            } else {
+                Err(CustomError::from_string(format!(
+                    "none of the {} stored snapshots could be loaded",
+                    infos.len()
+                )))
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::ops::{Div, Mul};
+
 use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -5,13 +7,99 @@ use crate::models::types::*;
 
 // use serde::{Deserializer, Serializer};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Product {
     pub id: String,
     pub base_currency: String,
     pub quote_currency: String,
     pub base_scale: i32,
     pub quote_scale: i32,
+    // trading rules, a zero value means the rule is not enforced
+    // price increment, one unit of quote_scale if not set
+    #[serde(default)]
+    pub tick_size: Decimal,
+    // size increment, one unit of base_scale if not set
+    #[serde(default)]
+    pub lot_size: Decimal,
+    #[serde(default)]
+    pub min_size: Decimal,
+    #[serde(default)]
+    pub max_size: Decimal,
+    // bounds on price * size of a limit order, or on the funds of a market order
+    #[serde(default)]
+    pub min_notional: Decimal,
+    #[serde(default)]
+    pub max_notional: Decimal,
+    // limit orders priced more than this percentage away from the last trade price are rejected
+    #[serde(default)]
+    pub price_band: Decimal,
+    // a market order that would move the price more than this percentage halts the book
+    #[serde(default)]
+    pub circuit_breaker: Decimal,
+    // how long a halt lasts in seconds, 60 if not set
+    #[serde(default)]
+    pub halt_duration: u64,
+    // publish an l2update log with the changed price levels after every order
+    #[serde(default)]
+    pub l2_update: bool,
+    // fees in basis points of the quote notional of a trade, a negative maker fee is a rebate
+    #[serde(default)]
+    pub maker_fee_bps: Decimal,
+    #[serde(default)]
+    pub taker_fee_bps: Decimal,
+    // track balances in the engine and reject orders the user cannot pay for
+    #[serde(default)]
+    pub risk_ledger: bool,
+}
+
+impl Product {
+    pub fn effective_tick_size(&self) -> Decimal {
+        if self.tick_size.is_zero() {
+            Decimal::new(1, self.quote_scale as u32)
+        } else {
+            self.tick_size
+        }
+    }
+
+    pub fn effective_lot_size(&self) -> Decimal {
+        if self.lot_size.is_zero() {
+            Decimal::new(1, self.base_scale as u32)
+        } else {
+            self.lot_size
+        }
+    }
+
+    pub fn effective_halt_duration(&self) -> u64 {
+        if self.halt_duration == 0 {
+            60
+        } else {
+            self.halt_duration
+        }
+    }
+
+    /// Quote value of a trade, rounded half away from zero to exactly quote_scale digits.
+    pub fn notional(&self, price: &Decimal, size: &Decimal) -> Decimal {
+        self.to_quote_scale(price.mul(size))
+    }
+
+    /// Fee of `fee_bps` basis points on the quote value of a trade, rounded the same way.
+    /// It is taken from the exact price * size, so it is rounded only once.
+    pub fn fee(&self, price: &Decimal, size: &Decimal, fee_bps: &Decimal) -> Decimal {
+        self.to_quote_scale(price.mul(size).mul(fee_bps).div(Decimal::from(10_000)))
+    }
+
+    fn to_quote_scale(&self, amount: Decimal) -> Decimal {
+        let mut amount = amount.round_dp_with_strategy(
+            self.quote_scale as u32,
+            RoundingStrategy::MidpointAwayFromZero,
+        );
+        amount.rescale(self.quote_scale as u32);
+        // a rebate that rounds away is 0.00, not -0.00
+        if amount.is_zero() {
+            amount.set_sign_positive(true);
+        }
+        amount
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -25,6 +113,12 @@ pub struct Order {
     pub price: Decimal,
     pub size: Decimal,
     pub funds: Decimal,
+    // trigger price of stop and stop-limit orders
+    #[serde(default)]
+    pub stop_price: Decimal,
+    // visible slice size of an iceberg order, zero shows the whole order
+    #[serde(default)]
+    pub display_size: Decimal,
     #[serde(serialize_with = "serialize_order_type")]
     #[serde(deserialize_with = "deserialize_order_type")]
     pub r#type: OrderType,
@@ -34,37 +128,94 @@ pub struct Order {
     #[serde(serialize_with = "serialize_time_in_force_type")]
     #[serde(deserialize_with = "deserialize_time_in_force_type")]
     pub time_in_force: TimeInForceType,
+    // expiry of a GTT order, or a time on the last day of a GTD order, in timestamp_nanos
+    #[serde(default)]
+    pub expire_time: u64,
     #[serde(serialize_with = "serialize_order_status")]
     #[serde(deserialize_with = "deserialize_order_status")]
     pub status: OrderStatus,
+    // self-trade prevention mode, applied when this order is the taker
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_self_trade_prevention")]
+    #[serde(deserialize_with = "deserialize_self_trade_prevention")]
+    pub stp: SelfTradePrevention,
+    // new market state carried by a control message
+    #[serde(default)]
+    #[serde(serialize_with = "serialize_option_market_state")]
+    #[serde(deserialize_with = "deserialize_option_market_state")]
+    pub market_state: Option<MarketState>,
+    // fee tier of the user, overrides the fees of the product for this order
+    #[serde(default)]
+    pub maker_fee_bps: Option<Decimal>,
+    #[serde(default)]
+    pub taker_fee_bps: Option<Decimal>,
+    // deposit or withdrawal carried by a control message
+    #[serde(default)]
+    pub transfer: Option<Transfer>,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order {
+            id: 0,
+            created_at: 0,
+            product_id: String::new(),
+            user_id: 0,
+            client_oid: String::new(),
+            price: Default::default(),
+            size: Default::default(),
+            funds: Default::default(),
+            stop_price: Default::default(),
+            display_size: Default::default(),
+            r#type: OrderType::OrderTypeLimit,
+            side: Side::SideBuy,
+            time_in_force: TimeInForceType::GoodTillCanceled,
+            expire_time: 0,
+            status: OrderStatus::OrderStatusNew,
+            stp: SelfTradePrevention::SelfTradePreventionNone,
+            market_state: None,
+            maker_fee_bps: None,
+            taker_fee_bps: None,
+            transfer: None,
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+impl Order {
+    /// A new GTC limit order of BTC-USD, the order every test starts from.
+    pub fn new_test_order(id: u64, user_id: u64, side: Side, size: &str, price: &str) -> Order {
+        Order {
+            id,
+            product_id: "BTC-USD".to_string(),
+            user_id,
+            price: Decimal::from_str(price).unwrap(),
+            size: Decimal::from_str(size).unwrap(),
+            side,
+            ..Default::default()
+        }
+    }
+}
 
-    use rust_decimal::Decimal;
+/// Funds moved in or out of the risk ledger for the user of a control message.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Transfer {
+    #[serde(serialize_with = "serialize_transfer_type")]
+    #[serde(deserialize_with = "deserialize_transfer_type")]
+    pub r#type: TransferType,
+    // base_currency or quote_currency of the product
+    pub currency: String,
+    pub amount: Decimal,
+}
 
+#[cfg(test)]
+mod tests {
     use crate::models::models::Order;
-    use crate::models::types::{OrderStatus, OrderType, Side, TimeInForceType};
+    use crate::models::types::{MarketState, OrderStatus, Side};
 
     #[test]
     fn test_serialize_order() {
-        let order = Order {
-            id: 1,
-            created_at: 1695783003020967000,
-            product_id: "BTC-USD".to_string(),
-            user_id: 1,
-            client_oid: "".to_string(),
-            price: Decimal::from_str(&*"1000.00".to_string()).unwrap(),
-            size: Decimal::from_str(&*"3.00".to_string()).unwrap(),
-            funds: Default::default(),
-            r#type: OrderType::OrderTypeLimit,
-            side: Side::SideBuy,
-            time_in_force: TimeInForceType::GoodTillCanceled,
-            status: OrderStatus::OrderStatusNew,
-        };
-
+        let order = Order::new_test_order(1, 1, Side::SideBuy, "3.00", "1000.00");
         let s = serde_json::to_string(&order).unwrap();
         println!("{}", s);
     }
@@ -76,4 +227,13 @@ mod tests {
 
         println!("{:?}", o);
     }
+
+    #[test]
+    fn test_deserialize_control_message() {
+        let string = r#"{"id":2,"created_at":1695783003020967000,"product_id":"BTC-USD","user_id":0,"client_oid":"","price":"0","size":"0","funds":"0","type":"limit","side":"buy","time_in_force":"GTC","status":"control","market_state":"cancel_only"}"#;
+        let o: Order = serde_json::from_str(string).unwrap();
+
+        assert!(matches!(o.status, OrderStatus::OrderStatusControl));
+        assert_eq!(o.market_state, Some(MarketState::MarketStateCancelOnly));
+    }
 }
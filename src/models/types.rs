@@ -4,6 +4,24 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 pub enum OrderType {
     OrderTypeLimit,
     OrderTypeMarket,
+    OrderTypeStop,
+    OrderTypeStopLimit,
+}
+
+impl OrderType {
+    /// Whether this order waits in the trigger book until its stop price is crossed.
+    pub fn is_stop(&self) -> bool {
+        matches!(self, OrderType::OrderTypeStop | OrderType::OrderTypeStopLimit)
+    }
+
+    /// The order type a stop order becomes once it has been triggered.
+    pub fn triggered(&self) -> Self {
+        match self {
+            OrderType::OrderTypeStop => OrderType::OrderTypeMarket,
+            OrderType::OrderTypeStopLimit => OrderType::OrderTypeLimit,
+            other => other.clone(),
+        }
+    }
 }
 
 pub fn serialize_order_type<S>(order_type: &OrderType, serializer: S) -> Result<S::Ok, S::Error>
@@ -13,6 +31,8 @@ where
     let string = match order_type {
         OrderType::OrderTypeLimit => "limit",
         OrderType::OrderTypeMarket => "market",
+        OrderType::OrderTypeStop => "stop",
+        OrderType::OrderTypeStopLimit => "stop_limit",
     };
     serializer.serialize_str(string)
 }
@@ -25,6 +45,8 @@ where
     match string {
         "limit" => Ok(OrderType::OrderTypeLimit),
         "market" => Ok(OrderType::OrderTypeMarket),
+        "stop" => Ok(OrderType::OrderTypeStop),
+        "stop_limit" => Ok(OrderType::OrderTypeStopLimit),
         _ => Err(serde::de::Error::custom("invalid order_type string")),
     }
 }
@@ -73,6 +95,25 @@ pub enum TimeInForceType {
     ImmediateOrCancel,
     GoodTillCrossing,
     FillOrKill,
+    GoodTillTime,
+    GoodTillDate,
+}
+
+/// Nanoseconds in a day, a GTD order lasts until the end of the day of its expire time.
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+impl TimeInForceType {
+    /// When an order with this time in force and `expire_time` leaves the book, zero if it
+    /// does not expire.
+    pub fn expiry(&self, expire_time: u64) -> u64 {
+        match self {
+            TimeInForceType::GoodTillTime => expire_time,
+            TimeInForceType::GoodTillDate if expire_time != 0 => {
+                (expire_time / DAY_NANOS + 1) * DAY_NANOS
+            }
+            _ => 0,
+        }
+    }
 }
 
 pub fn serialize_time_in_force_type<S>(
@@ -87,6 +128,8 @@ where
         TimeInForceType::ImmediateOrCancel => "IOC",
         TimeInForceType::GoodTillCrossing => "GTX",
         TimeInForceType::FillOrKill => "FOK",
+        TimeInForceType::GoodTillTime => "GTT",
+        TimeInForceType::GoodTillDate => "GTD",
     };
     serializer.serialize_str(string)
 }
@@ -101,6 +144,8 @@ where
         "IOC" => Ok(TimeInForceType::ImmediateOrCancel),
         "GTX" => Ok(TimeInForceType::GoodTillCrossing),
         "FOK" => Ok(TimeInForceType::FillOrKill),
+        "GTT" => Ok(TimeInForceType::GoodTillTime),
+        "GTD" => Ok(TimeInForceType::GoodTillDate),
         _ => Err(serde::de::Error::custom(
             "invalid time_in_force_type string",
         )),
@@ -115,6 +160,8 @@ pub enum OrderStatus {
     OrderStatusCancelled,
     OrderStatusPartial,
     OrderStatusFilled,
+    OrderStatusAmending,
+    OrderStatusControl,
 }
 
 pub fn serialize_order_status<S>(
@@ -131,6 +178,8 @@ where
         OrderStatus::OrderStatusCancelled => "cancelled",
         OrderStatus::OrderStatusPartial => "partial",
         OrderStatus::OrderStatusFilled => "filled",
+        OrderStatus::OrderStatusAmending => "amending",
+        OrderStatus::OrderStatusControl => "control",
     };
     serializer.serialize_str(string)
 }
@@ -147,6 +196,8 @@ where
         "cancelled" => Ok(OrderStatus::OrderStatusCancelled),
         "partial" => Ok(OrderStatus::OrderStatusPartial),
         "filled" => Ok(OrderStatus::OrderStatusFilled),
+        "amending" => Ok(OrderStatus::OrderStatusAmending),
+        "control" => Ok(OrderStatus::OrderStatusControl),
         _ => Err(serde::de::Error::custom("invalid order_status string")),
     }
 }
@@ -155,6 +206,9 @@ where
 pub enum DoneReason {
     DoneReasonFilled,
     DoneReasonCancelled,
+    DoneReasonSelfTradePrevented,
+    DoneReasonRejected,
+    DoneReasonExpired,
 }
 
 pub fn serialize_done_reason<S>(done_reason: &DoneReason, serializer: S) -> Result<S::Ok, S::Error>
@@ -164,6 +218,9 @@ where
     let string = match done_reason {
         DoneReason::DoneReasonFilled => "filled",
         DoneReason::DoneReasonCancelled => "cancelled",
+        DoneReason::DoneReasonSelfTradePrevented => "stp",
+        DoneReason::DoneReasonRejected => "rejected",
+        DoneReason::DoneReasonExpired => "expired",
     };
     serializer.serialize_str(string)
 }
@@ -176,12 +233,375 @@ where
     match string {
         "filled" => Ok(DoneReason::DoneReasonFilled),
         "cancelled" => Ok(DoneReason::DoneReasonCancelled),
+        "stp" => Ok(DoneReason::DoneReasonSelfTradePrevented),
+        "rejected" => Ok(DoneReason::DoneReasonRejected),
+        "expired" => Ok(DoneReason::DoneReasonExpired),
         _ => Err(serde::de::Error::custom("invalid done_reason string")),
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RejectReason {
+    RejectReasonInvalidPrice,
+    RejectReasonInvalidSize,
+    RejectReasonTickSize,
+    RejectReasonLotSize,
+    RejectReasonMinSize,
+    RejectReasonMaxSize,
+    RejectReasonMinNotional,
+    RejectReasonMaxNotional,
+    RejectReasonPriceBand,
+    RejectReasonCircuitBreaker,
+    RejectReasonHalted,
+    RejectReasonCancelOnly,
+    RejectReasonPostOnly,
+    RejectReasonAuction,
+    RejectReasonInvalidExpireTime,
+    RejectReasonInvalidTimeInForce,
+    RejectReasonInsufficientFunds,
+    RejectReasonInvalidCurrency,
+    RejectReasonUnknownOrder,
+    RejectReasonNotAmendable,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::RejectReasonInvalidPrice => "invalid_price",
+            RejectReason::RejectReasonInvalidSize => "invalid_size",
+            RejectReason::RejectReasonTickSize => "tick_size",
+            RejectReason::RejectReasonLotSize => "lot_size",
+            RejectReason::RejectReasonMinSize => "min_size",
+            RejectReason::RejectReasonMaxSize => "max_size",
+            RejectReason::RejectReasonMinNotional => "min_notional",
+            RejectReason::RejectReasonMaxNotional => "max_notional",
+            RejectReason::RejectReasonPriceBand => "price_band",
+            RejectReason::RejectReasonCircuitBreaker => "circuit_breaker",
+            RejectReason::RejectReasonHalted => "halted",
+            RejectReason::RejectReasonCancelOnly => "cancel_only",
+            RejectReason::RejectReasonPostOnly => "post_only",
+            RejectReason::RejectReasonAuction => "auction",
+            RejectReason::RejectReasonInvalidExpireTime => "invalid_expire_time",
+            RejectReason::RejectReasonInvalidTimeInForce => "invalid_time_in_force",
+            RejectReason::RejectReasonInsufficientFunds => "insufficient_funds",
+            RejectReason::RejectReasonInvalidCurrency => "invalid_currency",
+            RejectReason::RejectReasonUnknownOrder => "unknown_order",
+            RejectReason::RejectReasonNotAmendable => "not_amendable",
+        }
+    }
+}
+
+pub fn serialize_reject_reason<S>(
+    reject_reason: &Option<RejectReason>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match reject_reason {
+        Some(r) => serializer.serialize_str(r.as_str()),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_reject_reason<'de, D>(deserializer: D) -> Result<Option<RejectReason>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: Option<&str> = Deserialize::deserialize(deserializer)?;
+    match string {
+        None => Ok(None),
+        Some("invalid_price") => Ok(Some(RejectReason::RejectReasonInvalidPrice)),
+        Some("invalid_size") => Ok(Some(RejectReason::RejectReasonInvalidSize)),
+        Some("tick_size") => Ok(Some(RejectReason::RejectReasonTickSize)),
+        Some("lot_size") => Ok(Some(RejectReason::RejectReasonLotSize)),
+        Some("min_size") => Ok(Some(RejectReason::RejectReasonMinSize)),
+        Some("max_size") => Ok(Some(RejectReason::RejectReasonMaxSize)),
+        Some("min_notional") => Ok(Some(RejectReason::RejectReasonMinNotional)),
+        Some("max_notional") => Ok(Some(RejectReason::RejectReasonMaxNotional)),
+        Some("price_band") => Ok(Some(RejectReason::RejectReasonPriceBand)),
+        Some("circuit_breaker") => Ok(Some(RejectReason::RejectReasonCircuitBreaker)),
+        Some("halted") => Ok(Some(RejectReason::RejectReasonHalted)),
+        Some("cancel_only") => Ok(Some(RejectReason::RejectReasonCancelOnly)),
+        Some("post_only") => Ok(Some(RejectReason::RejectReasonPostOnly)),
+        Some("auction") => Ok(Some(RejectReason::RejectReasonAuction)),
+        Some("invalid_expire_time") => Ok(Some(RejectReason::RejectReasonInvalidExpireTime)),
+        Some("invalid_time_in_force") => Ok(Some(RejectReason::RejectReasonInvalidTimeInForce)),
+        Some("insufficient_funds") => Ok(Some(RejectReason::RejectReasonInsufficientFunds)),
+        Some("invalid_currency") => Ok(Some(RejectReason::RejectReasonInvalidCurrency)),
+        Some("unknown_order") => Ok(Some(RejectReason::RejectReasonUnknownOrder)),
+        Some("not_amendable") => Ok(Some(RejectReason::RejectReasonNotAmendable)),
+        Some(_) => Err(serde::de::Error::custom("invalid reject_reason string")),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub enum MarketState {
+    #[default]
+    MarketStateContinuous,
+    MarketStatePostOnly,
+    MarketStateCancelOnly,
+    MarketStateHalted,
+    MarketStateAuction,
+}
+
+impl MarketState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MarketState::MarketStateContinuous => "continuous",
+            MarketState::MarketStatePostOnly => "post_only",
+            MarketState::MarketStateCancelOnly => "cancel_only",
+            MarketState::MarketStateHalted => "halted",
+            MarketState::MarketStateAuction => "auction",
+        }
+    }
+}
+
+pub fn serialize_market_state<S>(market_state: &MarketState, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(market_state.as_str())
+}
+
+pub fn deserialize_market_state<'de, D>(deserializer: D) -> Result<MarketState, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "continuous" => Ok(MarketState::MarketStateContinuous),
+        "post_only" => Ok(MarketState::MarketStatePostOnly),
+        "cancel_only" => Ok(MarketState::MarketStateCancelOnly),
+        "halted" => Ok(MarketState::MarketStateHalted),
+        "auction" => Ok(MarketState::MarketStateAuction),
+        _ => Err(serde::de::Error::custom("invalid market_state string")),
+    }
+}
+
+pub fn serialize_option_market_state<S>(
+    market_state: &Option<MarketState>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match market_state {
+        Some(m) => serializer.serialize_str(m.as_str()),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_option_market_state<'de, D>(
+    deserializer: D,
+) -> Result<Option<MarketState>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: Option<&str> = Deserialize::deserialize(deserializer)?;
+    match string {
+        None => Ok(None),
+        Some("continuous") => Ok(Some(MarketState::MarketStateContinuous)),
+        Some("post_only") => Ok(Some(MarketState::MarketStatePostOnly)),
+        Some("cancel_only") => Ok(Some(MarketState::MarketStateCancelOnly)),
+        Some("halted") => Ok(Some(MarketState::MarketStateHalted)),
+        Some("auction") => Ok(Some(MarketState::MarketStateAuction)),
+        Some(_) => Err(serde::de::Error::custom("invalid market_state string")),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum TransferType {
+    TransferTypeDeposit,
+    TransferTypeWithdraw,
+}
+
+impl TransferType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferType::TransferTypeDeposit => "deposit",
+            TransferType::TransferTypeWithdraw => "withdraw",
+        }
+    }
+}
+
+pub fn serialize_transfer_type<S>(transfer_type: &TransferType, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(transfer_type.as_str())
+}
+
+pub fn deserialize_transfer_type<'de, D>(deserializer: D) -> Result<TransferType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "deposit" => Ok(TransferType::TransferTypeDeposit),
+        "withdraw" => Ok(TransferType::TransferTypeWithdraw),
+        _ => Err(serde::de::Error::custom("invalid transfer_type string")),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub enum SelfTradePrevention {
+    #[default]
+    SelfTradePreventionNone,
+    SelfTradePreventionCancelNewest,
+    SelfTradePreventionCancelOldest,
+    SelfTradePreventionCancelBoth,
+    SelfTradePreventionDecrementAndCancel,
+}
+
+pub fn serialize_self_trade_prevention<S>(
+    self_trade_prevention: &SelfTradePrevention,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let string = match self_trade_prevention {
+        SelfTradePrevention::SelfTradePreventionNone => "none",
+        SelfTradePrevention::SelfTradePreventionCancelNewest => "cn",
+        SelfTradePrevention::SelfTradePreventionCancelOldest => "co",
+        SelfTradePrevention::SelfTradePreventionCancelBoth => "cb",
+        SelfTradePrevention::SelfTradePreventionDecrementAndCancel => "dc",
+    };
+    serializer.serialize_str(string)
+}
+
+pub fn deserialize_self_trade_prevention<'de, D>(
+    deserializer: D,
+) -> Result<SelfTradePrevention, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "none" => Ok(SelfTradePrevention::SelfTradePreventionNone),
+        "cn" => Ok(SelfTradePrevention::SelfTradePreventionCancelNewest),
+        "co" => Ok(SelfTradePrevention::SelfTradePreventionCancelOldest),
+        "cb" => Ok(SelfTradePrevention::SelfTradePreventionCancelBoth),
+        "dc" => Ok(SelfTradePrevention::SelfTradePreventionDecrementAndCancel),
+        _ => Err(serde::de::Error::custom(
+            "invalid self_trade_prevention string",
+        )),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub enum WireFormat {
+    #[default]
+    WireFormatJson,
+    WireFormatBinary,
+}
+
+impl WireFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WireFormat::WireFormatJson => "json",
+            WireFormat::WireFormatBinary => "binary",
+        }
+    }
+}
+
+pub fn serialize_wire_format<S>(wire_format: &WireFormat, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(wire_format.as_str())
+}
+
+pub fn deserialize_wire_format<'de, D>(deserializer: D) -> Result<WireFormat, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "json" => Ok(WireFormat::WireFormatJson),
+        "binary" => Ok(WireFormat::WireFormatBinary),
+        _ => Err(serde::de::Error::custom("invalid wire_format string")),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub enum SnapshotStoreType {
+    #[default]
+    SnapshotStoreTypeRedis,
+    SnapshotStoreTypeFile,
+}
+
+impl SnapshotStoreType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotStoreType::SnapshotStoreTypeRedis => "redis",
+            SnapshotStoreType::SnapshotStoreTypeFile => "file",
+        }
+    }
+}
+
+pub fn serialize_snapshot_store_type<S>(
+    snapshot_store_type: &SnapshotStoreType,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(snapshot_store_type.as_str())
+}
+
+pub fn deserialize_snapshot_store_type<'de, D>(deserializer: D) -> Result<SnapshotStoreType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "redis" => Ok(SnapshotStoreType::SnapshotStoreTypeRedis),
+        "file" => Ok(SnapshotStoreType::SnapshotStoreTypeFile),
+        _ => Err(serde::de::Error::custom("invalid snapshot_store_type string")),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    CompressionNone,
+    CompressionGzip,
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::CompressionNone => "none",
+            Compression::CompressionGzip => "gzip",
+        }
+    }
+}
+
+pub fn serialize_compression<S>(compression: &Compression, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(compression.as_str())
+}
+
+pub fn deserialize_compression<'de, D>(deserializer: D) -> Result<Compression, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string: &str = Deserialize::deserialize(deserializer)?;
+    match string {
+        "none" => Ok(Compression::CompressionNone),
+        "gzip" => Ok(Compression::CompressionGzip),
+        _ => Err(serde::de::Error::custom("invalid compression string")),
+    }
+}
+
 pub const ORDER_TYPE_LIMIT: OrderType = OrderType::OrderTypeLimit;
 pub const ORDER_TYPE_MARKET: OrderType = OrderType::OrderTypeMarket;
+pub const ORDER_TYPE_STOP: OrderType = OrderType::OrderTypeStop;
+pub const ORDER_TYPE_STOP_LIMIT: OrderType = OrderType::OrderTypeStopLimit;
 
 pub const SIDE_BUY: Side = Side::SideBuy;
 pub const SIDE_SELL: Side = Side::SideSell;
@@ -197,6 +617,11 @@ pub const ORDER_STATUS_CANCELLING: OrderStatus = OrderStatus::OrderStatusCancell
 pub const ORDER_STATUS_CANCELLED: OrderStatus = OrderStatus::OrderStatusCancelled;
 pub const ORDER_STATUS_PARTIAL: OrderStatus = OrderStatus::OrderStatusPartial;
 pub const ORDER_STATUS_FILLED: OrderStatus = OrderStatus::OrderStatusFilled;
+pub const ORDER_STATUS_AMENDING: OrderStatus = OrderStatus::OrderStatusAmending;
+pub const ORDER_STATUS_CONTROL: OrderStatus = OrderStatus::OrderStatusControl;
 
 pub const DONE_REASON_FILLED: DoneReason = DoneReason::DoneReasonFilled;
 pub const DONE_REASON_CANCELLED: DoneReason = DoneReason::DoneReasonCancelled;
+pub const DONE_REASON_SELF_TRADE_PREVENTED: DoneReason = DoneReason::DoneReasonSelfTradePrevented;
+pub const DONE_REASON_REJECTED: DoneReason = DoneReason::DoneReasonRejected;
+pub const DONE_REASON_EXPIRED: DoneReason = DoneReason::DoneReasonExpired;
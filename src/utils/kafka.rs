@@ -9,9 +9,12 @@ use rdkafka::producer::FutureProducer;
 pub type DefaultConsumer = StreamConsumer<DefaultConsumerContext>;
 pub type DefaultProducer = FutureProducer<DefaultProducerContext>;
 
+/// An idempotent, transactional producer. Retries never duplicate or reorder messages, and
+/// a new producer with the same `transactional_id` fences off any older one still running.
 pub fn new_kafka_producer(
     brokers: &[String],
     message_time_out: u64,
+    transactional_id: &str,
 ) -> KafkaResult<DefaultProducer> {
     let producer: DefaultProducer = ClientConfig::new()
         .set("bootstrap.servers", brokers.join(","))
@@ -21,6 +24,32 @@ pub fn new_kafka_producer(
             "message.timeout.ms",
             format!("{}", message_time_out * 1000),
         )
+        .set("enable.idempotence", "true")
+        .set("transactional.id", transactional_id)
+        .set(
+            "transaction.timeout.ms",
+            format!("{}", message_time_out * 1000),
+        )
+        .create_with_context(DefaultProducerContext)?;
+
+    Ok(producer)
+}
+
+/// An idempotent producer without transactions, for topics that are not part of the
+/// exactly-once log stream.
+pub fn new_kafka_idempotent_producer(
+    brokers: &[String],
+    message_time_out: u64,
+) -> KafkaResult<DefaultProducer> {
+    let producer: DefaultProducer = ClientConfig::new()
+        .set("bootstrap.servers", brokers.join(","))
+        .set("api.version.request", "true")
+        .set("broker.version.fallback", "2.1.0")
+        .set(
+            "message.timeout.ms",
+            format!("{}", message_time_out * 1000),
+        )
+        .set("enable.idempotence", "true")
         .create_with_context(DefaultProducerContext)?;
 
     Ok(producer)
@@ -31,8 +60,10 @@ pub fn new_kafka_consumer(
     group_id: &str,
     topic: &str,
     session_time_out: u64,
+    exactly_once: bool,
 ) -> KafkaResult<DefaultConsumer> {
-    let consumer: DefaultConsumer = ClientConfig::new()
+    let mut config = ClientConfig::new();
+    config
         .set("bootstrap.servers", brokers.join(","))
         .set("group.id", group_id)
         .set("enable.partition.eof", "false")
@@ -41,11 +72,39 @@ pub fn new_kafka_consumer(
         .set(
             "session.timeout.ms",
             format!("{}", session_time_out * 1000),
-        )
-        .set("enable.auto.commit", "true")
-        .create_with_context(DefaultConsumerContext)?;
+        );
+    if exactly_once {
+        // offsets are committed by the log producer's transactions instead
+        config
+            .set("enable.auto.commit", "false")
+            .set("isolation.level", "read_committed");
+    } else {
+        config.set("enable.auto.commit", "true");
+    }
+    let consumer: DefaultConsumer = config.create_with_context(DefaultConsumerContext)?;
 
     consumer.subscribe(&[topic])?;
 
     Ok(consumer)
 }
+
+/// A consumer that only sees committed transactions and is positioned by `assign`, for
+/// reading back what a transactional producer wrote. With `partition_eof` it reports the
+/// end of each partition as an error, which comes after any transaction markers there.
+pub fn new_kafka_reader(
+    brokers: &[String],
+    group_id: &str,
+    partition_eof: bool,
+) -> KafkaResult<DefaultConsumer> {
+    let consumer: DefaultConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers.join(","))
+        .set("group.id", group_id)
+        .set("enable.partition.eof", format!("{}", partition_eof))
+        .set("api.version.request", "true")
+        .set("broker.version.fallback", "2.1.0")
+        .set("isolation.level", "read_committed")
+        .set("enable.auto.commit", "false")
+        .create_with_context(DefaultConsumerContext)?;
+
+    Ok(consumer)
+}
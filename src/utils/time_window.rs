@@ -21,7 +21,7 @@ pub const DEFAULT_TABLE_SPLIT_COUNT: u64 = 128;
 ///
 /// The timestamp is stored in bits 22-62 relative to the Snowflake epoch.
 /// Returns relative time (ms since Snowflake epoch) to match the coordinate system
-/// used by `current_time_since_snowflake_epoch()`.
+/// used by `OrderBook::time_since_snowflake_epoch()`.
 #[inline]
 pub fn extract_timestamp_from_id(order_id: u64) -> i64 {
     (order_id >> 22) as i64
@@ -41,11 +41,11 @@ fn create_order(
         client_oid: format!("client_{}", order_id),
         price,
         size,
-        funds: Decimal::ZERO,
         r#type: order_type,
         side,
         time_in_force,
         status,
+        ..Default::default()
     }
 }
 
@@ -1,3 +1,4 @@
+use orderbook_rs::matching::codec::{decode_log, payload_format};
 use rdkafka::consumer::{stream_consumer::StreamConsumer, Consumer};
 use rdkafka::message::Message;
 use serde_json::Value;
@@ -21,6 +22,8 @@ async fn main() {
         .set("session.timeout.ms", "10000")
         .set("enable.auto.commit", "true")
         .set("auto.offset.reset", "earliest")
+        // skip logs of aborted transactions
+        .set("isolation.level", "read_committed")
         .create()
         .expect("Consumer creation failed");
 
@@ -39,14 +42,14 @@ async fn main() {
         match consumer.recv().await {
             Ok(message) => {
                 if let Some(payload) = message.payload() {
-                    let json_str = String::from_utf8_lossy(payload);
                     let offset = message.offset();
 
-                    // Debug: print raw JSON
-                    println!("[RAW] offset={} payload={}", offset, &json_str);
-
-                    match serde_json::from_str::<Value>(&json_str) {
+                    // logs may be JSON or binary, both decode to the JSON form
+                    match decode_log(payload, &payload_format(payload)) {
                         Ok(log_value) => {
+                            // Debug: print the log as JSON
+                            println!("[RAW] offset={} payload={}", offset, log_value);
+
                             // type is nested inside "base" object
                             let base = &log_value["base"];
                             let log_type = base["type"].as_str().unwrap_or("unknown");